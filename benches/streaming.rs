@@ -0,0 +1,78 @@
+//!
+//! streaming.rs
+//!
+//! the cost of streaming one captured block to several LPCM clients at 192 kHz/24 bit,
+//! with a copy and a sample by sample conversion per client (the old path) and
+//! with one shared block and one shared bulk conversion (the current path)
+//!
+//! cargo bench --bench streaming
+//!
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::{collections::VecDeque, hint::black_box, sync::Arc};
+use swyh_rs::{
+    enums::streaming::Dither,
+    utils::{
+        i24::I24Sample,
+        streamencoder::{LpcmEncoder, StreamEncoder},
+    },
+};
+
+const SAMPLE_RATE: u32 = 192_000;
+const CHANNELS: u16 = 2;
+// the samples of 10 msecs, a typical capture block
+const BLOCK_SIZE: usize = (SAMPLE_RATE as usize / 100) * CHANNELS as usize;
+
+fn captured_block() -> Vec<f32> {
+    (0..BLOCK_SIZE)
+        .map(|n| ((n as f32) * 0.01).sin() * 0.5)
+        .collect()
+}
+
+fn fan_out(c: &mut Criterion) {
+    let captured = captured_block();
+    let mut group = c.benchmark_group("lpcm_24bit_192khz");
+    group.throughput(Throughput::Elements(BLOCK_SIZE as u64));
+    for clients in [1usize, 4, 8] {
+        let mut outs: Vec<VecDeque<u8>> = vec![VecDeque::with_capacity(BLOCK_SIZE * 3); clients];
+        group.bench_with_input(
+            BenchmarkId::new("copy_per_client", clients),
+            &clients,
+            |b, _| {
+                b.iter(|| {
+                    for out in outs.iter_mut() {
+                        out.clear();
+                        let samples = captured.to_vec();
+                        for sample in samples.iter() {
+                            let i24 = sample.to_i24();
+                            out.push_back(i24.b1);
+                            out.push_back(i24.b2);
+                            out.push_back(i24.b3);
+                        }
+                        black_box(&out);
+                    }
+                })
+            },
+        );
+        let encoders: Vec<LpcmEncoder> = (0..clients)
+            .map(|_| LpcmEncoder::new(SAMPLE_RATE, 24, CHANNELS, Dither::None))
+            .collect();
+        group.bench_with_input(
+            BenchmarkId::new("shared_block", clients),
+            &clients,
+            |b, _| {
+                b.iter(|| {
+                    let block: Arc<[f32]> = Arc::from(captured.as_slice());
+                    for (encoder, out) in encoders.iter().zip(outs.iter_mut()) {
+                        out.clear();
+                        encoder.encode(&block, out);
+                        black_box(&out);
+                    }
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, fan_out);
+criterion_main!(benches);
@@ -1,22 +1,31 @@
-use std::{collections::HashMap, fs::File, net::IpAddr, path::Path, thread, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    net::IpAddr,
+    path::Path,
+    sync::Arc,
+    thread,
+    time::Duration,
+};
 
-use cpal::traits::StreamTrait;
 use crossbeam_channel::{unbounded, Receiver, Sender};
 use log::{debug, error, info, LevelFilter};
 use simplelog::{ColorChoice, CombinedLogger, Config, TermLogger, WriteLogger};
 use swyh_rs::{
     enums::streaming::{StreamingFormat::Wav, StreamingState},
-    globals::statics::{APP_NAME, APP_VERSION, CLIENTS, CONFIG, LOGCHANNEL},
-    openhome::rendercontrol::{discover, Renderer, StreamInfo, WavData},
+    globals::statics::{
+        APP_NAME, APP_VERSION, AUDIO_SOURCES, CLIENTS, CONFIG, LOGCHANNEL, RENDERERS,
+    },
+    openhome::rendercontrol::{discover, Renderer, StreamInfo},
     server::streaming_server::{run_server, StreamerFeedBack},
     utils::{
-        audiodevices::{
-            capture_output_audio, get_default_audio_output_device, get_output_audio_devices,
-        },
-        bincommon::run_silence_injector,
+        audiodevices::{get_default_audio_output_device, get_output_audio_devices},
+        audiosource::{open_audio_source, start_audio_source},
+        bincommon::run_rms_monitor,
         commandline::Args,
         local_ip_address::{get_interfaces, get_local_addr},
         priority::raise_priority,
+        recorder::{start_recording, stop_recording},
         ui_logger::{disable_ui_log, ui_log},
     },
 };
@@ -27,6 +36,8 @@ fn main() -> Result<(), i32> {
     // gracefully exit on Ctrl-C
     ctrlc::set_handler(move || {
         println!("Received Ctrl+C -> exiting.");
+        // finish the recording file
+        stop_recording();
         std::process::exit(0);
     })
     .expect("Error setting Ctrl-C handler");
@@ -89,6 +100,14 @@ fn main() -> Result<(), i32> {
     if args.inject_silence.is_some() {
         config.inject_silence = args.inject_silence;
     }
+    // set args audio source
+    if args.audio_source.is_some() {
+        config.audio_source = args.audio_source.clone();
+    }
+    // set args follow default device
+    if args.follow_default.is_some() {
+        config.capture_follow_default = args.follow_default;
+    }
     info!("Config: {:?}", config);
 
     // set args soundsource index
@@ -114,6 +133,17 @@ fn main() -> Result<(), i32> {
         }
         source_names.push(devname);
     }
+    *AUDIO_SOURCES.write() = source_names.clone();
+    // args: the capture config of the captured device, default removes it
+    if let Some(settings) = args.capture_config.clone() {
+        let device_name = match config.capture_follow_default {
+            Some(true) => get_default_audio_output_device()
+                .map(|device| device.name().to_string())
+                .unwrap_or(config.sound_source.clone()),
+            _ => audio_output_device.name().to_string(),
+        };
+        config.set_capture_settings(&device_name, settings);
+    }
 
     // get the list of available networks
     let networks = get_interfaces();
@@ -137,40 +167,33 @@ fn main() -> Result<(), i32> {
         }
     };
 
-    // we need to pass some audio config data to the play function
-    let audio_cfg = audio_output_device.default_config().clone();
-    let wd = WavData {
-        sample_format: audio_cfg.sample_format(),
-        sample_rate: audio_cfg.sample_rate(),
-        channels: audio_cfg.channels(),
+    // the selected audio source: the capture device, a pipe, a file or a test signal
+    let source = match open_audio_source(&config, audio_output_device) {
+        Ok(source) => source,
+        Err(e) => {
+            ui_log(format!("*E*E*> Could not open the audio source: {e}"));
+            return Err(-2);
+        }
     };
+    // we need to pass some audio config data to the play function
+    let wd = source.wav_data();
 
     // raise process priority a bit to prevent audio stuttering under cpu load
     raise_priority();
 
     // the rms monitor channel
-    let rms_channel: (Sender<Vec<f32>>, Receiver<Vec<f32>>) = unbounded();
+    let rms_channel = unbounded::<Arc<[f32]>>();
 
-    // capture system audio
+    // capture system audio (and inject silence if InjectSilence is on)
     debug!("Try capturing system audio");
-    let stream: cpal::Stream;
-    match capture_output_audio(&audio_output_device, rms_channel.0) {
-        Some(s) => {
-            stream = s;
-            stream.play().unwrap();
-        }
-        None => {
-            ui_log("*E*E*> Could not capture audio ...Please check configuration.".to_string());
+    let _source = match start_audio_source(source, rms_channel.0) {
+        Ok(running) => running,
+        Err(e) => {
+            ui_log(format!(
+                "*E*E*> Could not capture audio ...Please check configuration. {e}"
+            ));
             return Err(-2);
         }
-    }
-
-    // If silence injector is on, create a silence injector stream.
-    let _silence_stream = if let Some(true) = CONFIG.read().inject_silence {
-        ui_log("Injecting silence into the output stream".to_string());
-        Some(run_silence_injector(&audio_output_device))
-    } else {
-        None
     };
 
     // set args ssdp_interval
@@ -178,6 +201,14 @@ fn main() -> Result<(), i32> {
         config.ssdp_interval_mins = args.ssdp_interval_mins.unwrap();
     }
 
+    // start the "monitor_rms" thread, the RMS values are only shown in the web UI
+    let rms_receiver = rms_channel.1;
+    let _ = thread::Builder::new()
+        .name("rms_monitor".into())
+        .stack_size(4 * 1024 * 1024)
+        .spawn(move || run_rms_monitor(&wd.clone(), rms_receiver, |_, _| {}))
+        .unwrap();
+
     // now start the SSDP discovery update thread with a Crossbeam channel for renderer updates
     // the discovered renderers will be kept in this list
     ui_log("Discover networks".to_string());
@@ -208,6 +239,7 @@ fn main() -> Result<(), i32> {
     // set args bits per sample
     if args.bits_per_sample.is_some() {
         config.bits_per_sample = args.bits_per_sample;
+        config.float_samples = args.float_samples;
     }
     // set args streaming format
     if args.auto_resume.is_some() {
@@ -226,6 +258,35 @@ fn main() -> Result<(), i32> {
             config.use_wave_format = false;
         }
     }
+    // set args dither
+    if args.dither.is_some() {
+        config.dither = args.dither;
+    }
+    // set args WAV header
+    if args.wav_header.is_some() {
+        config.wav_header = args.wav_header;
+    }
+    // set args FLAC encoder settings
+    if args.flac_compression_level.is_some() {
+        config.flac_compression_level = args.flac_compression_level;
+    }
+    if args.flac_block_size.is_some() {
+        config.flac_block_size = args.flac_block_size;
+    }
+    if args.flac_verify.is_some() {
+        config.flac_verify = args.flac_verify;
+    }
+    // set args keep-alive policy
+    if args.keep_alive.is_some() {
+        config.keep_alive = args.keep_alive;
+    }
+    // set args pre-roll and low latency mode
+    if args.pre_roll_ms.is_some() {
+        config.pre_roll_ms = args.pre_roll_ms;
+    }
+    if args.low_latency.is_some() {
+        config.low_latency = args.low_latency;
+    }
     // finally start a webserver on the local address,
     // with a Crossbeam feedback channel for connection accept/drop
     let (feedback_tx, feedback_rx): (Sender<StreamerFeedBack>, Receiver<StreamerFeedBack>) =
@@ -291,18 +352,21 @@ fn main() -> Result<(), i32> {
         return Ok(());
     }
 
+    // start recording if requested
+    if args.record == Some(true) {
+        if let Err(e) = start_recording() {
+            ui_log(format!("*E*E*> {e}"));
+        }
+    }
+
     // get the logreader channel
     let logreader = &LOGCHANNEL.read().1;
 
     // prepare for playing
-    let wd = WavData {
-        sample_format: audio_cfg.sample_format(),
-        sample_rate: audio_cfg.sample_rate(),
-        channels: audio_cfg.channels(),
-    };
     let streaminfo = StreamInfo {
         sample_rate: wd.sample_rate.0,
         bits_per_sample: config.bits_per_sample.unwrap(),
+        channels: wd.channels,
         streaming_format: config.streaming_format.unwrap(),
     };
     let _ = player.play(
@@ -312,10 +376,17 @@ fn main() -> Result<(), i32> {
         &streaminfo,
     );
 
+    // the renderers that were stopped by an API request must not be autoresumed
+    let mut stopped_renderers: HashSet<String> = HashSet::new();
     loop {
         while let Ok(streamer_feedback) = feedback_rx.try_recv() {
             match streamer_feedback.streaming_state {
-                StreamingState::Started => {}
+                StreamingState::Started => {
+                    stopped_renderers.remove(&streamer_feedback.remote_ip);
+                }
+                StreamingState::Stopped => {
+                    stopped_renderers.insert(streamer_feedback.remote_ip);
+                }
                 StreamingState::Ended => {
                     // first check if the renderer has actually not started streaming again
                     // as this can happen with Bubble/Nest Audio Openhome
@@ -325,7 +396,9 @@ fn main() -> Result<(), i32> {
                         .any(|chanstrm| chanstrm.remote_ip == streamer_feedback.remote_ip);
                     if !still_streaming {
                         let config = CONFIG.read().clone();
-                        if config.auto_resume {
+                        if config.auto_resume
+                            && !stopped_renderers.contains(&streamer_feedback.remote_ip)
+                        {
                             if let Some(r) = renderers
                                 .iter()
                                 .find(|r| r.remote_addr == streamer_feedback.remote_ip)
@@ -333,6 +406,7 @@ fn main() -> Result<(), i32> {
                                 let streaminfo = StreamInfo {
                                     sample_rate: wd.sample_rate.0,
                                     bits_per_sample: config.bits_per_sample.unwrap(),
+                                    channels: wd.channels,
                                     streaming_format: config.streaming_format.unwrap(),
                                 };
                                 let _ = r.play(
@@ -377,6 +451,7 @@ fn run_ssdp_updater(ssdp_tx: Sender<Renderer>, ssdp_interval_mins: f64) {
                     r.dev_name, r.dev_model, r.remote_addr
                 );
                 rmap.insert(r.remote_addr.clone(), r.clone());
+                RENDERERS.write().push(r.clone());
             }
         }
         thread::sleep(Duration::from_millis(
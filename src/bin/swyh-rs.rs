@@ -37,32 +37,32 @@ SOFTWARE.
 */
 use swyh_rs::{
     enums::streaming::StreamingState,
-    globals::statics::{APP_NAME, APP_VERSION, CLIENTS, CONFIG, LOGCHANNEL},
-    openhome::rendercontrol::{discover, Renderer, StreamInfo, WavData},
+    globals::statics::{
+        APP_NAME, APP_VERSION, AUDIO_SOURCES, CLIENTS, CONFIG, LOGCHANNEL, RENDERERS,
+    },
+    openhome::rendercontrol::{discover, Renderer, StreamInfo},
     server::streaming_server::{run_server, StreamerFeedBack},
     ui::mainform::MainForm,
     utils::{
-        audiodevices::{
-            capture_output_audio, get_default_audio_output_device, get_output_audio_devices,
-        },
-        bincommon::run_silence_injector,
+        audiodevices::{get_default_audio_output_device, get_output_audio_devices, CaptureSource},
+        audiosource::{open_audio_source, start_audio_source, AudioSource},
+        bincommon::run_rms_monitor,
         local_ip_address::*,
         priority::raise_priority,
+        recorder::{recording_status, stop_recording},
         ui_logger::ui_log,
     },
 };
 
-use cpal::{traits::StreamTrait, Sample};
 use crossbeam_channel::{unbounded, Receiver, Sender};
 use fltk::{
     app, dialog,
-    misc::Progress,
     prelude::{ButtonExt, WidgetExt},
 };
 use log::{debug, info, LevelFilter};
 use simplelog::{ColorChoice, CombinedLogger, Config, TermLogger, WriteLogger};
 use std::{
-    cell::Cell, collections::HashMap, fs::File, net::IpAddr, path::Path, rc::Rc, thread,
+    cell::Cell, collections::HashMap, fs::File, net::IpAddr, path::Path, rc::Rc, sync::Arc, thread,
     time::Duration,
 };
 
@@ -153,6 +153,7 @@ fn main() {
         }
         source_names.push(devname);
     }
+    *AUDIO_SOURCES.write() = source_names.clone();
 
     // get the default network that connects to the internet
     let local_addr: IpAddr = {
@@ -171,12 +172,19 @@ fn main() {
     let networks = get_interfaces();
 
     // we need to pass some audio config data to the play function
-    let audio_cfg = audio_output_device.default_config();
-    let wd = WavData {
-        sample_format: audio_cfg.sample_format(),
-        sample_rate: audio_cfg.sample_rate(),
-        channels: audio_cfg.channels(),
+    // the selected audio source: the capture device, a pipe, a file or a test signal
+    let source: Box<dyn AudioSource> = match open_audio_source(&config, audio_output_device) {
+        Ok(source) => source,
+        Err(e) => {
+            ui_log(format!(
+                "*E*E*> Could not open the audio source: {e}, using the default audio device"
+            ));
+            Box::new(CaptureSource::new(
+                get_default_audio_output_device().expect("No default audio device"),
+            ))
+        }
     };
+    let wd = source.wav_data();
 
     // we now have enough information to create the GUI with meaningful data
     let mut mf = MainForm::create(
@@ -193,27 +201,18 @@ fn main() {
     raise_priority();
 
     // the rms monitor channel
-    let rms_channel: (Sender<Vec<f32>>, Receiver<Vec<f32>>) = unbounded();
+    let rms_channel = unbounded::<Arc<[f32]>>();
 
-    // capture system audio
+    // capture system audio (and inject silence if InjectSilence is on)
     debug!("Try capturing system audio");
-    let stream: cpal::Stream;
-    match capture_output_audio(&audio_output_device, rms_channel.0) {
-        Some(s) => {
-            stream = s;
-            stream.play().unwrap();
-        }
-        None => {
-            ui_log("*E*E*> Could not capture audio ...Please check configuration.".to_string());
+    let _source = match start_audio_source(source, rms_channel.0) {
+        Ok(running) => Some(running),
+        Err(e) => {
+            ui_log(format!(
+                "*E*E*> Could not capture audio ...Please check configuration. {e}"
+            ));
+            None
         }
-    }
-
-    // If silence injector is on, create a silence injector stream.
-    let _silence_stream = if let Some(true) = CONFIG.read().inject_silence {
-        ui_log("Injecting silence into the output stream".to_owned());
-        Some(run_silence_injector(&audio_output_device))
-    } else {
-        None
     };
 
     // now start the SSDP discovery update thread with a Crossbeam channel for renderer updates
@@ -231,12 +230,18 @@ fn main() {
 
     // also start the "monitor_rms" thread
     let rms_receiver = rms_channel.1;
-    let mon_l = mf.rms_mon_l.clone();
-    let mon_r = mf.rms_mon_r.clone();
+    let mut mon_l = mf.rms_mon_l.clone();
+    let mut mon_r = mf.rms_mon_r.clone();
     let _ = thread::Builder::new()
         .name("rms_monitor".into())
         .stack_size(4 * 1024 * 1024)
-        .spawn(move || run_rms_monitor(&wd.clone(), rms_receiver, mon_l, mon_r))
+        .spawn(move || {
+            run_rms_monitor(&wd.clone(), rms_receiver, |rms_l, rms_r| {
+                mon_l.set_value(rms_l);
+                mon_r.set_value(rms_r);
+                app::awake();
+            })
+        })
         .unwrap();
 
     // finally start a webserver on the local address,
@@ -273,6 +278,11 @@ fn main() {
         if config_changed.get() && app_restart(&mf) != 0 {
             config_changed.set(false);
         }
+        // recording can also be started and stopped with the API or end after an error
+        let recording = recording_status().is_some();
+        if mf.record.is_set() != recording {
+            mf.record.set(recording);
+        }
         // check if the streaming webserver has closed a connection not caused by
         // pushing a renderer button
         // in that case we turn the button off as a visual feedback for the user
@@ -302,6 +312,7 @@ fn main() {
                                     let streaminfo = StreamInfo {
                                         sample_rate: wd.sample_rate.0,
                                         bits_per_sample: config.bits_per_sample.unwrap(),
+                                        channels: wd.channels,
                                         streaming_format: config.streaming_format.unwrap(),
                                     };
                                     let _ = r.play(
@@ -316,6 +327,12 @@ fn main() {
                             }
                         }
                     }
+                    StreamingState::Stopped => {
+                        // stopped by an API request: no autoresume
+                        if button.is_set() {
+                            button.set(false);
+                        }
+                    }
                 }
             }
         }
@@ -330,6 +347,8 @@ fn main() {
             mf.add_log_msg(msg);
         }
     } // while app::wait()
+      // finish the recording file
+    stop_recording();
 }
 
 fn app_restart(mf: &MainForm) -> i32 {
@@ -376,6 +395,7 @@ fn run_ssdp_updater(ssdp_tx: Sender<Renderer>, ssdp_interval_mins: f64) {
                     r.dev_name, r.dev_model, r.remote_addr
                 );
                 rmap.insert(r.remote_addr.clone(), r.clone());
+                RENDERERS.write().push(r.clone());
             }
         }
         thread::sleep(Duration::from_millis(
@@ -383,39 +403,3 @@ fn run_ssdp_updater(ssdp_tx: Sender<Renderer>, ssdp_interval_mins: f64) {
         ));
     }
 }
-
-fn run_rms_monitor(
-    wd: &WavData,
-    rms_receiver: Receiver<Vec<f32>>,
-    mut rms_frame_l: Progress,
-    mut rms_frame_r: Progress,
-) {
-    // compute # of samples needed to get a 10 Hz refresh rate
-    let samples_per_update = ((wd.sample_rate.0 * wd.channels as u32) / 10) as i64;
-    let mut nsamples = 0i64;
-    let mut sum_l = 0i64;
-    let mut sum_r = 0i64;
-    while let Ok(samples) = rms_receiver.recv() {
-        for (n, sample) in samples.iter().enumerate() {
-            nsamples += 1;
-            let i64sample: i64 = i16::from_sample(*sample) as i64;
-            if n & 1 == 0 {
-                sum_l += i64sample * i64sample;
-            } else {
-                sum_r += i64sample * i64sample;
-            }
-            if nsamples >= samples_per_update {
-                // compute rms value
-                let rms_l = ((sum_l / nsamples) as f64).sqrt();
-                rms_frame_l.set_value(rms_l);
-                let rms_r = ((sum_r / nsamples) as f64).sqrt();
-                rms_frame_r.set_value(rms_r);
-                app::awake();
-                //reset counters
-                nsamples = 0;
-                sum_l = 0;
-                sum_r = 0;
-            }
-        }
-    }
-}
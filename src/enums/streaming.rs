@@ -1,11 +1,13 @@
 use serde::{Deserialize, Serialize};
-use std::fmt;
+use std::{fmt, str::FromStr};
 
 /// streaming state
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum StreamingState {
     Started,
     Ended,
+    /// stopped by a remote control request, so don't autoresume
+    Stopped,
 }
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
@@ -13,6 +15,9 @@ pub enum StreamingFormat {
     Lpcm,
     Wav,
     Flac,
+    Mp3,
+    Opus,
+    OggFlac,
 }
 
 impl fmt::Display for StreamingFormat {
@@ -21,6 +26,159 @@ impl fmt::Display for StreamingFormat {
             StreamingFormat::Lpcm => write!(f, "LPCM"),
             StreamingFormat::Wav => write!(f, "WAV"),
             StreamingFormat::Flac => write!(f, "FLAC"),
+            StreamingFormat::Mp3 => write!(f, "MP3"),
+            StreamingFormat::Opus => write!(f, "OPUS"),
+            StreamingFormat::OggFlac => write!(f, "OGGFLAC"),
+        }
+    }
+}
+
+impl FromStr for StreamingFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "lpcm" => Ok(StreamingFormat::Lpcm),
+            "wav" => Ok(StreamingFormat::Wav),
+            "flac" => Ok(StreamingFormat::Flac),
+            "mp3" => Ok(StreamingFormat::Mp3),
+            "opus" => Ok(StreamingFormat::Opus),
+            "oggflac" => Ok(StreamingFormat::OggFlac),
+            _ => Err(format!("invalid streaming format {s}")),
+        }
+    }
+}
+
+/// the dither applied when the f32 samples are reduced to 16 bit
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum Dither {
+    /// no dither, the samples are converted as they are
+    None,
+    /// triangular (TPDF) dither of 1 LSB peak
+    Tpdf,
+    /// TPDF dither with noise shaping, moving the noise to the less audible high frequencies
+    Shaped,
+}
+
+impl fmt::Display for Dither {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Dither::None => write!(f, "NONE"),
+            Dither::Tpdf => write!(f, "TPDF"),
+            Dither::Shaped => write!(f, "SHAPED"),
+        }
+    }
+}
+
+impl FromStr for Dither {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(Dither::None),
+            "tpdf" => Ok(Dither::Tpdf),
+            "shaped" => Ok(Dither::Shaped),
+            _ => Err(format!("invalid dither {s}")),
+        }
+    }
+}
+
+/// the header of the WAV streaming format
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum WavHeader {
+    /// RIFF WAVE with 32 bit sizes
+    Classic,
+    /// RF64 with the 64 bit sizes in a ds64 chunk
+    Rf64,
+    /// Sony Wave64
+    Wave64,
+}
+
+impl fmt::Display for WavHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WavHeader::Classic => write!(f, "CLASSIC"),
+            WavHeader::Rf64 => write!(f, "RF64"),
+            WavHeader::Wave64 => write!(f, "W64"),
+        }
+    }
+}
+
+impl FromStr for WavHeader {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "classic" => Ok(WavHeader::Classic),
+            "rf64" => Ok(WavHeader::Rf64),
+            "w64" | "wave64" => Ok(WavHeader::Wave64),
+            _ => Err(format!("invalid WAV header {s}")),
+        }
+    }
+}
+
+/// what is streamed when nothing has been captured for a while,
+/// to keep the renderer from closing the connection
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum KeepAlivePolicy {
+    /// digital silence
+    Silence,
+    /// a very faint TPDF noise, for renderers that detect digital silence
+    Noise,
+    /// nothing, the stream simply waits for the next samples
+    None,
+}
+
+impl fmt::Display for KeepAlivePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeepAlivePolicy::Silence => write!(f, "SILENCE"),
+            KeepAlivePolicy::Noise => write!(f, "NOISE"),
+            KeepAlivePolicy::None => write!(f, "NONE"),
+        }
+    }
+}
+
+impl FromStr for KeepAlivePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "silence" => Ok(KeepAlivePolicy::Silence),
+            "noise" => Ok(KeepAlivePolicy::Noise),
+            "none" => Ok(KeepAlivePolicy::None),
+            _ => Err(format!("invalid keep-alive policy {s}")),
+        }
+    }
+}
+
+/// what happens when the queue of a client is full, because the renderer
+/// doesn't read the stream fast enough
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum OverflowPolicy {
+    /// the oldest queued samples are dropped to make room for the new ones
+    DropOldest,
+    /// the client is disconnected
+    Disconnect,
+}
+
+impl fmt::Display for OverflowPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OverflowPolicy::DropOldest => write!(f, "DROPOLDEST"),
+            OverflowPolicy::Disconnect => write!(f, "DISCONNECT"),
+        }
+    }
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "dropoldest" | "drop" => Ok(OverflowPolicy::DropOldest),
+            "disconnect" => Ok(OverflowPolicy::Disconnect),
+            _ => Err(format!("invalid overflow policy {s}")),
         }
     }
 }
@@ -1,9 +1,17 @@
-use crate::utils::{configuration::Configuration, rwstream::ChannelStream};
+use crate::{
+    openhome::rendercontrol::Renderer,
+    utils::{
+        configuration::Configuration, preroll::PreRoll, recorder::Recorder, rwstream::ChannelStream,
+    },
+};
 
 use crossbeam_channel::{unbounded, Receiver, Sender};
 use once_cell::sync::Lazy;
-use parking_lot::RwLock;
-use std::{collections::HashMap, sync::atomic::AtomicBool};
+use parking_lot::{Mutex, RwLock};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64},
+};
 
 /// app version
 pub const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -15,9 +23,25 @@ pub const SERVER_PORT: u16 = 5901;
 // streaming clients of the webserver
 pub static CLIENTS: Lazy<RwLock<HashMap<String, ChannelStream>>> =
     Lazy::new(|| RwLock::new(HashMap::new()));
+// the renderers discovered by SSDP
+pub static RENDERERS: Lazy<RwLock<Vec<Renderer>>> = Lazy::new(|| RwLock::new(Vec::new()));
+// the names of the available audio sources
+pub static AUDIO_SOURCES: Lazy<RwLock<Vec<String>>> = Lazy::new(|| RwLock::new(Vec::new()));
 // the global GUI logger textbox channel used by all threads
 pub static LOGCHANNEL: Lazy<RwLock<(Sender<String>, Receiver<String>)>> =
     Lazy::new(|| RwLock::new(unbounded()));
+// the most recent log messages (with their sequence number) for the web UI
+pub static LOG_HISTORY: Lazy<RwLock<VecDeque<(u64, String)>>> =
+    Lazy::new(|| RwLock::new(VecDeque::new()));
+// the most recently captured audio, for the clients that want a pre-roll
+pub static PRE_ROLL: Lazy<Mutex<PreRoll>> = Lazy::new(|| Mutex::new(PreRoll::new()));
+// the recorder, while recording
+pub static RECORDER: Lazy<Mutex<Option<Recorder>>> = Lazy::new(|| Mutex::new(None));
+// the last computed RMS values (f32 bits) for the web UI
+pub static RMS_LEFT: AtomicU32 = AtomicU32::new(0);
+pub static RMS_RIGHT: AtomicU32 = AtomicU32::new(0);
+// the number of captured blocks dropped because the capture ring buffer was full
+pub static CAPTURE_OVERRUNS: AtomicU64 = AtomicU64::new(0);
 // the global configuration state
 pub static CONFIG: Lazy<RwLock<Configuration>> =
     Lazy::new(|| RwLock::new(Configuration::read_config()));
@@ -6,7 +6,10 @@
 /// Only tested with Volumio streamers (https://volumio.org/)
 ///
 ///
-use crate::{enums::streaming::StreamingFormat, globals::statics::CONFIG};
+use crate::{
+    enums::streaming::StreamingFormat, globals::statics::CONFIG,
+    utils::streamencoder::stream_protocol_info,
+};
 use bitflags::bitflags;
 use log::{debug, error, info};
 use std::{
@@ -45,19 +48,13 @@ static AV_SET_TRANSPORT_URI_TEMPLATE: &str = "\
 </s:Body>\
 </s:Envelope>";
 
-/// didl protocolinfo
-static L16_PROT_INFO: &str = "http-get:*:audio/L16;rate={sample_rate};channels=2:DLNA.ORG_PN=LPCM";
-static L24_PROT_INFO: &str = "http-get:*:audio/L24;rate={sample_rate};channels=2:DLNA.ORG_PN=LPCM";
-static WAV_PROT_INFO: &str = "http-get:*:audio/wav:DLNA.ORG_PN=WAV;DLNA.ORG_OP=01;DLNA.ORG_CI=0;DLNA.ORG_FLAGS=03700000000000000000000000000000";
-static FLAC_PROT_INFO: &str = "http-get:*:audio/flac:DLNA.ORG_PN=FLAC;DLNA.ORG_OP=01;DLNA.ORG_CI=0;DLNA.ORG_FLAGS=01700000000000000000000000000000";
-
 /// didl metadata template
 static DIDL_TEMPLATE: &str = "\
 <DIDL-Lite xmlns=\"urn:schemas-upnp-org:metadata-1-0/DIDL-Lite/\" xmlns:dc=\"http://purl.org/dc/elements/1.1/\" xmlns:upnp=\"urn:schemas-upnp-org:metadata-1-0/upnp/\">\
 <item id=\"1\" parentID=\"0\" restricted=\"0\">\
 <dc:title>swyh-rs</dc:title>\
 <res bitsPerSample=\"{bits_per_sample}\" \
-nrAudioChannels=\"2\" \
+nrAudioChannels=\"{channels}\" \
 sampleFrequency=\"{sample_rate}\" \
 protocolInfo=\"{didl_prot_info}\" \
 duration=\"{duration}\" >{server_uri}</res>\
@@ -122,6 +119,8 @@ pub struct WavData {
 pub struct StreamInfo {
     pub sample_rate: u32,
     pub bits_per_sample: u16,
+    /// the number of captured channels
+    pub channels: u16,
     pub streaming_format: StreamingFormat,
 }
 
@@ -238,40 +237,34 @@ impl Renderer {
         log: &dyn Fn(String),
         streaminfo: &StreamInfo,
     ) -> Result<(), &str> {
-        let use_wav_format = streaminfo.streaming_format == StreamingFormat::Wav;
         // build the hashmap with the formatting vars for the OH and AV play templates
         let mut fmt_vars = HashMap::new();
         let (host, port) = self.parse_url(&self.dev_url, log);
         let addr = format!("{local_addr}:{server_port}");
         let local_url = format!("http://{addr}/stream/swyh.wav");
         fmt_vars.insert("server_uri".to_string(), local_url);
-        fmt_vars.insert(
-            "bits_per_sample".to_string(),
-            streaminfo.bits_per_sample.to_string(),
+        // the stream is resampled if the renderer needs another sample rate
+        let sample_rate = CONFIG
+            .read()
+            .output_sample_rate(&self.remote_addr, streaminfo.sample_rate);
+        fmt_vars.insert("sample_rate".to_string(), sample_rate.to_string());
+        fmt_vars.insert("duration".to_string(), "00:00:00".to_string());
+        // multichannel renderers get the captured channels, the others stereo
+        let channels = CONFIG.read().output_channels(
+            &self.remote_addr,
+            streaminfo.channels,
+            streaminfo.streaming_format,
         );
-        fmt_vars.insert(
-            "sample_rate".to_string(),
-            streaminfo.sample_rate.to_string(),
+        fmt_vars.insert("channels".to_string(), channels.to_string());
+        // the sample size the encoder will really send
+        let (prot_info, bits_per_sample) = stream_protocol_info(
+            streaminfo.streaming_format,
+            sample_rate,
+            streaminfo.bits_per_sample,
+            channels,
         );
-        fmt_vars.insert("duration".to_string(), "00:00:00".to_string());
-        let mut didl_prot: String;
-        if streaminfo.streaming_format == StreamingFormat::Flac {
-            didl_prot = htmlescape::encode_minimal(FLAC_PROT_INFO);
-        } else if use_wav_format {
-            didl_prot = htmlescape::encode_minimal(WAV_PROT_INFO);
-        } else if streaminfo.bits_per_sample == 16 {
-            didl_prot = htmlescape::encode_minimal(L16_PROT_INFO);
-        } else {
-            didl_prot = htmlescape::encode_minimal(L24_PROT_INFO);
-        }
-        match strfmt(&didl_prot, &fmt_vars) {
-            Ok(s) => didl_prot = s,
-            Err(e) => {
-                didl_prot = format!("oh_play: error {e} formatting didl_prot");
-                log(didl_prot.clone());
-                return Err(BAD_TEMPL);
-            }
-        }
+        fmt_vars.insert("bits_per_sample".to_string(), bits_per_sample.to_string());
+        let didl_prot = htmlescape::encode_minimal(&prot_info);
         fmt_vars.insert("didl_prot_info".to_string(), didl_prot);
         let mut didl_data = htmlescape::encode_minimal(DIDL_TEMPLATE);
         match strfmt(&didl_data, &fmt_vars) {
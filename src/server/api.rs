@@ -0,0 +1,481 @@
+///
+/// api.rs
+///
+/// a small JSON REST API on the streaming webserver, to control swyh-rs without the GUI
+///
+///   GET  /api/renderers               the discovered renderers and their streaming state
+///   POST /api/renderers/{ip}/play     start playing on a renderer
+///   POST /api/renderers/{ip}/stop     stop playing on a renderer
+///   GET  /api/clients                 the active streaming clients
+///   GET  /api/sources                 the available audio sources
+///   GET  /api/networks                the available networks
+///   GET  /api/log?since={seq}         the log messages starting at sequence number seq
+///   GET  /api/rms                     the current left and right RMS values
+///   GET  /api/capture                 the capture statistics (ring buffer overruns)
+///   GET  /api/recording               the recording state and the current file
+///   POST /api/recording/start         start recording to the RecordingDir
+///   POST /api/recording/stop          stop recording
+///   GET  /api/config                  the current configuration
+///   PUT  /api/config                  update (some of) the configuration values
+///
+/// if an ApiToken is configured every request needs an "Authorization: Bearer {ApiToken}" header,
+/// the ApiToken itself is never returned and can only be set in the config file
+///
+/// the values that run a command or access a path (IcyMetaCommand, IcyMetaFile, RecordingDir
+/// and AudioSource) can't be changed with the API either, as it needs no ApiToken by default
+///
+use crate::{
+    enums::streaming::{StreamingFormat, StreamingState},
+    globals::statics::{
+        AUDIO_SOURCES, CAPTURE_OVERRUNS, CLIENTS, CONFIG, RENDERERS, RMS_LEFT, RMS_RIGHT,
+    },
+    openhome::rendercontrol::{Renderer, StreamInfo, WavData},
+    server::streaming_server::{get_query_param, StreamerFeedBack},
+    utils::{
+        audiosource::SourceSpec,
+        configuration::{Configuration, MAX_PRE_ROLL_MS},
+        local_ip_address::get_interfaces,
+        recorder::{recording_status, start_recording, stop_recording},
+        ui_logger::{get_log_history, ui_log},
+    },
+};
+use crossbeam_channel::Sender;
+use log::debug;
+use serde_json::{json, Map, Value};
+use std::{net::IpAddr, sync::atomic::Ordering::Relaxed};
+use tiny_http::{Header, Method, Request, Response};
+
+/// configuration values that can't be changed with the API
+const READ_ONLY_KEYS: [&str; 7] = [
+    "ConfigDir",
+    "ConfigId",
+    "ApiToken",
+    "IcyMetaCommand",
+    "IcyMetaFile",
+    "RecordingDir",
+    "AudioSource",
+];
+
+/// configuration values that are left out of the API responses
+const SECRET_KEYS: [&str; 1] = ["ApiToken"];
+/// configuration values that only take effect after a restart
+const RESTART_KEYS: [&str; 13] = [
+    "ServerPort",
+    "SoundCard",
+    "SoundCardIndex",
+    "CaptureConfig",
+    "LogLevel",
+    "SSDPIntervalMins",
+    "LastNetwork",
+    "StreamingFormat",
+    "BitsPerSample",
+    "InjectSilence",
+    "ChannelSwap",
+    "Balance",
+    "MonoSum",
+];
+
+/// handle_api_request - dispatch an /api request
+pub fn handle_api_request(
+    mut rq: Request,
+    local_addr: IpAddr,
+    server_port: u16,
+    wd: WavData,
+    feedback_tx: &Sender<StreamerFeedBack>,
+) {
+    let remote_addr = format!("{}", rq.remote_addr().unwrap());
+    debug!("API {} {} from {}", rq.method(), rq.url(), remote_addr);
+    let (status, body) = if !is_authorized(&rq) {
+        api_error(401, "missing or invalid API token")
+    } else {
+        let url = rq.url().to_string();
+        let path = url.split('?').next().unwrap_or_default();
+        let segments: Vec<&str> = path
+            .trim_start_matches("/api")
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .collect();
+        match (rq.method(), segments.as_slice()) {
+            (Method::Get, ["renderers"]) => (200, get_renderers()),
+            (Method::Post, ["renderers", id, action]) => {
+                renderer_action(id, action, local_addr, server_port, &wd, feedback_tx)
+            }
+            (Method::Get, ["clients"]) => (200, get_clients()),
+            (Method::Get, ["sources"]) => (200, get_sources()),
+            (Method::Get, ["networks"]) => (200, get_networks()),
+            (Method::Get, ["log"]) => (200, get_log(&url)),
+            (Method::Get, ["rms"]) => (200, get_rms()),
+            (Method::Get, ["capture"]) => (200, get_capture()),
+            (Method::Get, ["recording"]) => (200, get_recording()),
+            (Method::Post, ["recording", "start"]) => match start_recording() {
+                Ok(()) => (200, get_recording()),
+                Err(e) => api_error(500, &e),
+            },
+            (Method::Post, ["recording", "stop"]) => {
+                stop_recording();
+                (200, get_recording())
+            }
+            (Method::Get, ["config"]) => (200, get_config()),
+            (Method::Put, ["config"]) => {
+                let mut content = String::new();
+                match rq.as_reader().read_to_string(&mut content) {
+                    Ok(_) => put_config(&content),
+                    Err(e) => api_error(400, &format!("unable to read request body: {e}")),
+                }
+            }
+            (
+                _,
+                ["renderers"]
+                | ["renderers", _, _]
+                | ["clients"]
+                | ["sources"]
+                | ["networks"]
+                | ["log"]
+                | ["rms"]
+                | ["capture"]
+                | ["recording"]
+                | ["recording", "start" | "stop"]
+                | ["config"],
+            ) => api_error(405, "method not allowed"),
+            _ => api_error(404, "unknown API endpoint"),
+        }
+    };
+    let ct_hdr = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap();
+    let cc_hdr = Header::from_bytes(&b"Connection"[..], &b"close"[..]).unwrap();
+    let response = Response::from_string(body.to_string())
+        .with_status_code(status)
+        .with_header(ct_hdr)
+        .with_header(cc_hdr);
+    if let Err(e) = rq.respond(response) {
+        ui_log(format!(
+            "=>Http API connection with {remote_addr} terminated [{e}]"
+        ));
+    }
+}
+
+/// is_authorized - check the bearer token if an ApiToken has been configured
+fn is_authorized(rq: &Request) -> bool {
+    let token = match CONFIG.read().api_token.clone() {
+        Some(token) if !token.is_empty() => token,
+        _ => return true,
+    };
+    rq.headers()
+        .iter()
+        .filter(|h| h.field.equiv("Authorization"))
+        .any(|h| h.value.as_str().trim().strip_prefix("Bearer ") == Some(token.as_str()))
+}
+
+fn api_error(status: u16, msg: &str) -> (u16, Value) {
+    (status, json!({ "error": msg }))
+}
+
+/// a renderer is playing when it has an active streaming connection
+fn is_playing(remote_ip: &str) -> bool {
+    CLIENTS
+        .read()
+        .values()
+        .any(|chanstrm| chanstrm.remote_ip == remote_ip)
+}
+
+fn renderer_json(r: &Renderer) -> Value {
+    json!({
+        "id": r.remote_addr,
+        "name": r.dev_name,
+        "model": r.dev_model,
+        "url": r.dev_url,
+        "protocols": format!("{:?}", r.supported_protocols),
+        "playing": is_playing(&r.remote_addr),
+    })
+}
+
+fn get_renderers() -> Value {
+    let renderers = RENDERERS.read();
+    Value::Array(renderers.iter().map(renderer_json).collect())
+}
+
+/// renderer_action - play or stop, using the same Renderer methods as the GUI buttons
+fn renderer_action(
+    id: &str,
+    action: &str,
+    local_addr: IpAddr,
+    server_port: u16,
+    wd: &WavData,
+    feedback_tx: &Sender<StreamerFeedBack>,
+) -> (u16, Value) {
+    let renderer = match RENDERERS.read().iter().find(|r| r.remote_addr == id) {
+        Some(r) => r.clone(),
+        None => return api_error(404, &format!("unknown renderer {id}")),
+    };
+    match action {
+        "play" => {
+            let config = CONFIG.read().clone();
+            let streaminfo = StreamInfo {
+                sample_rate: wd.sample_rate.0,
+                bits_per_sample: config.bits_per_sample.unwrap(),
+                channels: wd.channels,
+                streaming_format: config.streaming_format.unwrap(),
+            };
+            ui_log(format!("API: start playing on {}", renderer.dev_name));
+            if let Err(e) = renderer.play(&local_addr, server_port, &ui_log, &streaminfo) {
+                return api_error(500, e);
+            }
+            (200, renderer_json(&renderer))
+        }
+        "stop" => {
+            ui_log(format!("API: stop playing on {}", renderer.dev_name));
+            // tell the GUI/CLI first, so that the connection close doesn't trigger autoresume
+            let _ = feedback_tx.send(StreamerFeedBack {
+                remote_ip: renderer.remote_addr.clone(),
+                streaming_state: StreamingState::Stopped,
+            });
+            renderer.stop_play(&ui_log);
+            (200, renderer_json(&renderer))
+        }
+        _ => api_error(404, &format!("unknown renderer action {action}")),
+    }
+}
+
+fn get_clients() -> Value {
+    let clients = CLIENTS.read();
+    Value::Array(
+        clients
+            .iter()
+            .map(|(remote_addr, chs)| {
+                json!({
+                    "remote_addr": remote_addr,
+                    "remote_ip": chs.remote_ip,
+                    "format": chs.streaming_format.to_string(),
+                    "bits_per_sample": chs.bits_per_sample(),
+                    "bytes_sent": chs.bytes_sent(),
+                    "buffer_ms": chs.buffer_ms(),
+                    "drift_ppm": chs.drift_ppm(),
+                })
+            })
+            .collect(),
+    )
+}
+
+fn get_sources() -> Value {
+    json!({
+        "sources": *AUDIO_SOURCES.read(),
+        "selected": CONFIG.read().sound_source,
+        "selected_index": CONFIG.read().sound_source_index,
+    })
+}
+
+fn get_networks() -> Value {
+    json!({
+        "networks": get_interfaces(),
+        "selected": CONFIG.read().last_network,
+    })
+}
+
+/// get_log - the log messages since the sequence number in the "since" query parameter
+fn get_log(url: &str) -> Value {
+    let since = get_query_param(url, "since")
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0);
+    let (next, lines) = get_log_history(since);
+    json!({ "next": next, "lines": lines })
+}
+
+fn get_rms() -> Value {
+    json!({
+        "monitoring": CONFIG.read().monitor_rms,
+        "left": f32::from_bits(RMS_LEFT.load(Relaxed)),
+        "right": f32::from_bits(RMS_RIGHT.load(Relaxed)),
+    })
+}
+
+/// get_capture - the capture statistics
+fn get_capture() -> Value {
+    json!({ "overruns": CAPTURE_OVERRUNS.load(Relaxed) })
+}
+
+/// get_recording - the recording state, the current file and the recorded bytes
+fn get_recording() -> Value {
+    match recording_status() {
+        Some(status) => json!({
+            "recording": true,
+            "file": status.file.map(|f| f.display().to_string()),
+            "bytes": status.bytes,
+            "files": status.files,
+        }),
+        None => json!({ "recording": false }),
+    }
+}
+
+fn get_config() -> Value {
+    config_json(&CONFIG.read())
+}
+
+/// config_json - the configuration as JSON, without the secret values
+fn config_json(conf: &Configuration) -> Value {
+    let mut value = serde_json::to_value(conf).unwrap_or_default();
+    if let Some(values) = value.as_object_mut() {
+        for key in SECRET_KEYS {
+            values.remove(key);
+        }
+    }
+    value
+}
+
+/// put_config - merge the JSON object in the request body with the current configuration
+///
+/// the keys are the same as in the config file, e.g. {"AutoResume": true, "BitsPerSample": 24}
+fn put_config(content: &str) -> (u16, Value) {
+    let update: Value = match serde_json::from_str(content) {
+        Ok(v) => v,
+        Err(e) => return api_error(400, &format!("invalid JSON: {e}")),
+    };
+    let Some(update) = update.as_object() else {
+        return api_error(400, "expected a JSON object");
+    };
+    let mut conf = CONFIG.write();
+    let (new_conf, restart_required) = match merge_config(&conf, update) {
+        Ok(merged) => merged,
+        Err(e) => return e,
+    };
+    if let Err(e) = new_conf.update_config() {
+        return api_error(500, &format!("unable to save the configuration: {e}"));
+    }
+    *conf = new_conf;
+    let mut result = config_json(&conf);
+    result["RestartRequired"] = Value::Bool(restart_required);
+    (200, result)
+}
+
+/// merge_config - the validated configuration with the updated values, and whether
+/// a restart is required
+///
+/// the read-only values can be sent back unchanged, so that a GET result can be PUT
+fn merge_config(
+    conf: &Configuration,
+    update: &Map<String, Value>,
+) -> Result<(Configuration, bool), (u16, Value)> {
+    let mut value = serde_json::to_value(conf).unwrap_or_default();
+    let current = value.as_object_mut().unwrap();
+    let mut restart_required = false;
+    for (key, new_value) in update {
+        if READ_ONLY_KEYS.contains(&key.as_str()) {
+            if current.get(key) != Some(new_value) {
+                return Err(api_error(400, &format!("{key} can't be changed")));
+            }
+            continue;
+        }
+        match current.get_mut(key) {
+            Some(old_value) => {
+                if old_value != new_value && RESTART_KEYS.contains(&key.as_str()) {
+                    ui_log(format!(
+                        "*W*W*> {key} changed to {new_value}, restart required!!"
+                    ));
+                    restart_required = true;
+                }
+                *old_value = new_value.clone();
+            }
+            None => {
+                return Err(api_error(
+                    400,
+                    &format!("unknown configuration value {key}"),
+                ))
+            }
+        }
+    }
+    let mut new_conf: Configuration = match serde_json::from_value(value) {
+        Ok(c) => c,
+        Err(e) => return Err(api_error(400, &format!("invalid configuration value: {e}"))),
+    };
+    if !matches!(new_conf.bits_per_sample, Some(16 | 24 | 32)) {
+        return Err(api_error(400, "BitsPerSample must be 16, 24 or 32"));
+    }
+    if new_conf.ssdp_interval_mins < 0.5 {
+        return Err(api_error(400, "SSDPIntervalMins must be at least 0.5"));
+    }
+    if !matches!(new_conf.flac_compression_level, Some(0..=8)) {
+        return Err(api_error(400, "FlacCompressionLevel must be 0..8"));
+    }
+    if !matches!(new_conf.flac_block_size, Some(0 | 16..=65535)) {
+        return Err(api_error(400, "FlacBlockSize must be 0 or 16..65535"));
+    }
+    if !new_conf
+        .keep_alive_noise_level
+        .is_some_and(|db| (-96.0..=-20.0).contains(&db))
+    {
+        return Err(api_error(400, "KeepAliveNoiseLevel must be -96..-20 dB"));
+    }
+    if !matches!(new_conf.client_queue_ms, Some(100..=60000)) {
+        return Err(api_error(400, "ClientQueueMs must be 100..60000"));
+    }
+    if !matches!(new_conf.drift_target_ms, Some(20..=10000)) {
+        return Err(api_error(400, "DriftTargetMs must be 20..10000"));
+    }
+    if !matches!(new_conf.pre_roll_ms, Some(0..=MAX_PRE_ROLL_MS)) {
+        return Err(api_error(
+            400,
+            &format!("PreRollMs must be 0..{MAX_PRE_ROLL_MS}"),
+        ));
+    }
+    if let Err(e) = new_conf
+        .audio_source
+        .as_deref()
+        .unwrap_or_default()
+        .parse::<SourceSpec>()
+    {
+        return Err(api_error(400, &format!("AudioSource: {e}")));
+    }
+    for (source, settings) in new_conf.capture_config.iter().flatten() {
+        if let Err(e) = settings.validate() {
+            return Err(api_error(400, &format!("CaptureConfig {source}: {e}")));
+        }
+    }
+    if !matches!(
+        new_conf.recording_format,
+        Some(StreamingFormat::Flac | StreamingFormat::Wav)
+    ) {
+        return Err(api_error(400, "RecordingFormat must be Flac or Wav"));
+    }
+    // keep UseWaveFormat in sync with the streaming format, like the GUI does
+    if let Some(format) = new_conf.streaming_format {
+        new_conf.use_wave_format = format == StreamingFormat::Wav;
+    }
+    Ok((new_conf, restart_required))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(json: Value) -> Map<String, Value> {
+        json.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn read_only_values() {
+        let conf = Configuration::new();
+        // a GET result can be sent back
+        let get = config_json(&conf);
+        assert!(merge_config(&conf, &update(get)).is_ok());
+        let (merged, restart_required) =
+            merge_config(&conf, &update(json!({"AutoResume": true}))).unwrap();
+        assert!(merged.auto_resume && !restart_required);
+        // a read-only value can't be changed
+        let (status, _) = merge_config(&conf, &update(json!({"ConfigId": "other"}))).unwrap_err();
+        assert_eq!(status, 400);
+        let (status, _) = merge_config(&conf, &update(json!({"ApiToken": "guess"}))).unwrap_err();
+        assert_eq!(status, 400);
+    }
+
+    #[test]
+    fn no_commands_or_paths() {
+        let conf = Configuration::new();
+        for update_value in [
+            json!({"IcyMetaCommand": "touch /tmp/pwned"}),
+            json!({"IcyMetaFile": "/etc/passwd"}),
+            json!({"RecordingDir": "/etc"}),
+            json!({"AudioSource": "file:/etc/passwd"}),
+        ] {
+            let (status, _) = merge_config(&conf, &update(update_value)).unwrap_err();
+            assert_eq!(status, 400);
+        }
+    }
+}
@@ -1 +1,4 @@
+pub mod api;
+pub mod playlist;
 pub mod streaming_server;
+pub mod webui;
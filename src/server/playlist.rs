@@ -0,0 +1,81 @@
+///
+/// playlist.rs
+///
+/// M3U and PLS playlists with the stream url, for generic players like VLC, mpv or foobar2000
+///
+use crate::{
+    enums::streaming::StreamingFormat,
+    server::streaming_server::{get_header, get_query_param},
+    utils::ui_logger::ui_log,
+};
+use log::debug;
+use std::net::IpAddr;
+use tiny_http::{Header, Request, Response};
+
+/// handle_playlist_request - serve /swyh.m3u or /swyh.pls
+///
+/// an optional "format" query parameter is passed on to the stream url
+pub fn handle_playlist_request(rq: Request, local_addr: IpAddr, server_port: u16) {
+    let remote_addr = format!("{}", rq.remote_addr().unwrap());
+    // use the address the player used to reach us, it can differ from the configured network
+    let host = get_header(&rq, "Host").unwrap_or_else(|| format!("{local_addr}:{server_port}"));
+    let format = get_query_param(rq.url(), "format").and_then(|f| f.parse().ok());
+    let url = stream_url(&host, format);
+    debug!("Playlist {} for {}: {}", rq.url(), remote_addr, url);
+    let (ct_text, playlist) = if rq.url().starts_with("/swyh.pls") {
+        ("audio/x-scpls", playlist_pls(&url))
+    } else {
+        ("audio/x-mpegurl", playlist_m3u(&url))
+    };
+    let ct_hdr = Header::from_bytes(&b"Content-Type"[..], ct_text.as_bytes()).unwrap();
+    let cc_hdr = Header::from_bytes(&b"Connection"[..], &b"close"[..]).unwrap();
+    let response = Response::from_string(playlist)
+        .with_header(ct_hdr)
+        .with_header(cc_hdr);
+    if let Err(e) = rq.respond(response) {
+        ui_log(format!(
+            "=>Http playlist connection with {remote_addr} terminated [{e}]"
+        ));
+    }
+}
+
+/// stream_url - the url of the stream, in the requested format or the configured format
+pub fn stream_url(host: &str, format: Option<StreamingFormat>) -> String {
+    match format {
+        Some(format) => format!(
+            "http://{host}/stream/swyh.wav?format={}",
+            format.to_string().to_lowercase()
+        ),
+        None => format!("http://{host}/stream/swyh.wav"),
+    }
+}
+
+fn playlist_m3u(url: &str) -> String {
+    format!("#EXTM3U\r\n#EXTINF:-1,swyh-rs\r\n{url}\r\n")
+}
+
+fn playlist_pls(url: &str) -> String {
+    format!("[playlist]\r\nNumberOfEntries=1\r\nFile1={url}\r\nTitle1=swyh-rs\r\nLength1=-1\r\nVersion=2\r\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn playlists() {
+        let url = stream_url("192.168.1.10:5901", Some(StreamingFormat::Flac));
+        assert_eq!(url, "http://192.168.1.10:5901/stream/swyh.wav?format=flac");
+        assert_eq!(
+            stream_url("192.168.1.10:5901", None),
+            "http://192.168.1.10:5901/stream/swyh.wav"
+        );
+        let m3u = playlist_m3u(&url);
+        assert!(m3u.starts_with("#EXTM3U\r\n"));
+        assert!(m3u.ends_with(&format!("{url}\r\n")));
+        let pls = playlist_pls(&url);
+        assert!(pls.starts_with("[playlist]\r\n"));
+        assert!(pls.contains(&format!("File1={url}\r\n")));
+        assert!(pls.contains("NumberOfEntries=1\r\n"));
+    }
+}
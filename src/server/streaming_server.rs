@@ -1,14 +1,27 @@
 use crate::{
     enums::streaming::{StreamingFormat, StreamingState},
-    globals::statics::{CLIENTS, CONFIG},
+    globals::statics::{CLIENTS, CONFIG, PRE_ROLL},
     openhome::rendercontrol::WavData,
-    utils::{rwstream::ChannelStream, ui_logger::ui_log},
+    server::{
+        api::handle_api_request,
+        playlist::handle_playlist_request,
+        webui::{handle_landing_request, handle_webui_request},
+    },
+    utils::{
+        icymeta::ICY_METAINT, rwstream::ChannelStream, streamencoder::new_encoder,
+        ui_logger::ui_log,
+    },
 };
-use crossbeam_channel::{unbounded, Receiver, Sender};
+use crossbeam_channel::Sender;
 use fltk::app;
 use log::debug;
-use std::{net::IpAddr, sync::Arc};
-use tiny_http::{Header, Method, Response, Server};
+use std::{
+    io::{self, Read},
+    net::IpAddr,
+    sync::Arc,
+    time::Duration,
+};
+use tiny_http::{Header, Method, Request, Response, Server, StatusCode};
 
 /// streaming state feedback for a client
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -19,10 +32,11 @@ pub struct StreamerFeedBack {
 
 /// run_server - run a tiny-http webserver to serve streaming requests from renderers
 ///
-/// all music is sent in audio/l16 PCM format (i16) with the sample rate of the source
-/// the samples are read from a crossbeam channel fed by the wave_reader
+/// the music is sent in the streaming format of the request by its StreamEncoder,
+/// with the sample rate and channels configured for the renderer (by default those of the source)
+/// the samples are read from a crossbeam channel fed by the capture distributor
 /// a ChannelStream is created for this purpose, and inserted in the array of active
-/// "clients" for the wave_reader
+/// "clients" for the capture distributor
 pub fn run_server(
     local_addr: &IpAddr,
     server_port: u16,
@@ -43,6 +57,12 @@ pub fn run_server(
     };
     ui_log(logmsg);
     let server = Arc::new(Server::http(addr).unwrap());
+    let watchdog_tx = feedback_tx.clone();
+    std::thread::Builder::new()
+        .name("client_watchdog".into())
+        .spawn(move || client_watchdog(watchdog_tx))
+        .unwrap();
+    let local_addr = *local_addr;
     let mut handles = Vec::new();
     // always have two threads ready to serve new requests
     for _ in 0..2 {
@@ -71,10 +91,32 @@ pub fn run_server(
                             .unwrap();
                     let nm_hdr = Header::from_bytes(&b"icy-name"[..], &b"swyh-rs"[..]).unwrap();
                     let cc_hdr = Header::from_bytes(&b"Connection"[..], &b"close"[..]).unwrap();
-                    // don't accept range headers (Linn) until I know how to handle them
-                    let acc_rng_hdr = Header::from_bytes(&b"Accept-Ranges"[..], &b"none"[..]).unwrap();
+                    // only "bytes=0-" ranges can be served from a live stream, see parse_range()
+                    let acc_rng_hdr = Header::from_bytes(&b"Accept-Ranges"[..], &b"bytes"[..]).unwrap();
+                    // the JSON REST API
+                    if rq.url() == "/api" || rq.url().starts_with("/api/") {
+                        handle_api_request(rq, local_addr, server_port, wd, &feedback_tx_c);
+                        return;
+                    }
+                    // the browser control UI, the landing page and the playlists
+                    let path = rq.url().split('?').next().unwrap_or_default().to_string();
+                    match path.as_str() {
+                        "/ui" | "/ui/" => {
+                            handle_webui_request(rq);
+                            return;
+                        }
+                        "/" | "/index.html" => {
+                            handle_landing_request(rq);
+                            return;
+                        }
+                        "/swyh.m3u" | "/swyh.pls" => {
+                            handle_playlist_request(rq, local_addr, server_port);
+                            return;
+                        }
+                        _ => {}
+                    }
                     // check url
-                    if rq.url() != "/stream/swyh.wav" {
+                    if path != "/stream/swyh.wav" {
                         ui_log(format!(
                             "Unrecognized request '{}' from {}'",
                             rq.url(),
@@ -91,29 +133,84 @@ pub fn run_server(
                         }
                         return;
                     }
-                    // get remote ip
-                    let remote_addr = format!("{}", rq.remote_addr().unwrap());
-                    let mut remote_ip = remote_addr.clone();
-                    if let Some(i) = remote_ip.find(':') {
-                        remote_ip.truncate(i);
-                    }
-                    // prpare streaming headers
+                    // prepare streaming headers
                     let conf = CONFIG.read().clone();
-                    let format = conf.streaming_format.unwrap();
-                    let ct_text = if format == StreamingFormat::Flac {
-                        "audio/flac".to_string()
-                    } else if conf.use_wave_format {
-                        "audio/vnd.wave;codec=1".to_string()
-                    } else { // LPCM
-                        if conf.bits_per_sample == Some(16) {
-                            format!("audio/L16;rate={};channels=2", wd.sample_rate.0) 
-                        } else {
-                            format!("audio/L24;rate={};channels=2", wd.sample_rate.0) 
-                        }
-                    };
-                    let ct_hdr = Header::from_bytes(&b"Content-Type"[..], ct_text.as_bytes()).unwrap();
+                    let mut format = conf.streaming_format.unwrap();
+                    // generic players can ask for a specific format with a "format" query parameter
+                    if let Some(value) = get_query_param(rq.url(), "format") {
+                        match value.parse::<StreamingFormat>() {
+                            Ok(f) => format = f,
+                            Err(e) => debug!("{e} requested by {remote_addr}, using the configured format"),
+                        }
+                    }
+                    // the captured samples are resampled if the renderer needs another sample rate
+                    let sample_rate = conf.output_sample_rate(&remote_ip, wd.sample_rate.0);
+                    // multichannel passthrough if enabled for the renderer, stereo otherwise
+                    let channels = conf.output_channels(&remote_ip, wd.channels, format);
+                    let encoder = new_encoder(format, sample_rate, conf.bits_per_sample.unwrap(), channels);
+                    let ct_hdr = Header::from_bytes(&b"Content-Type"[..], encoder.mime_type().as_bytes()).unwrap();
                     let tm_hdr =
                         Header::from_bytes(&b"TransferMode.DLNA.ORG"[..], &b"Streaming"[..]).unwrap();
+                    let cf_hdr = Header::from_bytes(
+                        &b"contentFeatures.dlna.org"[..],
+                        encoder.content_features().as_bytes(),
+                    )
+                    .unwrap();
+                    // a live stream can't be seeked in time (DLNA 7.4.40.3)
+                    if get_header(&rq, "TimeSeekRange.dlna.org").is_some() {
+                        debug!("TimeSeekRange rq from {}", remote_addr);
+                        let response = Response::empty(406)
+                            .with_header(cc_hdr)
+                            .with_header(srvr_hdr)
+                            .with_header(nm_hdr);
+                        if let Err(e) = rq.respond(response) {
+                            ui_log(format!(
+                                "=>Http connection with {remote_addr} terminated [{e}]"
+                            ));
+                        }
+                        return;
+                    }
+                    // only the start of a live stream can be served
+                    let range = get_header(&rq, "Range")
+                        .map(|r| parse_range(&r))
+                        .unwrap_or(ByteRange::All);
+                    if range == ByteRange::Unsatisfiable {
+                        // no Content-Range header: the complete length of a live stream is unknown
+                        debug!("Unsatisfiable Range rq from {}", remote_addr);
+                        let response = Response::empty(416)
+                            .with_header(cc_hdr)
+                            .with_header(srvr_hdr)
+                            .with_header(acc_rng_hdr)
+                            .with_header(nm_hdr);
+                        if let Err(e) = rq.respond(response) {
+                            ui_log(format!(
+                                "=>Http connection with {remote_addr} terminated [{e}]"
+                            ));
+                        }
+                        return;
+                    }
+                    // HEAD and GET get the same status and headers
+                    let (status, streamsize, chunked_threshold, content_range) =
+                        range_response(range, conf.disable_chunked);
+                    let mut headers = vec![
+                        cc_hdr.clone(),
+                        ct_hdr,
+                        tm_hdr,
+                        cf_hdr,
+                        srvr_hdr.clone(),
+                        acc_rng_hdr,
+                        nm_hdr.clone(),
+                    ];
+                    // ICY metadata for internet radio style clients
+                    let icy_metadata = get_header(&rq, "Icy-MetaData").is_some_and(|v| v.trim() == "1");
+                    if icy_metadata {
+                        headers.push(
+                            Header::from_bytes(&b"icy-metaint"[..], ICY_METAINT.to_string().as_bytes()).unwrap(),
+                        );
+                    }
+                    if let Some(cr_text) = content_range {
+                        headers.push(Header::from_bytes(&b"Content-Range"[..], cr_text.as_bytes()).unwrap());
+                    }
                     // handle response, streaming if GET, headers only otherwise
                     if matches!(rq.method(), Method::Get) {
                         ui_log(format!(
@@ -121,29 +218,53 @@ pub fn run_server(
                             rq.url(),
                             rq.remote_addr().unwrap()
                         ));
-                        // set transfer encoding chunked unless disabled
-                        let (streamsize, chunked_threshold) = {
-                            if conf.disable_chunked {
-                                (Some(usize::MAX - 1), usize::MAX)
-                            } else {
-                                (None, 8192)
-                            }
-                        };
-                        let (tx, rx): (Sender<Vec<f32>>, Receiver<Vec<f32>>) = unbounded();
-                        let channel_stream = ChannelStream::new(
-                            tx,
-                            rx,
+                        let mut channel_stream = match ChannelStream::new(
                             remote_ip.clone(),
-                            conf.use_wave_format,
                             wd.sample_rate.0,
-                            conf.bits_per_sample.unwrap(),
-                            conf.streaming_format.unwrap(),
-                        );
-                        let nclients = {
+                            sample_rate,
+                            encoder.bits_per_sample(),
+                            channels,
+                            encoder.clone(),
+                        ) {
+                            Ok(channel_stream) => channel_stream,
+                            Err(e) => {
+                                ui_log(format!("*E*E*> {e}"));
+                                let response = Response::empty(500)
+                                    .with_header(cc_hdr)
+                                    .with_header(srvr_hdr)
+                                    .with_header(nm_hdr);
+                                let _ = rq.respond(response);
+                                return;
+                            }
+                        };
+                        if sample_rate != wd.sample_rate.0 {
+                            ui_log(format!(
+                                "Resampling from {} to {sample_rate} Hz for {remote_addr}",
+                                wd.sample_rate.0
+                            ));
+                        }
+                        if channel_stream.drift_ppm().is_some() {
+                            ui_log(format!(
+                                "Drift compensation for {remote_addr}, target {} ms",
+                                conf.drift_target_ms()
+                            ));
+                        }
+                        if icy_metadata {
+                            debug!("ICY metadata requested by {}", remote_addr);
+                            channel_stream.enable_icy_metadata();
+                        }
+                        let (nclients, pre_roll_ms) = {
                             let mut clients = CLIENTS.write();
+                            // under the CLIENTS lock, see preroll.rs
+                            let pre_roll_ms = channel_stream.write_pre_roll(&PRE_ROLL.lock());
                             clients.insert(remote_addr.clone(), channel_stream.clone());
-                            clients.len()
+                            (clients.len(), pre_roll_ms)
                         };
+                        if pre_roll_ms > 0 {
+                            ui_log(format!("Sending {pre_roll_ms} ms pre-roll to {remote_addr}"));
+                        } else if channel_stream.is_low_latency() {
+                            ui_log(format!("Low latency streaming to {remote_addr}"));
+                        }
                         debug!("Now have {} streaming clients", nclients);
 
                         feedback_tx_c
@@ -153,66 +274,59 @@ pub fn run_server(
                             })
                             .unwrap();
                         std::thread::yield_now();
-                        let streaming_format = if format == StreamingFormat::Flac {
-                            "audio/FLAC"
-                        } else if format == StreamingFormat::Wav {
-                            "audio/wave;codec=1 (WAV)"
-                        } else { // LPCM
-                            if conf.bits_per_sample == Some(16) {
-                                "audio/L16 (LPCM)"
-                            } else {
-                                "audio/L24 (LPCM)"
-                            }
-                        };
                         ui_log(format!(
-                            "Streaming {streaming_format}, input sample format {:?}, channels=2, rate={}, disable chunked={} to {}",
+                            "Streaming {}, input sample format {:?}, channels={}, rate={}, disable chunked={} to {}",
+                            encoder.description(),
                             wd.sample_format,
-                            wd.sample_rate.0,
+                            channels,
+                            sample_rate,
                             conf.disable_chunked,
                             rq.remote_addr().unwrap()
                         ));
-                        let response = Response::empty(200)
-                            .with_data(channel_stream, streamsize)
-                            .with_chunked_threshold(chunked_threshold)
-                            .with_header(cc_hdr)
-                            .with_header(ct_hdr)
-                            .with_header(tm_hdr)
-                            .with_header(srvr_hdr)
-                            .with_header(acc_rng_hdr)
-                            .with_header(nm_hdr);
+                        // a partial range request gets exactly the requested number of bytes
+                        let body = channel_stream.take(streamsize.unwrap_or(usize::MAX) as u64);
+                        let response = Response::new(StatusCode(status), headers, body, streamsize, None)
+                            .with_chunked_threshold(chunked_threshold);
                         let e = rq.respond(response);
                         if e.is_err() {
                             ui_log(format!(
                                 "=>Http connection with {remote_addr} terminated [{e:?}]"
                             ));
                         }
-                        let nclients = {
+                        let (removed, nclients) = {
                             let mut clients = CLIENTS.write();
-                            if let Some(chs) = clients.remove(&remote_addr) { chs.stop_flac_encoder() };
-                            clients.len()
+                            let removed = if let Some(chs) = clients.remove(&remote_addr) {
+                                chs.stop_encoder();
+                                if let Some(ppm) = chs.drift_ppm() {
+                                    ui_log(format!("Estimated clock drift of {remote_addr}: {ppm:.1} ppm"));
+                                }
+                                true
+                            } else {
+                                false
+                            };
+                            (removed, clients.len())
                         };
                         debug!("Now have {} streaming clients left", nclients);
                         ui_log(format!("Streaming to {remote_addr} has ended"));
                         // inform the main thread that this renderer has finished receiving
                         // necessary if the connection close was not caused by our own GUI
                         // so that we can update the corresponding button state
-                        feedback_tx_c
-                            .send(StreamerFeedBack {
-                                remote_ip,
-                                streaming_state: StreamingState::Ended,
-                            })
-                            .unwrap();
-                        app::awake();
+                        // (the client_watchdog has already done so if it removed the client)
+                        if removed {
+                            feedback_tx_c
+                                .send(StreamerFeedBack {
+                                    remote_ip,
+                                    streaming_state: StreamingState::Ended,
+                                })
+                                .unwrap();
+                            app::awake();
+                        }
                         std::thread::yield_now();
                     } else if matches!(rq.method(), Method::Head) {
                         debug!("HEAD rq from {}", remote_addr);
-                        let response = Response::empty(200)
-                            .with_header(cc_hdr)
-                            .with_header(ct_hdr)
-                            .with_header(tm_hdr)
-                            .with_header(srvr_hdr)
-                            .with_header(acc_rng_hdr)
-                            .with_header(nm_hdr);
+                        // tiny_http doesn't send a body in response to a HEAD request
+                        let response = Response::new(StatusCode(status), headers, io::empty(), streamsize, None)
+                            .with_chunked_threshold(chunked_threshold);
                         if let Err(e) = rq.respond(response) {
                             ui_log(format!(
                                 "=>Http HEAD connection with {remote_addr} terminated [{e}]"
@@ -239,3 +353,186 @@ pub fn run_server(
         h.join().unwrap();
     }
 }
+
+/// client_watchdog - remove the clients that were disconnected by a queue overflow,
+/// or that haven't read their queued audio for StallTimeout seconds
+///
+/// a renderer that stops reading but keeps the connection open would otherwise stay
+/// in CLIENTS forever
+fn client_watchdog(feedback_tx: Sender<StreamerFeedBack>) {
+    loop {
+        std::thread::sleep(Duration::from_secs(1));
+        let removed: Vec<String> = CLIENTS
+            .read()
+            .iter()
+            .filter(|(_, chs)| chs.is_disconnected() || chs.is_stalled())
+            .map(|(remote_addr, _)| remote_addr.clone())
+            .collect();
+        for remote_addr in removed {
+            let Some(chs) = CLIENTS.write().remove(&remote_addr) else {
+                continue;
+            };
+            if chs.is_disconnected() {
+                ui_log(format!(
+                    "*W*W*> Removed {remote_addr} after a queue overflow"
+                ));
+            } else {
+                ui_log(format!(
+                    "*W*W*> Removed {remote_addr}, it has stopped reading the stream"
+                ));
+            }
+            chs.disconnect();
+            chs.stop_encoder();
+            let _ = feedback_tx.send(StreamerFeedBack {
+                remote_ip: chs.remote_ip.clone(),
+                streaming_state: StreamingState::Ended,
+            });
+            app::awake();
+        }
+    }
+}
+
+/// get_query_param - get the value of a query parameter of a request url
+pub fn get_query_param(url: &str, name: &str) -> Option<String> {
+    let (_, query) = url.split_once('?')?;
+    query
+        .split('&')
+        .filter_map(|kv| kv.split_once('='))
+        .find(|(k, _)| *k == name)
+        .map(|(_, v)| v.to_string())
+}
+
+/// the byte range requested by a renderer in the HTTP Range header
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum ByteRange {
+    /// no (usable) Range header: send the stream
+    All,
+    /// "bytes=0-" (the stream up to the largest length) or "bytes=0-last" (the start of the stream up to the last byte)
+    FromStart(Option<usize>),
+    /// any other range can't be satisfied by a live stream
+    Unsatisfiable,
+}
+
+/// range_response - the status, the Content-Length, the chunked threshold and the
+/// Content-Range of the response to a satisfiable range
+///
+/// a range starting at 0 gets a 206 with a Content-Length and no chunked transfer encoding,
+/// "bytes=0-" the largest length, as the complete length of a live stream is unknown ("*")
+/// without a range the transfer encoding is chunked unless disabled
+fn range_response(
+    range: ByteRange,
+    disable_chunked: bool,
+) -> (u16, Option<usize>, usize, Option<String>) {
+    match range {
+        ByteRange::FromStart(last) => {
+            let size = last
+                .map_or(usize::MAX, |last| last.saturating_add(1))
+                .min(usize::MAX - 1);
+            (
+                206,
+                Some(size),
+                usize::MAX,
+                Some(format!("bytes 0-{}/*", size - 1)),
+            )
+        }
+        _ if disable_chunked => (200, Some(usize::MAX - 1), usize::MAX, None),
+        _ => (200, None, 8192, None),
+    }
+}
+
+/// get_header - get the value of a request header (header names are case insensitive)
+pub fn get_header(rq: &Request, name: &'static str) -> Option<String> {
+    rq.headers()
+        .iter()
+        .find(|h| h.field.equiv(name))
+        .map(|h| h.value.as_str().trim().to_string())
+}
+
+/// parse_range - parse the value of a Range header
+///
+/// a live stream has no length and can't be seeked, so only ranges that start at 0 are satisfiable,
+/// anything that isn't a single byte range is ignored as required by RFC 9110
+fn parse_range(value: &str) -> ByteRange {
+    let Some(spec) = value.strip_prefix("bytes=") else {
+        return ByteRange::All;
+    };
+    if spec.contains(',') {
+        return ByteRange::All;
+    }
+    let Some((first, last)) = spec.trim().split_once('-') else {
+        return ByteRange::All;
+    };
+    let (first, last) = (first.trim(), last.trim());
+    if first.is_empty() {
+        // a suffix range (the last n bytes) of an endless stream
+        return match last.parse::<usize>() {
+            Ok(_) => ByteRange::Unsatisfiable,
+            Err(_) => ByteRange::All,
+        };
+    }
+    match first.parse::<usize>() {
+        Ok(0) if last.is_empty() => ByteRange::FromStart(None),
+        Ok(0) => match last.parse::<usize>() {
+            Ok(last) => ByteRange::FromStart(Some(last)),
+            Err(_) => ByteRange::All,
+        },
+        Ok(_) => ByteRange::Unsatisfiable,
+        Err(_) => ByteRange::All,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn range() {
+        assert_eq!(parse_range("bytes=0-"), ByteRange::FromStart(None));
+        assert_eq!(parse_range("bytes= 0-"), ByteRange::FromStart(None));
+        assert_eq!(
+            parse_range("bytes=0-1023"),
+            ByteRange::FromStart(Some(1023))
+        );
+        assert_eq!(parse_range("bytes=1024-"), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=100-200"), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-500"), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-10,20-30"), ByteRange::All);
+        assert_eq!(parse_range("bytes=a-"), ByteRange::All);
+        assert_eq!(parse_range("items=0-"), ByteRange::All);
+    }
+
+    #[test]
+    fn range_responses() {
+        let open_ended = range_response(ByteRange::FromStart(None), false);
+        assert_eq!(open_ended.0, 206);
+        assert_eq!(open_ended.1, Some(usize::MAX - 1));
+        assert_eq!(open_ended.2, usize::MAX);
+        assert_eq!(open_ended.3, Some(format!("bytes 0-{}/*", usize::MAX - 2)));
+        assert_eq!(
+            range_response(ByteRange::FromStart(Some(16383)), false),
+            (
+                206,
+                Some(16384),
+                usize::MAX,
+                Some("bytes 0-16383/*".to_string())
+            )
+        );
+        assert_eq!(
+            range_response(ByteRange::All, false),
+            (200, None, 8192, None)
+        );
+        assert_eq!(
+            range_response(ByteRange::All, true),
+            (200, Some(usize::MAX - 1), usize::MAX, None)
+        );
+    }
+
+    #[test]
+    fn query_param() {
+        let url = "/stream/swyh.wav?format=flac&x=1";
+        assert_eq!(get_query_param(url, "format"), Some("flac".to_string()));
+        assert_eq!(get_query_param(url, "x"), Some("1".to_string()));
+        assert_eq!(get_query_param(url, "y"), None);
+        assert_eq!(get_query_param("/stream/swyh.wav", "format"), None);
+    }
+}
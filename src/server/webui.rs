@@ -0,0 +1,36 @@
+///
+/// webui.rs
+///
+/// the browser based control UI, a single page without external assets that uses the /api endpoints,
+/// and the landing page with the stream urls and playlists and an HTML5 player
+///
+use crate::{globals::statics::APP_VERSION, utils::ui_logger::ui_log};
+use tiny_http::{Header, Request, Response};
+
+const WEBUI_HTML: &str = include_str!("webui.html");
+const LANDING_HTML: &str = include_str!("landing.html");
+
+/// handle_webui_request - serve the web UI page
+pub fn handle_webui_request(rq: Request) {
+    serve_page(rq, WEBUI_HTML);
+}
+
+/// handle_landing_request - serve the landing page
+pub fn handle_landing_request(rq: Request) {
+    serve_page(rq, LANDING_HTML);
+}
+
+fn serve_page(rq: Request, html: &str) {
+    let remote_addr = format!("{}", rq.remote_addr().unwrap());
+    let ct_hdr =
+        Header::from_bytes(&b"Content-Type"[..], &b"text/html; charset=utf-8"[..]).unwrap();
+    let cc_hdr = Header::from_bytes(&b"Connection"[..], &b"close"[..]).unwrap();
+    let response = Response::from_string(html.replace("{APP_VERSION}", APP_VERSION))
+        .with_header(ct_hdr)
+        .with_header(cc_hdr);
+    if let Err(e) = rq.respond(response) {
+        ui_log(format!(
+            "=>Http web UI connection with {remote_addr} terminated [{e}]"
+        ));
+    }
+}
@@ -3,7 +3,11 @@ use crate::{
     globals::statics::CONFIG,
     openhome::rendercontrol::{Renderer, StreamInfo, WavData},
     utils::{
+        audiodevices::{capture_choices, find_output_device, get_default_audio_output_device},
+        audiosource::SourceSpec,
         configuration::Configuration,
+        recorder::{start_recording, stop_recording},
+        testsignal::Signal,
         traits::FwSlashPipeEscape,
         ui_logger::{enable_ui_log, ui_log},
     },
@@ -11,6 +15,7 @@ use crate::{
 use fltk::{
     app,
     button::{CheckButton, LightButton},
+    dialog,
     enums::{Align, Color, Event, FrameType},
     frame::Frame,
     group::{Pack, PackType},
@@ -35,9 +40,11 @@ pub struct MainForm {
     pub ssdp_interval: Counter,
     pub log_level_choice: MenuButton,
     pub fmt_choice: MenuButton,
-    pub b24_bit: CheckButton,
+    pub bps_choice: MenuButton,
+    pub float_samples: CheckButton,
     pub disable_chunked: CheckButton,
     pub show_rms: CheckButton,
+    pub record: CheckButton,
     pub rms_mon_l: Progress,
     pub rms_mon_r: Progress,
     pub choose_audio_source_but: MenuButton,
@@ -169,38 +176,94 @@ impl MainForm {
         // setup audio source choice
         let mut pas = Pack::new(0, 0, GW, 25, "");
         pas.end();
-        let cur_audio_src = format!("Audio Source: {}", config.sound_source);
+        // a pipe, a file or a test signal instead of the capture device (AudioSource)
+        let cur_audio_src = match config.audio_source.as_deref() {
+            Some(spec) if spec != "capture" => format!("Audio Source: {spec}"),
+            _ => format!("Audio Source: {}", config.sound_source),
+        };
         ui_log("Setup audio sources".to_string());
         let mut choose_audio_source_but =
             MenuButton::new(0, 0, 0, 25, None).with_label(&cur_audio_src);
         for name in audio_sources.iter() {
             choose_audio_source_but.add_choice(&name.fw_slash_pipe_escape());
         }
+        let test_signals = [Signal::Sweep, Signal::PinkNoise, Signal::ChannelIdent];
+        for signal in test_signals.iter() {
+            choose_audio_source_but.add_choice(&format!("Test signal: {signal}"));
+        }
+        // a raw PCM pipe or an audio file is typed as an AudioSource spec
+        choose_audio_source_but.add_choice("Pipe or file source...");
         let rlock = Mutex::new(0);
         let config_ch_flag = config_changed.clone();
         let audio_sources_c = audio_sources.to_vec();
+        let channels = wd.channels;
         choose_audio_source_but.set_callback(move |b| {
             let mut recursion = rlock.lock();
             if *recursion > 0 {
                 return;
             }
             *recursion += 1;
-            let mut conf = CONFIG.write();
-            let mut i = b.value();
+            let i = b.value();
             if i < 0 {
+                *recursion -= 1;
                 return;
             }
-            if i as usize >= audio_sources_c.len() {
-                i = (audio_sources_c.len() - 1) as i32;
-            }
-            let name = audio_sources_c[i as usize].clone();
+            let signal_index = (i as usize).checked_sub(audio_sources_c.len());
+            let spec = match signal_index {
+                None => None,
+                Some(s) if s < test_signals.len() => {
+                    let signal = test_signals[s];
+                    // the default rate, the ident gets the channels of the current source
+                    Some(SourceSpec::Tone {
+                        signal,
+                        sample_rate: 48000,
+                        channels: if signal == Signal::ChannelIdent {
+                            channels
+                        } else {
+                            2
+                        },
+                    })
+                }
+                Some(_) => {
+                    // not with the CONFIG lock, the dialog runs the event loop
+                    let current = CONFIG.read().audio_source.clone().unwrap_or_default();
+                    let Some(input) = dialog::input_default(
+                        "Audio source: pcm:rate:channels:format:path or file:path",
+                        &current,
+                    ) else {
+                        *recursion -= 1;
+                        return;
+                    };
+                    match input.parse::<SourceSpec>() {
+                        Ok(spec) => Some(spec),
+                        Err(e) => {
+                            ui_log(format!("*E*E*> Invalid audio source {input}: {e}"));
+                            *recursion -= 1;
+                            return;
+                        }
+                    }
+                }
+            };
+            let mut conf = CONFIG.write();
+            let name = match spec {
+                None => {
+                    let name = audio_sources_c[i as usize].clone();
+                    conf.sound_source = name.clone();
+                    conf.sound_source_index = Some(i);
+                    conf.audio_source = Some("capture".to_string());
+                    name
+                }
+                Some(spec) => {
+                    let spec = spec.to_string();
+                    conf.audio_source = Some(spec.clone());
+                    spec
+                }
+            };
             ui_log(format!(
                 "*W*W*> Audio source changed to {name}, restart required!!"
             ));
-            conf.sound_source = name;
-            conf.sound_source_index = Some(i);
             let _ = conf.update_config();
-            b.set_label(&format!("New Audio Source: {}", conf.sound_source));
+            b.set_label(&format!("New Audio Source: {name}"));
             config_ch_flag.set(true);
             app::awake();
             *recursion -= 1;
@@ -208,6 +271,86 @@ impl MainForm {
         pas.add(&choose_audio_source_but);
         vpack.add(&pas);
 
+        // the capture config (rate, format, channels, buffer size) of the captured device
+        let mut pcap = Pack::new(0, 0, GW, 25, "");
+        pcap.set_spacing(10);
+        pcap.set_type(PackType::Horizontal);
+        pcap.end();
+        let capture_device = match config.capture_follow_default {
+            Some(true) => get_default_audio_output_device(),
+            _ => find_output_device(&config.sound_source),
+        };
+        let capture_device_name = capture_device
+            .as_ref()
+            .map(|device| device.name().to_string())
+            .unwrap_or(config.sound_source.clone());
+        let capture_settings = config.capture_settings(&capture_device_name);
+        let choices = capture_device
+            .map(|device| capture_choices(&device.supported_configs()))
+            .unwrap_or_default();
+        let mut capture_choice = MenuButton::new(0, 0, 350, 25, None)
+            .with_label(&format!("Capture Config: {capture_settings}"));
+        capture_choice.add_choice("default");
+        for choice in choices.iter() {
+            capture_choice.add_choice(&choice.to_string());
+        }
+        let rlock = Mutex::new(0);
+        let config_ch_flag = config_changed.clone();
+        let device_name = capture_device_name.clone();
+        capture_choice.set_callback(move |b| {
+            let mut recursion = rlock.lock();
+            if *recursion > 0 {
+                return;
+            }
+            *recursion += 1;
+            let i = b.value();
+            if i < 0 {
+                *recursion -= 1;
+                return;
+            }
+            let mut conf = CONFIG.write();
+            // keep the buffer size
+            let mut settings = i
+                .checked_sub(1)
+                .and_then(|i| choices.get(i as usize).cloned())
+                .unwrap_or_default();
+            settings.buffer_size = conf.capture_settings(&device_name).buffer_size;
+            conf.set_capture_settings(&device_name, settings.clone());
+            ui_log(format!(
+                "*W*W*> Capture config changed to {settings}, restart required!!"
+            ));
+            let _ = conf.update_config();
+            b.set_label(&format!("Capture Config: {settings}"));
+            config_ch_flag.set(true);
+            app::awake();
+            *recursion -= 1;
+        });
+        pcap.add(&capture_choice);
+        // the capture buffer size in frames, 0 is the device default
+        let buffer_label = Frame::new(0, 0, 110, 25, "Buffer (frames):");
+        pcap.add(&buffer_label);
+        let mut capture_buffer = IntInput::new(0, 0, 80, 25, "");
+        capture_buffer.set_value(&capture_settings.buffer_size.unwrap_or(0).to_string());
+        capture_buffer.set_maximum_size(6);
+        let config_ch_flag = config_changed.clone();
+        capture_buffer.set_callback(move |bs| {
+            let frames: u32 = bs.value().parse().unwrap_or(0);
+            let mut conf = CONFIG.write();
+            let mut settings = conf.capture_settings(&capture_device_name);
+            let buffer_size = (frames > 0).then_some(frames);
+            if settings.buffer_size != buffer_size {
+                settings.buffer_size = buffer_size;
+                conf.set_capture_settings(&capture_device_name, settings);
+                ui_log(format!(
+                    "*W*W*> Capture buffer size changed to {frames} frames, restart required!!"
+                ));
+                let _ = conf.update_config();
+                config_ch_flag.set(true);
+            }
+        });
+        pcap.add(&capture_buffer);
+        vpack.add(&pcap);
+
         // all other options
         let mut pconfig1 = Pack::new(0, 0, GW, 20, "");
         pconfig1.set_spacing(10);
@@ -333,6 +476,9 @@ impl MainForm {
             StreamingFormat::Lpcm.to_string(),
             StreamingFormat::Wav.to_string(),
             StreamingFormat::Flac.to_string(),
+            StreamingFormat::Mp3.to_string(),
+            StreamingFormat::Opus.to_string(),
+            StreamingFormat::OggFlac.to_string(),
         ];
         for fmt in formats.iter() {
             fmt_choice.add_choice(fmt.as_str());
@@ -360,6 +506,9 @@ impl MainForm {
                 "LPCM" => StreamingFormat::Lpcm,
                 "WAV" => StreamingFormat::Wav,
                 "FLAC" => StreamingFormat::Flac,
+                "MP3" => StreamingFormat::Mp3,
+                "OPUS" => StreamingFormat::Opus,
+                "OGGFLAC" => StreamingFormat::OggFlac,
                 _ => StreamingFormat::Lpcm,
             };
             conf.use_wave_format = newformat == StreamingFormat::Wav;
@@ -373,23 +522,52 @@ impl MainForm {
         });
         pconfig2.add(&fmt_choice);
 
-        // checkbutton to select 24 bit samples instead of the 16 bit default
-        let mut b24_bit = CheckButton::new(0, 0, 0, 0, "24 bit");
-        if config.bits_per_sample.unwrap() == 24 {
-            b24_bit.set(true);
+        // sample size choice, 16 bit is the default
+        let bps = format!("Bits: {}", config.bits_per_sample.unwrap_or(16));
+        let mut bps_choice = MenuButton::default().with_label(&bps);
+        let sample_sizes: [u16; 3] = [16, 24, 32];
+        for bps in sample_sizes.iter() {
+            bps_choice.add_choice(&bps.to_string());
         }
+        // apparently this event can recurse on very fast machines
+        // probably because it takes some time doing the file I/O, hence recursion lock
+        let rlock = Mutex::new(0);
         let config_ch_flag = config_changed.clone();
-        b24_bit.set_callback(move |b| {
+        bps_choice.set_callback(move |b| {
+            let mut recursion = rlock.lock();
+            if *recursion > 0 {
+                return;
+            }
+            *recursion += 1;
             let mut conf = CONFIG.write();
-            if b.is_set() {
-                conf.bits_per_sample = Some(24);
-            } else {
-                conf.bits_per_sample = Some(16);
+            let i = b.value();
+            if i < 0 {
+                *recursion -= 1;
+                return;
             }
+            let bps = sample_sizes[i as usize];
+            conf.bits_per_sample = Some(bps);
+            let _ = conf.update_config();
+            config_ch_flag.set(true);
+            b.set_label(&format!("Bits: {bps}"));
+            app::awake();
+            *recursion -= 1;
+        });
+        pconfig2.add(&bps_choice);
+
+        // checkbutton to send 32 bit WAV as IEEE float instead of integer samples
+        let mut float_samples = CheckButton::new(0, 0, 0, 0, "Float (WAV)");
+        if config.float_samples.unwrap_or(false) {
+            float_samples.set(true);
+        }
+        let config_ch_flag = config_changed.clone();
+        float_samples.set_callback(move |b| {
+            let mut conf = CONFIG.write();
+            conf.float_samples = Some(b.is_set());
             let _ = conf.update_config();
             config_ch_flag.set(true);
         });
-        pconfig2.add(&b24_bit);
+        pconfig2.add(&float_samples);
         // HTTP server listen port
         let mut listen_port = IntInput::new(0, 0, 0, 0, "HTTP Port:");
         listen_port.set_value(&CONFIG.read().server_port.unwrap_or_default().to_string());
@@ -469,6 +647,30 @@ impl MainForm {
             mon_r.set_value(0.0);
         });
         pconfig3.add(&show_rms);
+        // record the captured audio to the RecordingDir
+        let mut record = CheckButton::new(0, 0, 0, 0, "Record");
+        record.set_callback(move |b| {
+            if b.is_set() {
+                if let Err(e) = start_recording() {
+                    ui_log(format!("*E*E*> {e}"));
+                    b.set(false);
+                }
+            } else {
+                stop_recording();
+            }
+        });
+        pconfig3.add(&record);
+        // capture the default audio output device, also when it changes
+        let mut follow_default = CheckButton::new(0, 0, 0, 0, "Follow default");
+        if config.capture_follow_default.unwrap_or(false) {
+            follow_default.set(true);
+        }
+        follow_default.set_callback(move |b| {
+            let mut conf = CONFIG.write();
+            conf.capture_follow_default = Some(b.is_set());
+            let _ = conf.update_config();
+        });
+        pconfig3.add(&follow_default);
         // vertical pack for the RMS meters
         let mut pconfig3_v = Pack::new(0, 0, GW, 25, "");
         pconfig3_v.set_spacing(4);
@@ -516,9 +718,11 @@ impl MainForm {
             ssdp_interval,
             log_level_choice,
             fmt_choice,
-            b24_bit,
+            bps_choice,
+            float_samples,
             disable_chunked,
             show_rms,
+            record,
             rms_mon_l,
             rms_mon_r,
             choose_audio_source_but,
@@ -573,6 +777,7 @@ impl MainForm {
                 let streaminfo = StreamInfo {
                     sample_rate: wd.sample_rate.0,
                     bits_per_sample: config.bits_per_sample.unwrap(),
+                    channels: wd.channels,
                     streaming_format: config.streaming_format.unwrap(),
                 };
                 let _ = newr_c.play(
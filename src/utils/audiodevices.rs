@@ -1,15 +1,35 @@
 use crate::{
-    globals::statics::{CLIENTS, CONFIG},
-    utils::ui_logger::ui_log,
+    globals::statics::CONFIG,
+    utils::{
+        audiosource::{AudioSource, RunningSource},
+        bincommon::run_silence_injector,
+        capturering::CaptureWriter,
+        ui_logger::ui_log,
+    },
 };
 use cpal::{
-    traits::{DeviceTrait, HostTrait},
-    DefaultStreamConfigError, Sample, SupportedStreamConfig,
+    traits::{DeviceTrait, HostTrait, StreamTrait},
+    BufferSize, DefaultStreamConfigError, SampleFormat, SampleRate, SizedSample, StreamConfig,
+    SupportedBufferSize, SupportedStreamConfig, SupportedStreamConfigRange,
 };
-use crossbeam_channel::Sender;
+use crossbeam_channel::bounded;
 use dasp_sample::ToSample;
 use log::debug;
-use parking_lot::Once;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering::Relaxed},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+/// how often the capture supervisor checks the capture stream and the default device
+const CAPTURE_CHECK_INTERVAL: Duration = Duration::from_millis(500);
 
 /// A [cpal::Device] with either a default input or default output config.
 ///
@@ -96,6 +116,20 @@ impl Device {
     pub fn default_config(&self) -> &SupportedStreamConfig {
         &self.stream_config
     }
+
+    /// All supported stream configs, output or input like the default config
+    pub fn supported_configs(&self) -> Vec<SupportedStreamConfigRange> {
+        match &self.kind {
+            DeviceKind::Output(device) => device
+                .supported_output_configs()
+                .map(|configs| configs.collect())
+                .unwrap_or_default(),
+            DeviceKind::Input(device) => device
+                .supported_input_configs()
+                .map(|configs| configs.collect())
+                .unwrap_or_default(),
+        }
+    }
 }
 
 impl AsRef<cpal::Device> for Device {
@@ -204,95 +238,567 @@ pub fn get_default_audio_output_device() -> Option<Device> {
         .and_then(|device| DeviceKind::Output(device).try_into().ok())
 }
 
-/// capture_audio_output - capture the audio stream from the default audio output device
+/// find_output_device - the device with this name, if it is (still) there
+pub fn find_output_device(name: &str) -> Option<Device> {
+    cpal::available_hosts()
+        .into_iter()
+        .filter_map(|host_id| cpal::host_from_id(host_id).ok())
+        .filter_map(|host| host.devices().ok())
+        .flatten()
+        .find(|device| device.name().is_ok_and(|n| n == name))
+        .and_then(|device| Device::from_device(device).ok())
+}
+
+/// the name of the current default audio output device
+fn default_output_device_name() -> Option<String> {
+    cpal::default_host()
+        .default_output_device()
+        .and_then(|device| device.name().ok())
+}
+
+/// all cpal sample formats
+const SAMPLE_FORMATS: [SampleFormat; 10] = [
+    SampleFormat::I8,
+    SampleFormat::I16,
+    SampleFormat::I32,
+    SampleFormat::I64,
+    SampleFormat::U8,
+    SampleFormat::U16,
+    SampleFormat::U32,
+    SampleFormat::U64,
+    SampleFormat::F32,
+    SampleFormat::F64,
+];
+
+/// the sample rates offered for a supported config range
+const COMMON_SAMPLE_RATES: [u32; 8] = [22050, 32000, 44100, 48000, 88200, 96000, 176400, 192000];
+
+/// parse_sample_format - a cpal sample format by name (i16, f32, ...)
+pub fn parse_sample_format(s: &str) -> Result<SampleFormat, String> {
+    SAMPLE_FORMATS
+        .into_iter()
+        .find(|f| f.to_string() == s.to_lowercase())
+        .ok_or_else(|| format!("unknown sample format {s} (i8/i16/i32/i64/u8/u16/u32/u64/f32/f64)"))
+}
+
+/// CaptureSettings - the capture stream config of an audio source (the CaptureConfig table)
 ///
-/// sets up an input stream for the wave_reader in the appropriate format (f32/i16/u16)
-pub fn capture_output_audio(
-    device_wrap: &Device,
-    rms_sender: Sender<Vec<f32>>,
-) -> Option<cpal::Stream> {
-    let device = device_wrap.as_ref();
-    ui_log(format!(
-        "Capturing audio from: {}",
-        device
-            .name()
-            .expect("Could not get default audio device name")
-    ));
-    let audio_cfg = device_wrap
-        .kind
-        .default_config_any()
-        .expect("No default stream config found");
-    ui_log(format!("Default audio {audio_cfg:?}"));
-    let mut f32_samples: Vec<f32> = Vec::with_capacity(16384);
-    match audio_cfg.sample_format() {
-        cpal::SampleFormat::F32 => match device.build_input_stream(
-            &audio_cfg.config(),
-            move |data, _: &_| wave_reader::<f32>(data, &mut f32_samples, rms_sender.clone()),
-            capture_err_fn,
-            None,
-        ) {
-            Ok(stream) => Some(stream),
-            Err(e) => {
-                ui_log(format!("Error capturing f32 audio stream: {e}"));
-                None
+/// the fields that are not set keep the default of the device, the buffer size is in frames
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CaptureSettings {
+    #[serde(rename(deserialize = "SampleRate", serialize = "SampleRate"))]
+    pub sample_rate: Option<u32>,
+    #[serde(rename(deserialize = "SampleFormat", serialize = "SampleFormat"))]
+    pub sample_format: Option<String>,
+    #[serde(rename(deserialize = "Channels", serialize = "Channels"))]
+    pub channels: Option<u16>,
+    #[serde(rename(deserialize = "BufferSize", serialize = "BufferSize"))]
+    pub buffer_size: Option<u32>,
+}
+
+impl CaptureSettings {
+    /// check the values that don't depend on the device
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(format) = &self.sample_format {
+            parse_sample_format(format)?;
+        }
+        if self
+            .sample_rate
+            .is_some_and(|r| !(8000..=384000).contains(&r))
+        {
+            return Err("the sample rate must be 8000..384000".to_string());
+        }
+        if self.channels.is_some_and(|c| !(1..=8).contains(&c)) {
+            return Err("the channels must be 1..8".to_string());
+        }
+        if self.buffer_size == Some(0) {
+            return Err("the buffer size must be more than 0 frames".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn is_default(&self) -> bool {
+        *self == CaptureSettings::default()
+    }
+}
+
+/// the CLI syntax: {rate}:{format}:{channels}:{buffer frames}, empty fields and trailing
+/// fields that are left out keep the device default, "default" keeps all of them
+impl FromStr for CaptureSettings {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("default") {
+            return Ok(CaptureSettings::default());
+        }
+        let fields: Vec<&str> = s.split(':').collect();
+        if fields.len() > 4 {
+            return Err(format!("invalid capture config {s}"));
+        }
+        let field = |i: usize| fields.get(i).copied().filter(|f| !f.is_empty());
+        let number = |i: usize, what: &str| {
+            field(i)
+                .map(|f| f.parse().map_err(|_| format!("invalid {what} {f}")))
+                .transpose()
+        };
+        let settings = CaptureSettings {
+            sample_rate: number(0, "sample rate")?,
+            sample_format: field(1).map(|f| f.to_lowercase()),
+            channels: number(2, "channel count")?.map(|c: u32| c as u16),
+            buffer_size: number(3, "buffer size")?,
+        };
+        settings.validate()?;
+        Ok(settings)
+    }
+}
+
+impl fmt::Display for CaptureSettings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_default() {
+            return write!(f, "default");
+        }
+        let field = |v: Option<String>| v.unwrap_or_default();
+        let s = format!(
+            "{}:{}:{}:{}",
+            field(self.sample_rate.map(|r| r.to_string())),
+            field(self.sample_format.clone()),
+            field(self.channels.map(|c| c.to_string())),
+            field(self.buffer_size.map(|b| b.to_string())),
+        );
+        write!(f, "{}", s.trim_end_matches(':'))
+    }
+}
+
+/// select_stream_config - the supported config that matches the settings, and the buffer size
+///
+/// the settings that are not set are taken from the default config of the device
+pub fn select_stream_config(
+    ranges: &[SupportedStreamConfigRange],
+    default: &SupportedStreamConfig,
+    settings: &CaptureSettings,
+) -> Result<(SupportedStreamConfig, BufferSize), String> {
+    settings.validate()?;
+    let config = if settings.sample_rate.is_none()
+        && settings.sample_format.is_none()
+        && settings.channels.is_none()
+    {
+        default.clone()
+    } else {
+        let sample_format = match &settings.sample_format {
+            Some(format) => parse_sample_format(format)?,
+            None => default.sample_format(),
+        };
+        let channels = settings.channels.unwrap_or(default.channels());
+        let sample_rate = SampleRate(settings.sample_rate.unwrap_or(default.sample_rate().0));
+        ranges
+            .iter()
+            .find(|r| {
+                r.sample_format() == sample_format
+                    && r.channels() == channels
+                    && (r.min_sample_rate()..=r.max_sample_rate()).contains(&sample_rate)
+            })
+            .map(|r| r.clone().with_sample_rate(sample_rate))
+            .ok_or_else(|| {
+                format!(
+                    "{} Hz {sample_format} {channels} channels is not supported by the device",
+                    sample_rate.0
+                )
+            })?
+    };
+    let buffer_size = match settings.buffer_size {
+        None => BufferSize::Default,
+        Some(frames) => match config.buffer_size() {
+            SupportedBufferSize::Range { min, max } if !(*min..=*max).contains(&frames) => {
+                return Err(format!(
+                    "buffer size {frames} is not supported by the device ({min}..{max} frames)"
+                ))
             }
+            _ => BufferSize::Fixed(frames),
         },
-        cpal::SampleFormat::I16 => {
-            match device.build_input_stream(
-                &audio_cfg.config(),
-                move |data, _: &_| wave_reader::<i16>(data, &mut f32_samples, rms_sender.clone()),
-                capture_err_fn,
-                None,
-            ) {
-                Ok(stream) => Some(stream),
-                Err(e) => {
-                    ui_log(format!("Error capturing i16 audio stream: {e}"));
-                    None
+    };
+    Ok((config, buffer_size))
+}
+
+/// capture_choices - the rate, format and channel combinations of the supported configs
+///
+/// the common sample rates within each supported range, or its maximum rate
+pub fn capture_choices(ranges: &[SupportedStreamConfigRange]) -> Vec<CaptureSettings> {
+    let mut choices = Vec::new();
+    for range in ranges {
+        let (min, max) = (range.min_sample_rate().0, range.max_sample_rate().0);
+        let mut rates: Vec<u32> = COMMON_SAMPLE_RATES
+            .into_iter()
+            .filter(|r| (min..=max).contains(r))
+            .collect();
+        if rates.is_empty() {
+            rates.push(max);
+        }
+        for sample_rate in rates {
+            let choice = CaptureSettings {
+                sample_rate: Some(sample_rate),
+                sample_format: Some(range.sample_format().to_string()),
+                channels: Some(range.channels()),
+                buffer_size: None,
+            };
+            if !choices.contains(&choice) {
+                choices.push(choice);
+            }
+        }
+    }
+    choices
+}
+
+/// CaptureSource - the AudioSource of a capture device (the default audio output device
+/// or another SoundCard)
+pub struct CaptureSource {
+    device: Device,
+    config: SupportedStreamConfig,
+    buffer_size: BufferSize,
+}
+
+impl CaptureSource {
+    /// the capture settings that the device doesn't support are ignored, with a warning
+    pub fn new(device: Device, settings: &CaptureSettings) -> CaptureSource {
+        let (config, buffer_size) = select_stream_config(
+            &device.supported_configs(),
+            device.default_config(),
+            settings,
+        )
+        .unwrap_or_else(|e| {
+            ui_log(format!(
+                "*W*W*> Capture config {settings}: {e}, using the default config"
+            ));
+            (device.default_config().clone(), BufferSize::Default)
+        });
+        CaptureSource {
+            device,
+            config,
+            buffer_size,
+        }
+    }
+}
+
+impl AudioSource for CaptureSource {
+    fn name(&self) -> String {
+        self.device.name().to_string()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.config.sample_rate().0
+    }
+
+    fn channels(&self) -> u16 {
+        self.config.channels()
+    }
+
+    fn sample_format(&self) -> SampleFormat {
+        self.config.sample_format()
+    }
+
+    /// start the capture supervisor thread, that opens the capture stream and reopens it
+    /// when it fails, the first open error is returned
+    fn start(self: Box<Self>, writer: CaptureWriter) -> Result<RunningSource, String> {
+        ui_log(format!(
+            "Capture audio {:?}, buffer size {:?}",
+            self.config, self.buffer_size
+        ));
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let (started_tx, started_rx) = bounded(1);
+        // cpal streams can't be sent to another thread, so the supervisor owns them
+        thread::Builder::new()
+            .name("capture_supervisor".into())
+            .spawn(move || {
+                let mut supervisor = CaptureSupervisor::new(*self, writer);
+                let started = supervisor.open();
+                let ok = started.is_ok();
+                let _ = started_tx.send(started);
+                if ok {
+                    supervisor.run(&thread_stop);
                 }
+                // dropping the writer ends the capture distributor
+            })
+            .map_err(|e| format!("Unable to start the capture supervisor thread: {e}"))?;
+        started_rx
+            .recv()
+            .map_err(|e| format!("The capture supervisor thread has ended: {e}"))??;
+        Ok(RunningSource::new(stop))
+    }
+}
+
+/// CaptureSupervisor - keeps the capture stream of a device running
+///
+/// a stream of a removed device (cpal reports DeviceNotAvailable) is closed and reopened
+/// as soon as the device is back, other stream errors are only logged, or, with CaptureFollowDefault, the capture
+/// moves to the new default audio output device when it changes. the capture ring and
+/// the distributor stay, so the clients stay connected and get the keep-alive meanwhile.
+/// every reopened stream keeps the sample rate and the channels of the first one, another
+/// device gets its default sample format and buffer size.
+struct CaptureSupervisor {
+    device: Device,
+    // the device of the capture settings, and their sample format
+    selected_device: String,
+    sample_format: SampleFormat,
+    config: StreamConfig,
+    // shared with the callback of the current stream, the ring outlives the streams
+    writer: Arc<Mutex<CaptureWriter>>,
+    // set by the error callback of the current stream when its device is gone
+    failed: Arc<AtomicBool>,
+    // the capture stream and the silence injector, empty while the device is gone
+    streams: Vec<cpal::Stream>,
+    // reopen errors are logged once until the capture runs again
+    reopen_logged: bool,
+}
+
+impl CaptureSupervisor {
+    fn new(source: CaptureSource, writer: CaptureWriter) -> CaptureSupervisor {
+        let mut config = source.config.config();
+        config.buffer_size = source.buffer_size;
+        CaptureSupervisor {
+            selected_device: source.device.name().to_string(),
+            sample_format: source.config.sample_format(),
+            device: source.device,
+            config,
+            writer: Arc::new(Mutex::new(writer)),
+            failed: Arc::new(AtomicBool::new(false)),
+            streams: Vec::new(),
+            reopen_logged: false,
+        }
+    }
+
+    /// open - start a capture stream in the selected sample format (any cpal format),
+    /// that only writes the captured samples to the capture ring
+    ///
+    /// with InjectSilence the silence injector plays silence on the device too
+    fn open(&mut self) -> Result<(), String> {
+        self.failed.store(false, Relaxed);
+        let mut config = self.config.clone();
+        let sample_format = if self.device.name() == self.selected_device {
+            self.sample_format
+        } else {
+            config.buffer_size = BufferSize::Default;
+            self.device.default_config().sample_format()
+        };
+        let stream = match sample_format {
+            SampleFormat::I8 => self.build_stream::<i8>(&config),
+            SampleFormat::I16 => self.build_stream::<i16>(&config),
+            SampleFormat::I32 => self.build_stream::<i32>(&config),
+            SampleFormat::I64 => self.build_stream::<i64>(&config),
+            SampleFormat::U8 => self.build_stream::<u8>(&config),
+            SampleFormat::U16 => self.build_stream::<u16>(&config),
+            SampleFormat::U32 => self.build_stream::<u32>(&config),
+            SampleFormat::U64 => self.build_stream::<u64>(&config),
+            SampleFormat::F32 => self.build_stream::<f32>(&config),
+            SampleFormat::F64 => self.build_stream::<f64>(&config),
+            format => return Err(format!("Unsupported sample format {format:?}")),
+        }
+        .map_err(|e| format!("Error capturing {sample_format} audio stream: {e}"))?;
+        stream
+            .play()
+            .map_err(|e| format!("Unable to start the capture stream: {e}"))?;
+        self.streams.push(stream);
+        if let Some(true) = CONFIG.read().inject_silence {
+            ui_log("Injecting silence into the output stream".to_string());
+            match run_silence_injector(&self.device) {
+                Ok(stream) => self.streams.push(stream),
+                Err(e) => ui_log(format!("*W*W*> {e}")),
             }
         }
-        cpal::SampleFormat::U16 => {
-            match device.build_input_stream(
-                &audio_cfg.config(),
-                move |data, _: &_| wave_reader::<u16>(data, &mut f32_samples, rms_sender.clone()),
-                capture_err_fn,
-                None,
-            ) {
-                Ok(stream) => Some(stream),
-                Err(e) => {
-                    ui_log(format!("Error capturing u16 audio stream: {e}"));
-                    None
+        Ok(())
+    }
+
+    fn build_stream<T>(&self, config: &StreamConfig) -> Result<cpal::Stream, cpal::BuildStreamError>
+    where
+        T: SizedSample + ToSample<f32>,
+    {
+        let writer = self.writer.clone();
+        let failed = self.failed.clone();
+        let name = self.device.name().to_string();
+        self.device.as_ref().build_input_stream(
+            config,
+            // never contended: only the callback of the current stream writes, try_lock
+            // just keeps the callback real-time safe while an old stream is closing
+            move |data: &[T], _: &_| {
+                if let Some(mut writer) = writer.try_lock() {
+                    writer.write(data);
                 }
+            },
+            move |err| match err {
+                cpal::StreamError::DeviceNotAvailable => failed.store(true, Relaxed),
+                // the stream keeps running after a backend error (e.g. an overrun)
+                err => ui_log(format!(
+                    "*E*E*> Error {err} on the audio capture stream of {name}"
+                )),
+            },
+            None,
+        )
+    }
+
+    /// run - check the capture stream every CAPTURE_CHECK_INTERVAL until stopped
+    fn run(&mut self, stop: &AtomicBool) {
+        while !stop.load(Relaxed) {
+            thread::sleep(CAPTURE_CHECK_INTERVAL);
+            let follow_default = CONFIG.read().capture_follow_default.unwrap_or(false);
+            if !self.streams.is_empty() {
+                if self.failed.load(Relaxed) {
+                    ui_log(format!(
+                        "*W*W*> Lost the capture device {}, waiting for it to return",
+                        self.device.name()
+                    ));
+                } else if follow_default
+                    && default_output_device_name().is_some_and(|n| n != self.device.name())
+                {
+                    ui_log("The default audio output device has changed".to_string());
+                } else {
+                    continue;
+                }
+                self.streams.clear();
             }
+            self.reopen(follow_default);
         }
-        _ => None,
     }
-}
 
-/// capture_err_fn - called whan it's impossible to build an audio input stream
-fn capture_err_fn(err: cpal::StreamError) {
-    ui_log(format!("Error {err} building audio input stream"));
+    /// reopen - reopen the capture device, or the default device with CaptureFollowDefault
+    fn reopen(&mut self, follow_default: bool) {
+        let device = if follow_default {
+            get_default_audio_output_device()
+        } else {
+            find_output_device(self.device.name())
+        };
+        let Some(device) = device else {
+            return;
+        };
+        self.device = device;
+        match self.open() {
+            Ok(()) => {
+                ui_log(format!("Capturing audio from {} again", self.device.name()));
+                self.reopen_logged = false;
+            }
+            Err(e) => {
+                self.streams.clear();
+                if !self.reopen_logged {
+                    ui_log(format!(
+                        "*W*W*> Unable to reopen the capture device {} ({} Hz, {} channels), retrying: {e}",
+                        self.device.name(),
+                        self.config.sample_rate.0,
+                        self.config.channels
+                    ));
+                    self.reopen_logged = true;
+                }
+            }
+        }
+    }
 }
 
-/// wave_reader - the captured audio input stream reader
-///
-/// writes the captured samples to all registered clients in the
-/// CLIENTS ChannnelStream hashmap
-/// also feeds the RMS monitor channel if the RMS option is set
-fn wave_reader<T>(samples: &[T], f32_samples: &mut Vec<f32>, rms_sender: Sender<Vec<f32>>)
-where
-    T: Sample + ToSample<f32>,
-{
-    static INITIALIZER: Once = Once::new();
-    INITIALIZER.call_once(|| {
-        ui_log("The wave_reader is now receiving samples".to_string());
-    });
-    f32_samples.clear();
-    f32_samples.extend(samples.iter().map(|x: &T| T::to_sample::<f32>(*x)));
-    for (_, v) in CLIENTS.read().iter() {
-        v.write(f32_samples);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(
+        channels: u16,
+        min: u32,
+        max: u32,
+        format: SampleFormat,
+    ) -> SupportedStreamConfigRange {
+        SupportedStreamConfigRange::new(
+            channels,
+            SampleRate(min),
+            SampleRate(max),
+            SupportedBufferSize::Range { min: 64, max: 4096 },
+            format,
+        )
     }
-    if CONFIG.read().monitor_rms {
-        rms_sender.send(f32_samples.to_vec()).unwrap();
+
+    fn ranges() -> Vec<SupportedStreamConfigRange> {
+        vec![
+            range(2, 44100, 48000, SampleFormat::F32),
+            range(2, 44100, 192000, SampleFormat::I32),
+            range(6, 48000, 48000, SampleFormat::I16),
+        ]
+    }
+
+    fn default_config() -> SupportedStreamConfig {
+        SupportedStreamConfig::new(
+            2,
+            SampleRate(48000),
+            SupportedBufferSize::Range { min: 64, max: 4096 },
+            SampleFormat::F32,
+        )
+    }
+
+    #[test]
+    fn capture_settings() {
+        let settings: CaptureSettings = "96000:I32::512".parse().unwrap();
+        assert_eq!(settings.sample_rate, Some(96000));
+        assert_eq!(settings.sample_format.as_deref(), Some("i32"));
+        assert_eq!(settings.channels, None);
+        assert_eq!(settings.buffer_size, Some(512));
+        assert_eq!(settings.to_string(), "96000:i32::512");
+        let settings: CaptureSettings = "::6".parse().unwrap();
+        assert_eq!(settings.to_string(), "::6");
+        assert!("default".parse::<CaptureSettings>().unwrap().is_default());
+        assert_eq!(CaptureSettings::default().to_string(), "default");
+        assert!("48000:s16".parse::<CaptureSettings>().is_err());
+        assert!("48000:f32:0".parse::<CaptureSettings>().is_err());
+        assert!("48000:f32:2:0".parse::<CaptureSettings>().is_err());
+        assert!("1:f32".parse::<CaptureSettings>().is_err());
+        assert!("48000:f32:2:512:1".parse::<CaptureSettings>().is_err());
+        for format in SAMPLE_FORMATS {
+            assert_eq!(parse_sample_format(&format.to_string()), Ok(format));
+        }
+    }
+
+    #[test]
+    fn stream_config_selection() {
+        let ranges = ranges();
+        let default = default_config();
+        // no settings: the default config
+        let (config, buffer) =
+            select_stream_config(&ranges, &default, &CaptureSettings::default()).unwrap();
+        assert_eq!(config, default);
+        assert_eq!(buffer, BufferSize::Default);
+        // another format and rate, the default channels
+        let settings: CaptureSettings = "192000:i32::256".parse().unwrap();
+        let (config, buffer) = select_stream_config(&ranges, &default, &settings).unwrap();
+        assert_eq!(config.sample_rate(), SampleRate(192000));
+        assert_eq!(config.sample_format(), SampleFormat::I32);
+        assert_eq!(config.channels(), 2);
+        assert_eq!(buffer, BufferSize::Fixed(256));
+        // 6 channels at the default rate
+        let settings: CaptureSettings = ":i16:6".parse().unwrap();
+        let (config, _) = select_stream_config(&ranges, &default, &settings).unwrap();
+        assert_eq!((config.channels(), config.sample_rate().0), (6, 48000));
+        // not supported
+        for spec in ["96000", "44100:i16:6", ":f64", "::2:8192"] {
+            let settings: CaptureSettings = spec.parse().unwrap();
+            assert!(
+                select_stream_config(&ranges, &default, &settings).is_err(),
+                "{spec}"
+            );
+        }
+    }
+
+    #[test]
+    fn choices() {
+        let choices = capture_choices(&ranges());
+        let specs: Vec<String> = choices.iter().map(|c| c.to_string()).collect();
+        assert_eq!(
+            specs,
+            [
+                "44100:f32:2",
+                "48000:f32:2",
+                "44100:i32:2",
+                "48000:i32:2",
+                "88200:i32:2",
+                "96000:i32:2",
+                "176400:i32:2",
+                "192000:i32:2",
+                "48000:i16:6",
+            ]
+        );
+        // every choice is selectable
+        for choice in choices {
+            assert!(select_stream_config(&ranges(), &default_config(), &choice).is_ok());
+        }
     }
 }
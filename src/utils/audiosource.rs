@@ -0,0 +1,760 @@
+///
+/// audiosource.rs
+///
+/// AudioSource: where the streamed audio comes from
+///
+/// an AudioSource produces interleaved f32 frames at its sample rate into the capture ring,
+/// the capture distributor does the rest (see capturering.rs). the AudioSource config value
+/// (CLI option -A) selects the source:
+///
+///   capture                             the SoundCard capture device (cpal), the default
+///   pcm:{rate}:{channels}:{format}:{path} raw PCM (s16le, s24le, s32le or f32le) from a file,
+///                                       a FIFO (a PulseAudio/PipeWire pipe sink) or stdin (-)
+///   file:{path}                         a WAV or FLAC file, played in a loop
+///   tone:{signal}[:{rate}[:{channels}]] a test signal: sweep, pink or ident
+///
+/// the cpal capture source and its supervisor live in audiodevices.rs, the test signals in
+/// testsignal.rs.
+/// the other sources are read by a source thread, that keeps files and test signals
+/// at real time speed
+///
+use crate::{
+    globals::statics::CONFIG,
+    openhome::rendercontrol::WavData,
+    utils::{
+        audiodevices::{get_default_audio_output_device, CaptureSource, Device},
+        capturering::{capture_ring, run_distributor, CaptureWriter},
+        channelmap::{ChannelMapper, ChannelOptions},
+        configuration::Configuration,
+        testsignal::{Signal, TestSignal},
+        ui_logger::ui_log,
+    },
+};
+use crossbeam_channel::Sender;
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{self, BufReader, Read},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering::Relaxed},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// the source thread reads this many msecs of audio at a time
+const SOURCE_BLOCK_MS: u32 = 10;
+/// how long the source thread waits when the capture ring is full
+const RING_FULL_WAIT: Duration = Duration::from_millis(5);
+
+/// AudioSource - produces interleaved f32 frames into the capture ring
+pub trait AudioSource {
+    /// a description for the log
+    fn name(&self) -> String;
+
+    fn sample_rate(&self) -> u32;
+
+    fn channels(&self) -> u16;
+
+    /// the sample format of the source, only the capture devices have another format
+    fn sample_format(&self) -> cpal::SampleFormat {
+        cpal::SampleFormat::F32
+    }
+
+    /// start writing the frames to the capture ring, until the RunningSource is dropped
+    fn start(self: Box<Self>, writer: CaptureWriter) -> Result<RunningSource, String>;
+
+    /// the audio format data for the streaming server and the renderers
+    fn wav_data(&self) -> WavData {
+        WavData {
+            sample_format: self.sample_format(),
+            sample_rate: cpal::SampleRate(self.sample_rate()),
+            channels: self.channels(),
+        }
+    }
+}
+
+/// RunningSource - keeps a started AudioSource running, dropping it stops the source
+///
+/// every source runs in its own thread (the capture supervisor of a cpal source, the
+/// source thread of the other sources), that ends when the stop flag is set
+pub struct RunningSource {
+    stop: Arc<AtomicBool>,
+}
+
+impl RunningSource {
+    pub fn new(stop: Arc<AtomicBool>) -> RunningSource {
+        RunningSource { stop }
+    }
+}
+
+impl Drop for RunningSource {
+    fn drop(&mut self) {
+        self.stop.store(true, Relaxed);
+    }
+}
+
+/// start_audio_source - start the capture distributor and the source
+///
+/// the captured channels are mapped to the streamed stereo layout
+pub fn start_audio_source(
+    source: Box<dyn AudioSource>,
+    rms_sender: Sender<Arc<[f32]>>,
+) -> Result<RunningSource, String> {
+    ui_log(format!("Capturing audio from: {}", source.name()));
+    let channels = source.channels();
+    let mapper = ChannelMapper::new(channels, ChannelOptions::from_config(&CONFIG.read()));
+    if channels != 2 {
+        ui_log(format!("Mapping {channels} captured channels to stereo"));
+    }
+    let (writer, reader) = capture_ring(source.sample_rate(), channels);
+    thread::Builder::new()
+        .name("capture_distributor".into())
+        .spawn(move || run_distributor(reader, mapper, rms_sender))
+        .map_err(|e| format!("Unable to start the capture distributor thread: {e}"))?;
+    source.start(writer)
+}
+
+/// RawFormat - the sample format of a raw PCM source
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RawFormat {
+    S16Le,
+    S24Le,
+    S32Le,
+    F32Le,
+}
+
+impl RawFormat {
+    fn bytes(self) -> usize {
+        match self {
+            RawFormat::S16Le => 2,
+            RawFormat::S24Le => 3,
+            RawFormat::S32Le | RawFormat::F32Le => 4,
+        }
+    }
+
+    /// the f32 value of a little endian sample
+    fn to_f32(self, b: &[u8]) -> f32 {
+        match self {
+            RawFormat::S16Le => i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0,
+            RawFormat::S24Le => (i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8) as f32 / 8388608.0,
+            RawFormat::S32Le => i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32 / 2147483648.0,
+            RawFormat::F32Le => f32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+        }
+    }
+}
+
+impl FromStr for RawFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "s16le" => Ok(RawFormat::S16Le),
+            "s24le" => Ok(RawFormat::S24Le),
+            "s32le" => Ok(RawFormat::S32Le),
+            "f32le" => Ok(RawFormat::F32Le),
+            _ => Err(format!(
+                "unknown raw PCM format {s} (s16le/s24le/s32le/f32le)"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for RawFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RawFormat::S16Le => write!(f, "s16le"),
+            RawFormat::S24Le => write!(f, "s24le"),
+            RawFormat::S32Le => write!(f, "s32le"),
+            RawFormat::F32Le => write!(f, "f32le"),
+        }
+    }
+}
+
+/// SourceSpec - the parsed AudioSource config value
+#[derive(Debug, Clone, PartialEq)]
+pub enum SourceSpec {
+    Capture,
+    Pcm {
+        sample_rate: u32,
+        channels: u16,
+        format: RawFormat,
+        // - is stdin
+        path: String,
+    },
+    File(String),
+    Tone {
+        signal: Signal,
+        sample_rate: u32,
+        channels: u16,
+    },
+}
+
+impl FromStr for SourceSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (kind, rest) = s.split_once(':').unwrap_or((s, ""));
+        let rate = |r: &str| match r.parse::<u32>() {
+            Ok(rate @ 8000..=384000) => Ok(rate),
+            _ => Err(format!("invalid sample rate {r} (8000..384000)")),
+        };
+        let channels = |c: &str| match c.parse::<u16>() {
+            Ok(ch @ 1..=8) => Ok(ch),
+            _ => Err(format!("invalid number of channels {c} (1..8)")),
+        };
+        match kind.to_lowercase().as_str() {
+            "" | "capture" => Ok(SourceSpec::Capture),
+            "pcm" => {
+                // the path comes last, it may contain colons
+                let parts: Vec<&str> = rest.splitn(4, ':').collect();
+                let [r, c, format, path] = parts[..] else {
+                    return Err("expected pcm:{rate}:{channels}:{format}:{path}".to_string());
+                };
+                if path.is_empty() {
+                    return Err("no path for the raw PCM source".to_string());
+                }
+                Ok(SourceSpec::Pcm {
+                    sample_rate: rate(r)?,
+                    channels: channels(c)?,
+                    format: format.parse()?,
+                    path: path.to_string(),
+                })
+            }
+            "file" if !rest.is_empty() => Ok(SourceSpec::File(rest.to_string())),
+            "file" => Err("no path for the file source".to_string()),
+            "tone" => {
+                let mut parts = rest.split(':');
+                let signal = parts.next().unwrap_or_default().parse()?;
+                let sample_rate = parts.next().map_or(Ok(48000), rate)?;
+                let channels = parts.next().map_or(Ok(2), channels)?;
+                Ok(SourceSpec::Tone {
+                    signal,
+                    sample_rate,
+                    channels,
+                })
+            }
+            _ => Err(format!("unknown audio source {s} (capture/pcm/file/tone)")),
+        }
+    }
+}
+
+impl fmt::Display for SourceSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SourceSpec::Capture => write!(f, "capture"),
+            SourceSpec::Pcm {
+                sample_rate,
+                channels,
+                format,
+                path,
+            } => write!(f, "pcm:{sample_rate}:{channels}:{format}:{path}"),
+            SourceSpec::File(path) => write!(f, "file:{path}"),
+            SourceSpec::Tone {
+                signal,
+                sample_rate,
+                channels,
+            } => write!(f, "tone:{signal}:{sample_rate}:{channels}"),
+        }
+    }
+}
+
+/// open_audio_source - the AudioSource of the configuration, the device is the
+/// selected SoundCard for the capture source
+pub fn open_audio_source(
+    conf: &Configuration,
+    device: Device,
+) -> Result<Box<dyn AudioSource>, String> {
+    let spec: SourceSpec = conf.audio_source.as_deref().unwrap_or_default().parse()?;
+    Ok(match spec {
+        // the configured SoundCard, or the default device when following it
+        SourceSpec::Capture => {
+            let device = match conf.capture_follow_default {
+                Some(true) => get_default_audio_output_device().unwrap_or(device),
+                _ => device,
+            };
+            let settings = conf.capture_settings(device.name());
+            Box::new(CaptureSource::new(device, &settings))
+        }
+        SourceSpec::Pcm {
+            sample_rate,
+            channels,
+            format,
+            path,
+        } => Box::new(PcmSource::open(&path, sample_rate, channels, format)?),
+        SourceSpec::File(path) => Box::new(FileSource::open(PathBuf::from(path))?),
+        SourceSpec::Tone {
+            signal,
+            sample_rate,
+            channels,
+        } => Box::new(TestSignal::new(signal, sample_rate, channels)),
+    })
+}
+
+/// FrameReader - a source that is read by a source thread
+pub trait FrameReader: Send + 'static {
+    /// fill buf (whole frames) with interleaved samples, returns the number of samples,
+    /// 0 at the end of the source
+    fn read_frames(&mut self, buf: &mut [f32]) -> Result<usize, String>;
+
+    /// true if the samples are available faster than real time (files and generators),
+    /// false if reading waits for them (pipes)
+    fn needs_pacing(&self) -> bool;
+}
+
+/// start_source_thread - read the frames of the reader into the capture ring
+pub fn start_source_thread<R: FrameReader>(
+    name: String,
+    mut reader: R,
+    sample_rate: u32,
+    channels: u16,
+    mut writer: CaptureWriter,
+) -> Result<RunningSource, String> {
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread_stop = stop.clone();
+    let block_len = (sample_rate * SOURCE_BLOCK_MS / 1000) as usize * channels as usize;
+    thread::Builder::new()
+        .name("audio_source".into())
+        .spawn(move || {
+            let mut buf = vec![0f32; block_len];
+            let started = Instant::now();
+            let mut frames: u64 = 0;
+            while !thread_stop.load(Relaxed) {
+                let n = match reader.read_frames(&mut buf) {
+                    Ok(0) => {
+                        ui_log(format!("The audio source {name} has ended"));
+                        break;
+                    }
+                    Ok(n) => n,
+                    Err(e) => {
+                        ui_log(format!("*E*E*> Error reading the audio source {name}: {e}"));
+                        break;
+                    }
+                };
+                if reader.needs_pacing() {
+                    let due = Duration::from_secs_f64(frames as f64 / sample_rate as f64);
+                    if let Some(wait) = due.checked_sub(started.elapsed()) {
+                        thread::sleep(wait);
+                    }
+                }
+                // the samples of a source thread aren't dropped when the ring is full
+                while writer.free() < n && !thread_stop.load(Relaxed) {
+                    thread::sleep(RING_FULL_WAIT);
+                }
+                writer.write(&buf[..n]);
+                frames += (n / channels as usize) as u64;
+            }
+            // dropping the writer ends the capture distributor
+        })
+        .map_err(|e| format!("Unable to start the audio source thread: {e}"))?;
+    Ok(RunningSource::new(stop))
+}
+
+/// PcmSource - raw little endian PCM from a file, a FIFO or stdin
+///
+/// a file or FIFO is opened by the source thread, as opening a FIFO waits for its writer
+pub struct PcmSource {
+    path: String,
+    input: Option<Box<dyn Read + Send>>,
+    sample_rate: u32,
+    channels: u16,
+    format: RawFormat,
+    // only regular files are read faster than real time
+    paced: bool,
+    // a FIFO is reopened when its writer closes it, so that the stream goes on with the next writer
+    reopen: bool,
+    bytes: Vec<u8>,
+}
+
+impl PcmSource {
+    pub fn open(
+        path: &str,
+        sample_rate: u32,
+        channels: u16,
+        format: RawFormat,
+    ) -> Result<PcmSource, String> {
+        let (input, paced, reopen): (Option<Box<dyn Read + Send>>, bool, bool) = if path == "-" {
+            (Some(Box::new(io::stdin())), false, false)
+        } else {
+            let metadata = fs::metadata(path).map_err(|e| format!("Unable to open {path}: {e}"))?;
+            (None, metadata.is_file(), !metadata.is_file())
+        };
+        Ok(PcmSource {
+            path: path.to_string(),
+            input,
+            sample_rate,
+            channels,
+            format,
+            paced,
+            reopen,
+            bytes: Vec::new(),
+        })
+    }
+
+    #[cfg(test)]
+    fn from_reader(input: Box<dyn Read + Send>, channels: u16, format: RawFormat) -> PcmSource {
+        PcmSource {
+            path: String::new(),
+            input: Some(input),
+            sample_rate: 1000,
+            channels,
+            format,
+            paced: false,
+            reopen: false,
+            bytes: Vec::new(),
+        }
+    }
+
+    /// read_input - read a block from the input, opening it first if needed
+    fn read_input(&mut self, buf: &mut [f32]) -> Result<usize, String> {
+        let input = match self.input.as_mut() {
+            Some(input) => input,
+            None => {
+                let path = &self.path;
+                let file = File::open(path).map_err(|e| format!("Unable to open {path}: {e}"))?;
+                self.input.insert(Box::new(file))
+            }
+        };
+        let sample_bytes = self.format.bytes();
+        self.bytes.resize(buf.len() * sample_bytes, 0);
+        // a pipe returns what it has, read until the block is full or the input ends
+        let mut len = 0;
+        while len < self.bytes.len() {
+            match input.read(&mut self.bytes[len..]) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+        // a partial frame at the end of the input is dropped
+        let frame_bytes = sample_bytes * self.channels as usize;
+        let samples = len / frame_bytes * self.channels as usize;
+        for (s, b) in buf[..samples]
+            .iter_mut()
+            .zip(self.bytes.chunks_exact(sample_bytes))
+        {
+            *s = self.format.to_f32(b);
+        }
+        Ok(samples)
+    }
+}
+
+impl FrameReader for PcmSource {
+    fn read_frames(&mut self, buf: &mut [f32]) -> Result<usize, String> {
+        loop {
+            let samples = self.read_input(buf)?;
+            if samples > 0 || !self.reopen {
+                return Ok(samples);
+            }
+            // the writer has closed the FIFO, wait for the next one
+            ui_log(format!(
+                "The writer of {} has closed the pipe, reopening it",
+                self.path
+            ));
+            self.input = None;
+        }
+    }
+
+    fn needs_pacing(&self) -> bool {
+        self.paced
+    }
+}
+
+impl AudioSource for PcmSource {
+    fn name(&self) -> String {
+        let path = if self.path == "-" {
+            "stdin"
+        } else {
+            &self.path
+        };
+        format!(
+            "raw PCM {path} ({} Hz, {} channels, {})",
+            self.sample_rate, self.channels, self.format
+        )
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn start(self: Box<Self>, writer: CaptureWriter) -> Result<RunningSource, String> {
+        let (name, rate, channels) = (self.name(), self.sample_rate, self.channels);
+        start_source_thread(name, *self, rate, channels, writer)
+    }
+}
+
+/// the decoder of a WAV or FLAC file
+enum FileDecoder {
+    WavInt(hound::WavIntoSamples<BufReader<File>, i32>, f32),
+    WavFloat(hound::WavIntoSamples<BufReader<File>, f32>),
+    // the decoded samples of the current block and the next one to return
+    Flac(claxon::FlacReader<File>, Vec<f32>, usize, f32),
+}
+
+impl FileDecoder {
+    /// open - the decoder, the sample rate and the number of channels of the file
+    fn open(path: &Path) -> Result<(FileDecoder, u32, u16), String> {
+        let is_flac = path
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("flac"));
+        let name = path.display();
+        if is_flac {
+            let reader = claxon::FlacReader::open(path)
+                .map_err(|e| format!("Unable to open {name}: {e}"))?;
+            let info = reader.streaminfo();
+            let scale = (1u64 << (info.bits_per_sample - 1)) as f32;
+            Ok((
+                FileDecoder::Flac(reader, Vec::new(), 0, scale),
+                info.sample_rate,
+                info.channels as u16,
+            ))
+        } else {
+            let reader =
+                hound::WavReader::open(path).map_err(|e| format!("Unable to open {name}: {e}"))?;
+            let spec = reader.spec();
+            let decoder = match spec.sample_format {
+                hound::SampleFormat::Float => FileDecoder::WavFloat(reader.into_samples()),
+                hound::SampleFormat::Int => {
+                    let scale = (1u64 << (spec.bits_per_sample - 1)) as f32;
+                    FileDecoder::WavInt(reader.into_samples(), scale)
+                }
+            };
+            Ok((decoder, spec.sample_rate, spec.channels))
+        }
+    }
+
+    /// next - the next sample, None at the end of the file
+    fn next(&mut self) -> Result<Option<f32>, String> {
+        match self {
+            FileDecoder::WavInt(samples, scale) => samples
+                .next()
+                .transpose()
+                .map(|s| s.map(|s| s as f32 / *scale))
+                .map_err(|e| e.to_string()),
+            FileDecoder::WavFloat(samples) => samples.next().transpose().map_err(|e| e.to_string()),
+            FileDecoder::Flac(reader, block, pos, scale) => {
+                if *pos == block.len() {
+                    let buffer = Vec::with_capacity(block.capacity());
+                    let Some(b) = reader
+                        .blocks()
+                        .read_next_or_eof(buffer)
+                        .map_err(|e| e.to_string())?
+                    else {
+                        return Ok(None);
+                    };
+                    // the channels of a FLAC block aren't interleaved
+                    block.clear();
+                    for i in 0..b.duration() {
+                        block.extend((0..b.channels()).map(|ch| b.sample(ch, i) as f32 / *scale));
+                    }
+                    *pos = 0;
+                    if block.is_empty() {
+                        return Ok(None);
+                    }
+                }
+                *pos += 1;
+                Ok(Some(block[*pos - 1]))
+            }
+        }
+    }
+}
+
+/// FileSource - a WAV or FLAC file, played in a loop
+pub struct FileSource {
+    path: PathBuf,
+    decoder: FileDecoder,
+    sample_rate: u32,
+    channels: u16,
+}
+
+impl FileSource {
+    pub fn open(path: PathBuf) -> Result<FileSource, String> {
+        let (decoder, sample_rate, channels) = FileDecoder::open(&path)?;
+        if !(1..=8).contains(&channels) {
+            return Err(format!(
+                "{} has {channels} channels, at most 8 are supported",
+                path.display()
+            ));
+        }
+        Ok(FileSource {
+            path,
+            decoder,
+            sample_rate,
+            channels,
+        })
+    }
+}
+
+impl FrameReader for FileSource {
+    fn read_frames(&mut self, buf: &mut [f32]) -> Result<usize, String> {
+        let mut n = 0;
+        let mut rewound = false;
+        while n < buf.len() {
+            match self.decoder.next()? {
+                Some(s) => {
+                    buf[n] = s;
+                    n += 1;
+                    rewound = false;
+                }
+                // an empty file ends the source instead of looping forever
+                None if rewound => break,
+                None => {
+                    (self.decoder, _, _) = FileDecoder::open(&self.path)?;
+                    rewound = true;
+                }
+            }
+        }
+        Ok(n - n % self.channels as usize)
+    }
+
+    fn needs_pacing(&self) -> bool {
+        true
+    }
+}
+
+impl AudioSource for FileSource {
+    fn name(&self) -> String {
+        format!(
+            "{} ({} Hz, {} channels)",
+            self.path.display(),
+            self.sample_rate,
+            self.channels
+        )
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn start(self: Box<Self>, writer: CaptureWriter) -> Result<RunningSource, String> {
+        let (name, rate, channels) = (self.name(), self.sample_rate, self.channels);
+        start_source_thread(name, *self, rate, channels, writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn source_specs() {
+        assert_eq!("".parse(), Ok(SourceSpec::Capture));
+        assert_eq!("Capture".parse(), Ok(SourceSpec::Capture));
+        // the path may contain colons
+        let spec: SourceSpec = "pcm:48000:2:s16le:C:\\audio\\pipe".parse().unwrap();
+        assert_eq!(
+            spec,
+            SourceSpec::Pcm {
+                sample_rate: 48000,
+                channels: 2,
+                format: RawFormat::S16Le,
+                path: "C:\\audio\\pipe".to_string(),
+            }
+        );
+        assert_eq!(spec.to_string(), "pcm:48000:2:s16le:C:\\audio\\pipe");
+        assert_eq!(
+            "file:/music/test.flac".parse(),
+            Ok(SourceSpec::File("/music/test.flac".to_string()))
+        );
+        assert_eq!(
+            "tone:ident:44100:6".parse(),
+            Ok(SourceSpec::Tone {
+                signal: Signal::ChannelIdent,
+                sample_rate: 44100,
+                channels: 6,
+            })
+        );
+        // the test signals default to 48 kHz stereo
+        let spec: SourceSpec = "tone:pink".parse().unwrap();
+        assert_eq!(spec.to_string(), "tone:pink:48000:2");
+        assert_eq!(spec.to_string().parse(), Ok(spec));
+        for bad in [
+            "pcm:48000:2:s16le",
+            "pcm:48000:9:s16le:-",
+            "pcm:1000:2:s16le:-",
+            "pcm:48000:2:u8:-",
+            "file:",
+            "tone:square",
+            "tone:sweep::2",
+            "alsa",
+        ] {
+            assert!(bad.parse::<SourceSpec>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn raw_pcm() {
+        // 3 frames of s24le stereo and half a frame
+        let mut bytes = Vec::new();
+        for s in [0x400000i32, -0x400000, 0x7fffff, -0x800000, 0, 1] {
+            bytes.extend_from_slice(&s.to_le_bytes()[..3]);
+        }
+        bytes.extend_from_slice(&[1, 2, 3]);
+        let mut source = PcmSource::from_reader(
+            Box::new(io::Cursor::new(bytes.clone())),
+            2,
+            RawFormat::S24Le,
+        );
+        let mut buf = [9f32; 8];
+        assert_eq!(source.read_frames(&mut buf).unwrap(), 6);
+        assert_eq!(buf[..4], [0.5, -0.5, 8388607.0 / 8388608.0, -1.0]);
+        assert_eq!(buf[4], 0.0);
+        assert_eq!(source.read_frames(&mut buf).unwrap(), 0);
+        // a pipe is opened by the reader, and reopened at the end of its input
+        let path = std::env::temp_dir().join(format!("swyh-rs-pipe-{}.raw", std::process::id()));
+        fs::write(&path, &bytes).unwrap();
+        let mut source =
+            PcmSource::open(path.to_str().unwrap(), 1000, 2, RawFormat::S24Le).unwrap();
+        assert!(source.input.is_none() && source.paced && !source.reopen);
+        source.reopen = true;
+        for _ in 0..2 {
+            buf = [9f32; 8];
+            assert_eq!(source.read_frames(&mut buf).unwrap(), 6);
+            assert_eq!(buf[0], 0.5);
+        }
+        let _ = fs::remove_file(&path);
+        assert!(PcmSource::open(path.to_str().unwrap(), 1000, 2, RawFormat::S24Le).is_err());
+        // the other formats
+        assert_eq!(RawFormat::S16Le.to_f32(&(-16384i16).to_le_bytes()), -0.5);
+        assert_eq!(RawFormat::S32Le.to_f32(&(1i32 << 30).to_le_bytes()), 0.5);
+        assert_eq!(RawFormat::F32Le.to_f32(&0.25f32.to_le_bytes()), 0.25);
+    }
+
+    #[test]
+    fn wav_file_loops() {
+        let path = std::env::temp_dir().join(format!("swyh-rs-source-{}.wav", std::process::id()));
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 44100,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut wav = hound::WavWriter::create(&path, spec).unwrap();
+        for s in [16384i16, -16384, 8192, -8192] {
+            wav.write_sample(s).unwrap();
+        }
+        wav.finalize().unwrap();
+        let mut source = FileSource::open(path.clone()).unwrap();
+        assert_eq!((source.sample_rate(), source.channels()), (44100, 2));
+        let mut buf = [0f32; 10];
+        assert_eq!(source.read_frames(&mut buf).unwrap(), 10);
+        assert_eq!(
+            buf,
+            [0.5, -0.5, 0.25, -0.25, 0.5, -0.5, 0.25, -0.25, 0.5, -0.5]
+        );
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -1,17 +1,25 @@
 //! Tools common to both the swyh-rs GUI and CLI.
 
+use std::sync::{atomic::Ordering::Relaxed, Arc};
+
 use cpal::{
     traits::{DeviceTrait, StreamTrait},
     Sample, SampleFormat, Stream, StreamConfig,
 };
+use crossbeam_channel::Receiver;
 
 use super::audiodevices::Device;
+use crate::{
+    globals::statics::{RMS_LEFT, RMS_RIGHT},
+    openhome::rendercontrol::WavData,
+};
 
 /// Inject silence into the audio stream to solve problems with Sonos when pausing audio.
 /// contributed by @genekellyjr, see issue #71
 ///
 /// Streams are asynchronous, so the silence stream is just returned to keep the object alive.
-pub fn run_silence_injector(device: &Device) -> Stream {
+/// The capture supervisor restarts it with the capture stream, so errors are returned.
+pub fn run_silence_injector(device: &Device) -> Result<Stream, String> {
     let config = device.default_config();
     let sample_format = config.sample_format();
     let err_fn = |err| eprintln!("an error occurred on the output audio stream: {err}");
@@ -27,19 +35,76 @@ pub fn run_silence_injector(device: &Device) -> Stream {
 
     let device = device.as_ref();
     let stream = match sample_format {
-        SampleFormat::F32 => device
-            .build_output_stream(&config, write_silence::<f32>, err_fn, None)
-            .unwrap(),
-        SampleFormat::I16 => device
-            .build_output_stream(&config, write_silence::<i16>, err_fn, None)
-            .unwrap(),
-        SampleFormat::U16 => device
-            .build_output_stream(&config, write_silence::<u16>, err_fn, None)
-            .unwrap(),
-        format => panic!("Unsupported sample format: {format:?}"),
-    };
+        SampleFormat::I8 => device.build_output_stream(&config, write_silence::<i8>, err_fn, None),
+        SampleFormat::I16 => {
+            device.build_output_stream(&config, write_silence::<i16>, err_fn, None)
+        }
+        SampleFormat::I32 => {
+            device.build_output_stream(&config, write_silence::<i32>, err_fn, None)
+        }
+        SampleFormat::I64 => {
+            device.build_output_stream(&config, write_silence::<i64>, err_fn, None)
+        }
+        SampleFormat::U8 => device.build_output_stream(&config, write_silence::<u8>, err_fn, None),
+        SampleFormat::U16 => {
+            device.build_output_stream(&config, write_silence::<u16>, err_fn, None)
+        }
+        SampleFormat::U32 => {
+            device.build_output_stream(&config, write_silence::<u32>, err_fn, None)
+        }
+        SampleFormat::U64 => {
+            device.build_output_stream(&config, write_silence::<u64>, err_fn, None)
+        }
+        SampleFormat::F32 => {
+            device.build_output_stream(&config, write_silence::<f32>, err_fn, None)
+        }
+        SampleFormat::F64 => {
+            device.build_output_stream(&config, write_silence::<f64>, err_fn, None)
+        }
+        format => return Err(format!("Unsupported sample format: {format:?}")),
+    }
+    .map_err(|e| format!("Unable to build the silence output stream: {e}"))?;
     stream
         .play()
-        .expect("Unable to inject silence into the output stream");
-    stream
+        .map_err(|e| format!("Unable to inject silence into the output stream: {e}"))?;
+    Ok(stream)
+}
+
+/// run_rms_monitor - compute the left and right RMS values of the captured samples
+///
+/// the RMS values are published (for the web UI) and passed to the `show_rms` closure
+/// about 10 times per second
+pub fn run_rms_monitor<F>(wd: &WavData, rms_receiver: Receiver<Arc<[f32]>>, mut show_rms: F)
+where
+    F: FnMut(f64, f64),
+{
+    // compute # of samples needed to get a 10 Hz refresh rate
+    // (the captured samples have been mapped to stereo)
+    let samples_per_update = ((wd.sample_rate.0 * 2) / 10) as i64;
+    let mut nsamples = 0i64;
+    let mut sum_l = 0i64;
+    let mut sum_r = 0i64;
+    while let Ok(samples) = rms_receiver.recv() {
+        for (n, sample) in samples.iter().enumerate() {
+            nsamples += 1;
+            let i64sample: i64 = i16::from_sample(*sample) as i64;
+            if n & 1 == 0 {
+                sum_l += i64sample * i64sample;
+            } else {
+                sum_r += i64sample * i64sample;
+            }
+            if nsamples >= samples_per_update {
+                // compute rms value
+                let rms_l = ((sum_l / nsamples) as f64).sqrt();
+                let rms_r = ((sum_r / nsamples) as f64).sqrt();
+                RMS_LEFT.store((rms_l as f32).to_bits(), Relaxed);
+                RMS_RIGHT.store((rms_r as f32).to_bits(), Relaxed);
+                show_rms(rms_l, rms_r);
+                //reset counters
+                nsamples = 0;
+                sum_l = 0;
+                sum_r = 0;
+            }
+        }
+    }
 }
@@ -0,0 +1,243 @@
+///
+/// capturering.rs
+///
+/// CaptureRing: the lock-free path from the audio capture callback to the clients
+///
+/// the cpal callback runs on the real-time audio thread, so it only converts the captured
+/// samples to f32 into a preallocated single producer single consumer ring buffer: no locks,
+/// no allocations and no channel sends. the distributor thread reads the ring and does
+/// the rest: channel mapping, the fan-out to the clients, the pre-roll, the recorder and the
+/// RMS monitor.
+///
+/// when the distributor can't keep up and the ring is full, the captured block is dropped
+/// and counted as an overrun, the overruns are logged and shown by the API
+///
+use crate::{
+    globals::statics::{CAPTURE_OVERRUNS, CLIENTS, CONFIG, PRE_ROLL},
+    utils::{channelmap::ChannelMapper, recorder::record, ui_logger::ui_log},
+};
+use cpal::Sample;
+use crossbeam_channel::Sender;
+use dasp_sample::ToSample;
+use rtrb::{Consumer, Producer, RingBuffer};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering::Relaxed},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// the msecs of audio the ring buffer can hold
+pub const CAPTURE_RING_MS: u64 = 1000;
+
+/// how often the distributor looks for new samples
+const DISTRIBUTE_INTERVAL: Duration = Duration::from_millis(5);
+
+/// overruns are logged at most this often
+const OVERRUN_LOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// capture_ring - the ring buffer for CAPTURE_RING_MS of captured audio
+pub fn capture_ring(sample_rate: u32, channels: u16) -> (CaptureWriter, CaptureReader) {
+    let capacity = (sample_rate as u64 * channels as u64 * CAPTURE_RING_MS / 1000) as usize;
+    let (producer, consumer) = RingBuffer::new(capacity);
+    let overruns = Arc::new(AtomicU64::new(0));
+    (
+        CaptureWriter {
+            producer,
+            overruns: overruns.clone(),
+        },
+        CaptureReader {
+            consumer,
+            sample_rate,
+            channels: channels as usize,
+            overruns,
+        },
+    )
+}
+
+/// CaptureWriter - the capture callback end of the ring
+pub struct CaptureWriter {
+    producer: Producer<f32>,
+    overruns: Arc<AtomicU64>,
+}
+
+impl CaptureWriter {
+    /// write - real-time safe: the block is written as a whole or counted as an overrun,
+    /// so that the ring always holds whole frames
+    pub fn write<T>(&mut self, samples: &[T])
+    where
+        T: Sample + ToSample<f32>,
+    {
+        match self.producer.write_chunk_uninit(samples.len()) {
+            Ok(chunk) => {
+                chunk.fill_from_iter(samples.iter().map(|x: &T| T::to_sample::<f32>(*x)));
+            }
+            Err(_) => {
+                self.overruns.fetch_add(1, Relaxed);
+            }
+        }
+    }
+
+    /// the number of samples that can be written without an overrun
+    pub fn free(&self) -> usize {
+        self.producer.slots()
+    }
+}
+
+/// CaptureReader - the distributor end of the ring
+pub struct CaptureReader {
+    consumer: Consumer<f32>,
+    sample_rate: u32,
+    channels: usize,
+    overruns: Arc<AtomicU64>,
+}
+
+impl CaptureReader {
+    /// read - all the whole frames in the ring as one block, if any
+    pub fn read(&mut self) -> Option<Arc<[f32]>> {
+        let available = self.consumer.slots();
+        let n = available - available % self.channels;
+        if n == 0 {
+            return None;
+        }
+        let chunk = self.consumer.read_chunk(n).ok()?;
+        let (first, second) = chunk.as_slices();
+        let block: Arc<[f32]> = first.iter().chain(second).copied().collect();
+        chunk.commit_all();
+        Some(block)
+    }
+
+    /// the sample rate of the capture
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// the number of captured blocks dropped because the ring was full
+    pub fn overruns(&self) -> u64 {
+        self.overruns.load(Relaxed)
+    }
+
+    /// true when the capture stream is gone and the ring is empty
+    pub fn is_finished(&self) -> bool {
+        self.consumer.is_abandoned() && self.consumer.is_empty()
+    }
+}
+
+/// run_distributor - the capture distributor thread
+///
+/// maps the captured samples to stereo, and shares each block as one Arc<[f32]> with all
+/// registered clients in the CLIENTS ChannnelStream hashmap and keeps them for the pre-roll
+/// also feeds the recorder while recording and the RMS monitor channel if the RMS option is set
+/// ends when the capture stream has been dropped
+pub fn run_distributor(
+    mut reader: CaptureReader,
+    mapper: ChannelMapper,
+    rms_sender: Sender<Arc<[f32]>>,
+) {
+    let mut stereo_samples: Vec<f32> = Vec::with_capacity(16384);
+    let mut receiving = false;
+    let mut logged_overruns = 0;
+    let mut last_log = Instant::now() - OVERRUN_LOG_INTERVAL;
+    PRE_ROLL.lock().reset(reader.sample_rate());
+    while !reader.is_finished() {
+        let Some(captured) = reader.read() else {
+            thread::sleep(DISTRIBUTE_INTERVAL);
+            continue;
+        };
+        if !receiving {
+            ui_log("The capture distributor is now receiving samples".to_string());
+            receiving = true;
+        }
+        let stereo = if mapper.is_identity() {
+            captured.clone()
+        } else {
+            stereo_samples.clear();
+            mapper.map(&captured, &mut stereo_samples);
+            Arc::from(stereo_samples.as_slice())
+        };
+        let (monitor_rms, pre_roll_ms) = {
+            let conf = CONFIG.read();
+            (conf.monitor_rms, conf.max_pre_roll_ms())
+        };
+        // multichannel passthrough clients get the samples as captured
+        let clients = CLIENTS.read();
+        for (_, v) in clients.iter() {
+            if v.channels() == 2 {
+                v.write(&stereo);
+            } else {
+                v.write(&captured);
+            }
+        }
+        // under the CLIENTS lock, see preroll.rs
+        PRE_ROLL.lock().push(&stereo, &captured, pre_roll_ms);
+        drop(clients);
+        record(&stereo, reader.sample_rate());
+        if monitor_rms {
+            // the RMS monitor may be gone
+            let _ = rms_sender.send(stereo);
+        }
+        let overruns = reader.overruns();
+        CAPTURE_OVERRUNS.store(overruns, Relaxed);
+        if overruns > logged_overruns && last_log.elapsed() >= OVERRUN_LOG_INTERVAL {
+            ui_log(format!(
+                "*W*W*> Capture overrun: {} captured blocks dropped ({overruns} in total)",
+                overruns - logged_overruns
+            ));
+            logged_overruns = overruns;
+            last_log = Instant::now();
+        }
+    }
+    ui_log("The capture distributor has stopped".to_string());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn whole_frames() {
+        let (mut writer, mut reader) = capture_ring(1000, 2);
+        assert!(reader.read().is_none());
+        writer.write(&[0.5f32; 6]);
+        writer.write(&[i16::MAX; 4]);
+        let block = reader.read().unwrap();
+        assert_eq!(block.len(), 10);
+        assert_eq!(&block[..6], &[0.5f32; 6]);
+        assert!(block[6..].iter().all(|s| (*s - 1.0).abs() < 0.001));
+        assert!(reader.read().is_none());
+        // the ring wraps around without losing samples
+        for n in 0..10 {
+            writer.write(&[n as f32; 300]);
+            let block = reader.read().unwrap();
+            assert_eq!(block.len(), 300);
+            assert!(block.iter().all(|s| *s == n as f32));
+        }
+        assert_eq!(reader.overruns(), 0);
+        assert!(!reader.is_finished());
+        drop(writer);
+        assert!(reader.is_finished());
+    }
+
+    #[test]
+    fn overruns() {
+        // 1 second of 1 kHz stereo: 2000 samples
+        let (mut writer, mut reader) = capture_ring(1000, 2);
+        for _ in 0..4 {
+            writer.write(&[0.25f32; 600]);
+        }
+        // the fourth block doesn't fit and is dropped as a whole
+        assert_eq!(reader.overruns(), 1);
+        assert_eq!(reader.read().unwrap().len(), 1800);
+        writer.write(&[0.25f32; 600]);
+        assert_eq!(reader.read().unwrap().len(), 600);
+        assert_eq!(reader.overruns(), 1);
+        // the samples are still there after the capture stream is gone
+        writer.write(&[0.25f32; 2]);
+        drop(writer);
+        assert!(!reader.is_finished());
+        assert_eq!(reader.read().unwrap().len(), 2);
+        assert!(reader.is_finished());
+    }
+}
@@ -0,0 +1,231 @@
+///
+/// channelmap.rs
+///
+/// ChannelMapper: maps the interleaved samples of the capture device to the
+/// stereo layout that is streamed and advertised to the renderers
+///
+/// surround is downmixed with the ITU-R BS.775 coefficients (the LFE channel is dropped),
+/// mono is copied to both channels, and the swap, balance and mono sum options
+/// are applied to the stereo result
+///
+use crate::utils::configuration::Configuration;
+
+/// -3 dB, the downmix coefficient of the center and surround channels
+const MINUS_3DB: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+/// the stereo options of the channel mapping
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ChannelOptions {
+    /// swap the left and right channel
+    pub swap: bool,
+    /// -1.0 (left only) .. 0.0 (center) .. 1.0 (right only)
+    pub balance: f32,
+    /// the average of left and right on both channels
+    pub mono_sum: bool,
+}
+
+impl ChannelOptions {
+    pub fn from_config(conf: &Configuration) -> ChannelOptions {
+        ChannelOptions {
+            swap: conf.channel_swap.unwrap_or(false),
+            balance: conf.balance.unwrap_or(0.0).clamp(-1.0, 1.0),
+            mono_sum: conf.mono_sum.unwrap_or(false),
+        }
+    }
+}
+
+/// ChannelMapper - converts frames of `channels` samples to stereo frames
+#[derive(Debug, Clone)]
+pub struct ChannelMapper {
+    channels: usize,
+    // the (left, right) coefficients of each input channel
+    coefficients: Vec<(f32, f32)>,
+    left_gain: f32,
+    right_gain: f32,
+    options: ChannelOptions,
+}
+
+impl ChannelMapper {
+    pub fn new(channels: u16, options: ChannelOptions) -> ChannelMapper {
+        let channels = channels.max(1) as usize;
+        let mut coefficients = downmix_coefficients(channels);
+        // normalize so that a full scale signal on all channels can't clip
+        let sum_l: f32 = coefficients.iter().map(|c| c.0).sum();
+        let sum_r: f32 = coefficients.iter().map(|c| c.1).sum();
+        for c in coefficients.iter_mut() {
+            c.0 /= sum_l.max(1.0);
+            c.1 /= sum_r.max(1.0);
+        }
+        ChannelMapper {
+            channels,
+            coefficients,
+            left_gain: (1.0 - options.balance).min(1.0),
+            right_gain: (1.0 + options.balance).min(1.0),
+            options,
+        }
+    }
+
+    /// is_identity - true if the samples don't need any mapping (plain stereo)
+    pub fn is_identity(&self) -> bool {
+        self.channels == 2 && self.options == ChannelOptions::default()
+    }
+
+    /// map - append the stereo frames for the interleaved input samples to out
+    pub fn map(&self, input: &[f32], out: &mut Vec<f32>) {
+        out.reserve(input.len() / self.channels * 2);
+        for frame in input.chunks_exact(self.channels) {
+            let (mut left, mut right) = frame
+                .iter()
+                .zip(self.coefficients.iter())
+                .fold((0.0f32, 0.0f32), |(l, r), (s, c)| {
+                    (l + s * c.0, r + s * c.1)
+                });
+            if self.options.swap {
+                std::mem::swap(&mut left, &mut right);
+            }
+            if self.options.mono_sum {
+                let mono = (left + right) * 0.5;
+                left = mono;
+                right = mono;
+            }
+            out.push(left * self.left_gain);
+            out.push(right * self.right_gain);
+        }
+    }
+}
+
+/// the (left, right) downmix coefficients for the default channel order
+/// of WAVE_FORMAT_EXTENSIBLE (FL FR FC LFE BL BR SL SR)
+fn downmix_coefficients(channels: usize) -> Vec<(f32, f32)> {
+    const L: (f32, f32) = (1.0, 0.0);
+    const R: (f32, f32) = (0.0, 1.0);
+    const C: (f32, f32) = (MINUS_3DB, MINUS_3DB);
+    const LFE: (f32, f32) = (0.0, 0.0);
+    const LS: (f32, f32) = (MINUS_3DB, 0.0);
+    const RS: (f32, f32) = (0.0, MINUS_3DB);
+    match channels {
+        1 => vec![(1.0, 1.0)],
+        2 => vec![L, R],
+        3 => vec![L, R, C],
+        4 => vec![L, R, LS, RS],
+        5 => vec![L, R, C, LS, RS],
+        6 => vec![L, R, C, LFE, LS, RS],
+        7 => vec![L, R, C, LFE, C, LS, RS],
+        8 => vec![L, R, C, LFE, LS, RS, LS, RS],
+        // unknown layout: only the first two channels
+        n => {
+            let mut coefficients = vec![L, R];
+            coefficients.resize(n, LFE);
+            coefficients
+        }
+    }
+}
+
+/// channel_mask - the WAVE_FORMAT_EXTENSIBLE speaker positions of the default layout
+/// for the channel count (the same layouts as the downmix coefficients)
+pub fn channel_mask(channels: u16) -> u32 {
+    match channels {
+        1 => 0x4,   // FC
+        2 => 0x3,   // FL FR
+        3 => 0x7,   // FL FR FC
+        4 => 0x33,  // FL FR BL BR
+        5 => 0x37,  // FL FR FC BL BR
+        6 => 0x3f,  // FL FR FC LFE BL BR
+        7 => 0x70f, // FL FR FC LFE BC SL SR
+        8 => 0x63f, // FL FR FC LFE BL BR SL SR
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(channels: u16, options: ChannelOptions, input: &[f32]) -> Vec<f32> {
+        let mut out = Vec::new();
+        ChannelMapper::new(channels, options).map(input, &mut out);
+        out
+    }
+
+    fn assert_close(a: &[f32], b: &[f32]) {
+        assert_eq!(a.len(), b.len());
+        for (x, y) in a.iter().zip(b) {
+            assert!((x - y).abs() < 1e-5, "{a:?} != {b:?}");
+        }
+    }
+
+    #[test]
+    fn stereo() {
+        let options = ChannelOptions::default();
+        assert!(ChannelMapper::new(2, options).is_identity());
+        assert_close(
+            &map(2, options, &[0.1, 0.2, 0.3, 0.4]),
+            &[0.1, 0.2, 0.3, 0.4],
+        );
+    }
+
+    #[test]
+    fn mono_upmix() {
+        assert_close(
+            &map(1, ChannelOptions::default(), &[0.5, -0.25]),
+            &[0.5, 0.5, -0.25, -0.25],
+        );
+    }
+
+    #[test]
+    fn surround_downmix() {
+        let options = ChannelOptions::default();
+        let norm = 1.0 + 2.0 * MINUS_3DB;
+        // 5.1: FL FR FC LFE BL BR
+        assert_close(
+            &map(6, options, &[1.0, 0.0, 0.0, 1.0, 0.0, 0.0]),
+            &[1.0 / norm, 0.0],
+        );
+        assert_close(
+            &map(6, options, &[0.0, 0.0, 1.0, 0.0, 0.0, 0.0]),
+            &[MINUS_3DB / norm, MINUS_3DB / norm],
+        );
+        assert_close(
+            &map(6, options, &[0.0, 0.0, 0.0, 0.0, 0.0, 1.0]),
+            &[0.0, MINUS_3DB / norm],
+        );
+        // full scale on all channels doesn't clip
+        let out = map(8, options, &[1.0; 8]);
+        assert!(out.iter().all(|s| *s <= 1.0 + 1e-6));
+        // a truncated frame is dropped
+        assert_eq!(map(6, options, &[0.0; 9]).len(), 2);
+    }
+
+    #[test]
+    fn channel_masks() {
+        for channels in 1..=8u16 {
+            assert_eq!(channel_mask(channels).count_ones(), channels as u32);
+        }
+        assert_eq!(channel_mask(9), 0);
+    }
+
+    #[test]
+    fn stereo_options() {
+        let swap = ChannelOptions {
+            swap: true,
+            ..Default::default()
+        };
+        assert!(!ChannelMapper::new(2, swap).is_identity());
+        assert_close(&map(2, swap, &[0.1, 0.2]), &[0.2, 0.1]);
+        let mono_sum = ChannelOptions {
+            mono_sum: true,
+            ..Default::default()
+        };
+        assert_close(&map(2, mono_sum, &[0.1, 0.3]), &[0.2, 0.2]);
+        let balance = ChannelOptions {
+            balance: 0.5,
+            ..Default::default()
+        };
+        assert_close(&map(2, balance, &[0.4, 0.4]), &[0.2, 0.4]);
+        let balance = ChannelOptions {
+            balance: -1.0,
+            ..Default::default()
+        };
+        assert_close(&map(2, balance, &[0.4, 0.4]), &[0.4, 0.0]);
+    }
+}
@@ -6,8 +6,11 @@ use lexopt::{
 };
 use log::LevelFilter;
 
-use crate::enums::streaming::StreamingFormat;
-use crate::utils::traits::SanitizeArg;
+use crate::enums::streaming::{Dither, KeepAlivePolicy, StreamingFormat, WavHeader};
+use crate::utils::{
+    audiodevices::CaptureSettings, audiosource::SourceSpec, configuration::MAX_PRE_ROLL_MS,
+    traits::SanitizeArg,
+};
 
 #[derive(Clone, Debug)]
 pub struct Args {
@@ -22,10 +25,23 @@ pub struct Args {
     pub disable_chunked: Option<bool>,
     pub use_wave_format: Option<bool>,
     pub bits_per_sample: Option<u16>,
+    pub float_samples: Option<bool>,
     pub streaming_format: Option<StreamingFormat>,
+    pub dither: Option<Dither>,
+    pub wav_header: Option<WavHeader>,
+    pub flac_compression_level: Option<u32>,
+    pub flac_block_size: Option<u32>,
+    pub flac_verify: Option<bool>,
     pub player_ip: Option<String>,
     pub ip_address: Option<String>,
     pub inject_silence: Option<bool>,
+    pub keep_alive: Option<KeepAlivePolicy>,
+    pub pre_roll_ms: Option<u32>,
+    pub low_latency: Option<bool>,
+    pub record: Option<bool>,
+    pub audio_source: Option<String>,
+    pub follow_default: Option<bool>,
+    pub capture_config: Option<CaptureSettings>,
 }
 
 impl Default for Args {
@@ -48,10 +64,23 @@ impl Args {
             disable_chunked: None,
             use_wave_format: None,
             bits_per_sample: None,
+            float_samples: None,
             streaming_format: None,
+            dither: None,
+            wav_header: None,
+            flac_compression_level: None,
+            flac_block_size: None,
+            flac_verify: None,
             player_ip: None,
             ip_address: None,
             inject_silence: None,
+            keep_alive: None,
+            pre_roll_ms: None,
+            low_latency: None,
+            record: None,
+            audio_source: None,
+            follow_default: None,
+            capture_config: None,
         }
     }
 
@@ -70,11 +99,25 @@ Recognized options:
     -l (--log_level) string : log_level (info/debug) [info]
     -i (--ssdp_interval) i32 : ssdp_interval_mins [10]
     -d (--disable_chunked) bool : disable_chunked encoding [true]
-    -b (--bits) u16 : bits_per_sample (16/24) [16]
-    -f (--format) string : streaming_format (lpcm/flac/wav) [LPCM]
+    -b (--bits) string : bits_per_sample (16/24/32/32f, 32f is 32 bit float WAV) [16]
+    -f (--format) string : streaming_format (lpcm/flac/wav/mp3/opus/oggflac) [LPCM]
+    -D (--dither) string : dither when reducing to 16 bit (none/tpdf/shaped) [none]
+    -w (--wav_header) string : WAV header (classic/rf64/w64) [classic]
+    -L (--flac_level) u32 : FLAC compression level (0..8) [0]
+    -B (--flac_blocksize) u32 : FLAC block size (0 = libFLAC default, 16..65535) [0]
+    -V (--flac_verify) bool : verify the FLAC encoding [false]
     -o (--player_ip) string : the player ip address [last used player]
     -e (--ip_address) string : ip address of the network interface [last used]
     -S (--inject_silence) bool : inject silence into stream (bool) [false]
+    -k (--keep_alive) string : streamed when nothing is captured (silence/noise/none) [silence]
+    -P (--pre_roll) u32 : msecs of recent audio sent at once when a renderer connects (0..10000) [0]
+    -u (--low_latency) bool : low latency mode, no pre-roll and small reads [false]
+    -R (--record) bool : record the captured audio to the RecordingDir [false]
+    -A (--audio_source) string : capture, pcm:<rate>:<channels>:<s16le/s24le/s32le/f32le>:<path, - is stdin>,
+                                 file:<wav or flac file> or tone:<sweep/pink/ident>[:<rate>[:<channels>]] [capture]
+    -F (--follow_default) bool : capture the default audio output device, also when it changes [false]
+    -C (--capture_config) string : <rate>:<format>:<channels>:<buffer frames> of the capture device, empty fields keep
+                                   the device default, format i8/i16/i32/i64/u8/u16/u32/u64/f32/f64 [default]
 "#
         );
         println!("{:?}", self);
@@ -159,12 +202,20 @@ Recognized options:
                 }
                 Short('b') | Long("bits_per_sample") => {
                     if let Ok(bps) = argparser.value() {
-                        let n: u16 = bps.parse().unwrap();
-                        if let 16 | 24 = n {
-                            self.bits_per_sample = Some(n);
-                        } else {
-                            println!("bits_per_sample not 16 or 24");
-                            self.usage();
+                        let bps = bps.string().unwrap_or_default();
+                        match bps.as_str() {
+                            "16" | "24" | "32" => {
+                                self.bits_per_sample = Some(bps.parse().unwrap());
+                                self.float_samples = Some(false);
+                            }
+                            "32f" | "32F" => {
+                                self.bits_per_sample = Some(32);
+                                self.float_samples = Some(true);
+                            }
+                            _ => {
+                                println!("bits_per_sample not 16, 24, 32 or 32f");
+                                self.usage();
+                            }
                         }
                     }
                 }
@@ -182,6 +233,15 @@ Recognized options:
                             "FLAC" | "flac" | "Flac" => {
                                 self.streaming_format = Some(StreamingFormat::Flac)
                             }
+                            "MP3" | "mp3" | "Mp3" => {
+                                self.streaming_format = Some(StreamingFormat::Mp3)
+                            }
+                            "OPUS" | "opus" | "Opus" => {
+                                self.streaming_format = Some(StreamingFormat::Opus)
+                            }
+                            "OGGFLAC" | "oggflac" | "OggFlac" => {
+                                self.streaming_format = Some(StreamingFormat::OggFlac)
+                            }
                             _ => {
                                 println!("invalid streaming_format {streaming_format}");
                                 self.usage();
@@ -189,6 +249,129 @@ Recognized options:
                         }
                     }
                 }
+                Short('D') | Long("dither") => {
+                    if let Ok(dither) = argparser.value() {
+                        let dither = dither.string().unwrap_or_default();
+                        match dither.parse::<Dither>() {
+                            Ok(d) => self.dither = Some(d),
+                            Err(e) => {
+                                println!("{e}");
+                                self.usage();
+                            }
+                        }
+                    }
+                }
+                Short('w') | Long("wav_header") => {
+                    if let Ok(header) = argparser.value() {
+                        let header = header.string().unwrap_or_default();
+                        match header.parse::<WavHeader>() {
+                            Ok(h) => self.wav_header = Some(h),
+                            Err(e) => {
+                                println!("{e}");
+                                self.usage();
+                            }
+                        }
+                    }
+                }
+                Short('k') | Long("keep_alive") => {
+                    if let Ok(policy) = argparser.value() {
+                        let policy = policy.string().unwrap_or_default();
+                        match policy.parse::<KeepAlivePolicy>() {
+                            Ok(p) => self.keep_alive = Some(p),
+                            Err(e) => {
+                                println!("{e}");
+                                self.usage();
+                            }
+                        }
+                    }
+                }
+                Short('P') | Long("pre_roll") => {
+                    if let Ok(ms) = argparser.value() {
+                        let ms: u32 = ms.parse().unwrap();
+                        if ms <= MAX_PRE_ROLL_MS {
+                            self.pre_roll_ms = Some(ms);
+                        } else {
+                            println!("pre_roll not 0..{MAX_PRE_ROLL_MS}");
+                            self.usage();
+                        }
+                    }
+                }
+                Short('u') | Long("low_latency") => {
+                    if let Ok(low_latency) = argparser.value() {
+                        self.low_latency = Some(
+                            low_latency
+                                .string()
+                                .unwrap()
+                                .sanitize_bool()
+                                .parse()
+                                .unwrap(),
+                        );
+                    }
+                }
+                Short('R') | Long("record") => {
+                    if let Ok(record) = argparser.value() {
+                        self.record =
+                            Some(record.string().unwrap().sanitize_bool().parse().unwrap());
+                    }
+                }
+                Short('A') | Long("audio_source") => {
+                    if let Ok(spec) = argparser.value() {
+                        let spec = spec.string().unwrap();
+                        match spec.parse::<SourceSpec>() {
+                            Ok(spec) => self.audio_source = Some(spec.to_string()),
+                            Err(e) => {
+                                println!("audio_source: {e}");
+                                self.usage();
+                            }
+                        }
+                    }
+                }
+                Short('F') | Long("follow_default") => {
+                    if let Ok(follow) = argparser.value() {
+                        self.follow_default =
+                            Some(follow.string().unwrap().sanitize_bool().parse().unwrap());
+                    }
+                }
+                Short('C') | Long("capture_config") => {
+                    if let Ok(settings) = argparser.value() {
+                        let settings = settings.string().unwrap();
+                        match settings.parse::<CaptureSettings>() {
+                            Ok(settings) => self.capture_config = Some(settings),
+                            Err(e) => {
+                                println!("capture_config: {e}");
+                                self.usage();
+                            }
+                        }
+                    }
+                }
+                Short('L') | Long("flac_level") => {
+                    if let Ok(level) = argparser.value() {
+                        let level: u32 = level.parse().unwrap();
+                        if level <= 8 {
+                            self.flac_compression_level = Some(level);
+                        } else {
+                            println!("flac_level not 0..8");
+                            self.usage();
+                        }
+                    }
+                }
+                Short('B') | Long("flac_blocksize") => {
+                    if let Ok(size) = argparser.value() {
+                        let size: u32 = size.parse().unwrap();
+                        if let 0 | 16..=65535 = size {
+                            self.flac_block_size = Some(size);
+                        } else {
+                            println!("flac_blocksize not 0 or 16..65535");
+                            self.usage();
+                        }
+                    }
+                }
+                Short('V') | Long("flac_verify") => {
+                    if let Ok(verify) = argparser.value() {
+                        self.flac_verify =
+                            Some(verify.string().unwrap().sanitize_bool().parse().unwrap());
+                    }
+                }
                 Short('o') | Long("player") => {
                     if let Ok(player) = argparser.value() {
                         self.player_ip = Some(player.string().unwrap_or_default());
@@ -1,11 +1,13 @@
 use crate::{
-    enums::streaming::StreamingFormat,
+    enums::streaming::{Dither, KeepAlivePolicy, OverflowPolicy, StreamingFormat, WavHeader},
     globals::statics::{HAVE_UI, SERVER_PORT},
+    utils::{audiodevices::CaptureSettings, audiosource::SourceSpec},
 };
 use lexopt::{prelude::*, Parser};
 use log::LevelFilter;
 use serde::{Deserialize, Serialize};
 use std::{
+    collections::HashMap,
     f64, fs,
     fs::File,
     io::{BufRead, BufReader, BufWriter, Write},
@@ -16,6 +18,20 @@ use toml::*;
 
 const CONFIGFILE: &str = "config{}.toml";
 const PKGNAME: &str = env!("CARGO_PKG_NAME");
+// the default ICY StreamTitle template, see icymeta.rs
+const DEFAULT_ICY_TITLE: &str = "swyh-rs: {source}";
+// the level of the keep-alive noise in dBFS
+const DEFAULT_NOISE_LEVEL: f32 = -60.0;
+// the audio (msecs) that can be queued for a client
+const DEFAULT_CLIENT_QUEUE_MS: u32 = 2000;
+// a client that doesn't read queued audio for this many seconds is removed
+const DEFAULT_STALL_TIMEOUT: u32 = 10;
+// the longest pre-roll in msecs
+pub const MAX_PRE_ROLL_MS: u32 = 10000;
+// the default msecs of audio drift compensation keeps in a client queue
+const DEFAULT_DRIFT_TARGET_MS: u32 = 300;
+// a new recording file every hour
+const DEFAULT_RECORDING_ROTATE_MINS: u32 = 60;
 
 // the configuration struct, read from and saved in config.ini
 #[derive(Deserialize, Serialize, Clone, Debug)]
@@ -33,6 +49,13 @@ pub struct Configuration {
     pub sound_source: String,
     #[serde(rename(deserialize = "SoundCardIndex", serialize = "SoundCardIndex"))]
     pub sound_source_index: Option<i32>,
+    #[serde(rename(deserialize = "AudioSource", serialize = "AudioSource"))]
+    pub audio_source: Option<String>,
+    #[serde(rename(
+        deserialize = "CaptureFollowDefault",
+        serialize = "CaptureFollowDefault"
+    ))]
+    pub capture_follow_default: Option<bool>,
     #[serde(rename(deserialize = "LogLevel", serialize = "LogLevel"))]
     pub log_level: LevelFilter,
     #[serde(rename(deserialize = "SSDPIntervalMins", serialize = "SSDPIntervalMins"))]
@@ -45,12 +68,77 @@ pub struct Configuration {
     pub use_wave_format: bool,
     #[serde(rename(deserialize = "BitsPerSample", serialize = "BitsPerSample"))]
     pub bits_per_sample: Option<u16>,
+    #[serde(rename(deserialize = "FloatSamples", serialize = "FloatSamples"))]
+    pub float_samples: Option<bool>,
+    #[serde(rename(deserialize = "WavHeader", serialize = "WavHeader"))]
+    pub wav_header: Option<WavHeader>,
     #[serde(rename(deserialize = "StreamingFormat", serialize = "StreamingFormat"))]
     pub streaming_format: Option<StreamingFormat>,
+    #[serde(rename(
+        deserialize = "FlacCompressionLevel",
+        serialize = "FlacCompressionLevel"
+    ))]
+    pub flac_compression_level: Option<u32>,
+    #[serde(rename(deserialize = "FlacBlockSize", serialize = "FlacBlockSize"))]
+    pub flac_block_size: Option<u32>,
+    #[serde(rename(deserialize = "FlacVerify", serialize = "FlacVerify"))]
+    pub flac_verify: Option<bool>,
+    #[serde(rename(deserialize = "Mp3Bitrate", serialize = "Mp3Bitrate"))]
+    pub mp3_bitrate: Option<u32>,
+    #[serde(rename(deserialize = "Mp3Vbr", serialize = "Mp3Vbr"))]
+    pub mp3_vbr: Option<bool>,
+    #[serde(rename(deserialize = "OpusBitrate", serialize = "OpusBitrate"))]
+    pub opus_bitrate: Option<u32>,
+    #[serde(rename(deserialize = "OutputSampleRate", serialize = "OutputSampleRate"))]
+    pub output_sample_rate: Option<u32>,
+    #[serde(rename(
+        deserialize = "MultichannelRenderers",
+        serialize = "MultichannelRenderers"
+    ))]
+    pub multichannel_renderers: Option<Vec<String>>,
+    #[serde(rename(deserialize = "ChannelSwap", serialize = "ChannelSwap"))]
+    pub channel_swap: Option<bool>,
+    #[serde(rename(deserialize = "Balance", serialize = "Balance"))]
+    pub balance: Option<f32>,
+    #[serde(rename(deserialize = "MonoSum", serialize = "MonoSum"))]
+    pub mono_sum: Option<bool>,
+    #[serde(rename(deserialize = "Dither", serialize = "Dither"))]
+    pub dither: Option<Dither>,
     #[serde(rename(deserialize = "MonitorRms", serialize = "MonitorRms"))]
     pub monitor_rms: bool,
     #[serde(rename(deserialize = "CaptureTimeout", serialize = "CaptureTimeout"))]
     pub capture_timeout: Option<u32>,
+    #[serde(rename(deserialize = "KeepAlive", serialize = "KeepAlive"))]
+    pub keep_alive: Option<KeepAlivePolicy>,
+    #[serde(rename(deserialize = "KeepAliveNoiseLevel", serialize = "KeepAliveNoiseLevel"))]
+    pub keep_alive_noise_level: Option<f32>,
+    #[serde(rename(deserialize = "ClientQueueMs", serialize = "ClientQueueMs"))]
+    pub client_queue_ms: Option<u32>,
+    #[serde(rename(deserialize = "OverflowPolicy", serialize = "OverflowPolicy"))]
+    pub overflow_policy: Option<OverflowPolicy>,
+    #[serde(rename(deserialize = "StallTimeout", serialize = "StallTimeout"))]
+    pub stall_timeout: Option<u32>,
+    #[serde(rename(deserialize = "PreRollMs", serialize = "PreRollMs"))]
+    pub pre_roll_ms: Option<u32>,
+    #[serde(rename(deserialize = "LowLatency", serialize = "LowLatency"))]
+    pub low_latency: Option<bool>,
+    #[serde(rename(deserialize = "DriftCompensation", serialize = "DriftCompensation"))]
+    pub drift_compensation: Option<bool>,
+    #[serde(rename(deserialize = "DriftTargetMs", serialize = "DriftTargetMs"))]
+    pub drift_target_ms: Option<u32>,
+    #[serde(rename(deserialize = "RecordingDir", serialize = "RecordingDir"))]
+    pub recording_dir: Option<String>,
+    #[serde(rename(deserialize = "RecordingFormat", serialize = "RecordingFormat"))]
+    pub recording_format: Option<StreamingFormat>,
+    #[serde(rename(deserialize = "RecordingRotateMins", serialize = "RecordingRotateMins"))]
+    pub recording_rotate_mins: Option<u32>,
+    #[serde(rename(deserialize = "RecordingRotateMb", serialize = "RecordingRotateMb"))]
+    pub recording_rotate_mb: Option<u32>,
+    #[serde(rename(
+        deserialize = "RecordingSilenceSplit",
+        serialize = "RecordingSilenceSplit"
+    ))]
+    pub recording_silence_split: Option<u32>,
     #[serde(rename(deserialize = "InjectSilence", serialize = "InjectSilence"))]
     pub inject_silence: Option<bool>,
     #[serde(rename(deserialize = "LastRenderer", serialize = "LastRenderer"))]
@@ -61,6 +149,25 @@ pub struct Configuration {
     config_dir: PathBuf,
     #[serde(rename(deserialize = "ConfigId", serialize = "ConfigId"))]
     pub config_id: Option<String>,
+    #[serde(rename(deserialize = "ApiToken", serialize = "ApiToken"))]
+    pub api_token: Option<String>,
+    #[serde(rename(deserialize = "IcyTitle", serialize = "IcyTitle"))]
+    pub icy_title: Option<String>,
+    #[serde(rename(deserialize = "IcyMetaFile", serialize = "IcyMetaFile"))]
+    pub icy_meta_file: Option<String>,
+    #[serde(rename(deserialize = "IcyMetaCommand", serialize = "IcyMetaCommand"))]
+    pub icy_meta_command: Option<String>,
+    // a table must come after the other values in the TOML file
+    #[serde(rename(deserialize = "RendererSampleRates", serialize = "RendererSampleRates"))]
+    pub renderer_sample_rates: Option<HashMap<String, u32>>,
+    #[serde(rename(deserialize = "RendererKeepAlive", serialize = "RendererKeepAlive"))]
+    pub renderer_keep_alive: Option<HashMap<String, KeepAlivePolicy>>,
+    #[serde(rename(deserialize = "RendererPreRoll", serialize = "RendererPreRoll"))]
+    pub renderer_pre_roll: Option<HashMap<String, u32>>,
+    #[serde(rename(deserialize = "RendererLowLatency", serialize = "RendererLowLatency"))]
+    pub renderer_low_latency: Option<HashMap<String, bool>>,
+    #[serde(rename(deserialize = "CaptureConfig", serialize = "CaptureConfig"))]
+    pub capture_config: Option<HashMap<String, CaptureSettings>>,
 }
 
 impl Default for Configuration {
@@ -76,20 +183,59 @@ impl Configuration {
             auto_resume: false,
             sound_source: "None".to_string(),
             sound_source_index: None,
+            audio_source: Some("capture".to_string()),
+            capture_follow_default: Some(false),
             log_level: LevelFilter::Info,
             ssdp_interval_mins: 10.0,
             auto_reconnect: !HAVE_UI.load(Relaxed),
             disable_chunked: true,
             use_wave_format: false,
             bits_per_sample: Some(16),
+            float_samples: Some(false),
+            wav_header: Some(WavHeader::Classic),
             streaming_format: Some(StreamingFormat::Lpcm),
+            flac_compression_level: Some(0),
+            flac_block_size: Some(0),
+            flac_verify: Some(false),
+            mp3_bitrate: Some(320),
+            mp3_vbr: Some(false),
+            opus_bitrate: Some(128),
+            output_sample_rate: Some(0),
+            multichannel_renderers: Some(Vec::new()),
+            channel_swap: Some(false),
+            balance: Some(0.0),
+            mono_sum: Some(false),
+            dither: Some(Dither::None),
             monitor_rms: false,
             capture_timeout: Some(2000),
+            keep_alive: Some(KeepAlivePolicy::Silence),
+            keep_alive_noise_level: Some(DEFAULT_NOISE_LEVEL),
+            client_queue_ms: Some(DEFAULT_CLIENT_QUEUE_MS),
+            overflow_policy: Some(OverflowPolicy::DropOldest),
+            stall_timeout: Some(DEFAULT_STALL_TIMEOUT),
+            pre_roll_ms: Some(0),
+            low_latency: Some(false),
+            drift_compensation: Some(false),
+            drift_target_ms: Some(DEFAULT_DRIFT_TARGET_MS),
+            recording_dir: Some(String::new()),
+            recording_format: Some(StreamingFormat::Flac),
+            recording_rotate_mins: Some(DEFAULT_RECORDING_ROTATE_MINS),
+            recording_rotate_mb: Some(0),
+            recording_silence_split: Some(0),
             inject_silence: Some(false),
             last_renderer: "None".to_string(),
             last_network: "None".to_string(),
             config_dir: Self::get_config_dir(),
             config_id: Some(Self::get_config_id()),
+            api_token: None,
+            icy_title: Some(DEFAULT_ICY_TITLE.to_string()),
+            icy_meta_file: None,
+            icy_meta_command: None,
+            renderer_sample_rates: Some(HashMap::new()),
+            renderer_keep_alive: Some(HashMap::new()),
+            renderer_pre_roll: Some(HashMap::new()),
+            renderer_low_latency: Some(HashMap::new()),
+            capture_config: Some(HashMap::new()),
         }
     }
 
@@ -146,15 +292,181 @@ impl Configuration {
             config.configuration.server_port = Some(SERVER_PORT);
             force_update = true;
         }
-        if let Some(16 | 24) = config.configuration.bits_per_sample {
+        if let Some(16 | 24 | 32) = config.configuration.bits_per_sample {
         } else {
             config.configuration.bits_per_sample = Some(16);
             force_update = true;
         }
+        if config.configuration.float_samples.is_none() {
+            config.configuration.float_samples = Some(false);
+            force_update = true;
+        }
+        if config.configuration.wav_header.is_none() {
+            config.configuration.wav_header = Some(WavHeader::Classic);
+            force_update = true;
+        }
+        if !matches!(config.configuration.flac_compression_level, Some(0..=8)) {
+            config.configuration.flac_compression_level = Some(0);
+            force_update = true;
+        }
+        // 0 is the libFLAC default, otherwise 16..65535
+        if !matches!(config.configuration.flac_block_size, Some(0 | 16..=65535)) {
+            config.configuration.flac_block_size = Some(0);
+            force_update = true;
+        }
+        if config.configuration.flac_verify.is_none() {
+            config.configuration.flac_verify = Some(false);
+            force_update = true;
+        }
+        if config.configuration.mp3_bitrate.is_none() {
+            config.configuration.mp3_bitrate = Some(320);
+            force_update = true;
+        }
+        if config.configuration.mp3_vbr.is_none() {
+            config.configuration.mp3_vbr = Some(false);
+            force_update = true;
+        }
+        if config.configuration.opus_bitrate.is_none() {
+            config.configuration.opus_bitrate = Some(128);
+            force_update = true;
+        }
+        if config.configuration.output_sample_rate.is_none() {
+            config.configuration.output_sample_rate = Some(0);
+            force_update = true;
+        }
+        if config.configuration.renderer_sample_rates.is_none() {
+            config.configuration.renderer_sample_rates = Some(HashMap::new());
+            force_update = true;
+        }
+        if config.configuration.multichannel_renderers.is_none() {
+            config.configuration.multichannel_renderers = Some(Vec::new());
+            force_update = true;
+        }
+        if config.configuration.channel_swap.is_none() {
+            config.configuration.channel_swap = Some(false);
+            force_update = true;
+        }
+        if config.configuration.balance.is_none() {
+            config.configuration.balance = Some(0.0);
+            force_update = true;
+        }
+        if config.configuration.mono_sum.is_none() {
+            config.configuration.mono_sum = Some(false);
+            force_update = true;
+        }
+        if config.configuration.dither.is_none() {
+            config.configuration.dither = Some(Dither::None);
+            force_update = true;
+        }
         if config.configuration.capture_timeout.is_none() {
             config.configuration.capture_timeout = Some(2000);
             force_update = true;
         }
+        if config.configuration.keep_alive.is_none() {
+            config.configuration.keep_alive = Some(KeepAlivePolicy::Silence);
+            force_update = true;
+        }
+        if !config
+            .configuration
+            .keep_alive_noise_level
+            .is_some_and(|db| (-96.0..=-20.0).contains(&db))
+        {
+            config.configuration.keep_alive_noise_level = Some(DEFAULT_NOISE_LEVEL);
+            force_update = true;
+        }
+        if !matches!(config.configuration.client_queue_ms, Some(100..=60000)) {
+            config.configuration.client_queue_ms = Some(DEFAULT_CLIENT_QUEUE_MS);
+            force_update = true;
+        }
+        if config.configuration.overflow_policy.is_none() {
+            config.configuration.overflow_policy = Some(OverflowPolicy::DropOldest);
+            force_update = true;
+        }
+        // 0 disables the stall detection
+        if config.configuration.stall_timeout.is_none() {
+            config.configuration.stall_timeout = Some(DEFAULT_STALL_TIMEOUT);
+            force_update = true;
+        }
+        if config.configuration.renderer_keep_alive.is_none() {
+            config.configuration.renderer_keep_alive = Some(HashMap::new());
+            force_update = true;
+        }
+        // 0 disables the pre-roll
+        if !matches!(config.configuration.pre_roll_ms, Some(0..=MAX_PRE_ROLL_MS)) {
+            config.configuration.pre_roll_ms = Some(0);
+            force_update = true;
+        }
+        if config.configuration.low_latency.is_none() {
+            config.configuration.low_latency = Some(false);
+            force_update = true;
+        }
+        if config.configuration.drift_compensation.is_none() {
+            config.configuration.drift_compensation = Some(false);
+            force_update = true;
+        }
+        if !matches!(config.configuration.drift_target_ms, Some(20..=10000)) {
+            config.configuration.drift_target_ms = Some(DEFAULT_DRIFT_TARGET_MS);
+            force_update = true;
+        }
+        // an empty RecordingDir is the default directory, see recording_dir()
+        if config.configuration.recording_dir.is_none() {
+            config.configuration.recording_dir = Some(String::new());
+            force_update = true;
+        }
+        if !matches!(
+            config.configuration.recording_format,
+            Some(StreamingFormat::Flac | StreamingFormat::Wav)
+        ) {
+            config.configuration.recording_format = Some(StreamingFormat::Flac);
+            force_update = true;
+        }
+        // 0 disables the rotation and the silence split
+        if config.configuration.recording_rotate_mins.is_none() {
+            config.configuration.recording_rotate_mins = Some(DEFAULT_RECORDING_ROTATE_MINS);
+            force_update = true;
+        }
+        if config.configuration.recording_rotate_mb.is_none() {
+            config.configuration.recording_rotate_mb = Some(0);
+            force_update = true;
+        }
+        if config.configuration.recording_silence_split.is_none() {
+            config.configuration.recording_silence_split = Some(0);
+            force_update = true;
+        }
+        if config.configuration.renderer_pre_roll.is_none() {
+            config.configuration.renderer_pre_roll = Some(HashMap::new());
+            force_update = true;
+        }
+        if config.configuration.renderer_low_latency.is_none() {
+            config.configuration.renderer_low_latency = Some(HashMap::new());
+            force_update = true;
+        }
+        // the capture settings per audio source, see audiodevices.rs
+        match config.configuration.capture_config.as_mut() {
+            Some(capture_config) => {
+                let n = capture_config.len();
+                capture_config.retain(|_, settings| settings.validate().is_ok());
+                force_update |= capture_config.len() != n;
+            }
+            None => {
+                config.configuration.capture_config = Some(HashMap::new());
+                force_update = true;
+            }
+        }
+        // capture, pcm, file or tone, see audiosource.rs
+        if config
+            .configuration
+            .audio_source
+            .as_deref()
+            .is_none_or(|spec| spec.parse::<SourceSpec>().is_err())
+        {
+            config.configuration.audio_source = Some("capture".to_string());
+            force_update = true;
+        }
+        if config.configuration.capture_follow_default.is_none() {
+            config.configuration.capture_follow_default = Some(false);
+            force_update = true;
+        }
         if config.configuration.inject_silence.is_none() {
             config.configuration.inject_silence = Some(false);
             force_update = true;
@@ -163,23 +475,161 @@ impl Configuration {
             config.configuration.config_id = Some(String::new());
             force_update = true;
         }
+        if config.configuration.icy_title.is_none() {
+            config.configuration.icy_title = Some(DEFAULT_ICY_TITLE.to_string());
+            force_update = true;
+        }
         if force_update {
             config.configuration.update_config().unwrap();
         }
         config.configuration
     }
 
+    /// output_sample_rate - the sample rate of the stream for a renderer (ip address)
+    ///
+    /// a RendererSampleRates entry for the renderer overrides the OutputSampleRate,
+    /// 0 means no conversion: the sample rate of the capture device
+    pub fn output_sample_rate(&self, renderer_ip: &str, capture_rate: u32) -> u32 {
+        let rate = self
+            .renderer_sample_rates
+            .as_ref()
+            .and_then(|rates| rates.get(renderer_ip).copied())
+            .or(self.output_sample_rate)
+            .unwrap_or(0);
+        match rate {
+            8000..=384000 => rate,
+            _ => capture_rate,
+        }
+    }
+
+    /// capture_settings - the capture settings of an audio source, the device defaults if there are none
+    pub fn capture_settings(&self, sound_source: &str) -> CaptureSettings {
+        self.capture_config
+            .as_ref()
+            .and_then(|settings| settings.get(sound_source).cloned())
+            .unwrap_or_default()
+    }
+
+    /// set_capture_settings - set the capture settings of an audio source, the device defaults remove them
+    pub fn set_capture_settings(&mut self, sound_source: &str, settings: CaptureSettings) {
+        let capture_config = self.capture_config.get_or_insert_with(HashMap::new);
+        if settings.is_default() {
+            capture_config.remove(sound_source);
+        } else {
+            capture_config.insert(sound_source.to_string(), settings);
+        }
+    }
+
+    /// keep_alive_policy - what is streamed to a renderer (ip address) when nothing is captured
+    ///
+    /// a RendererKeepAlive entry for the renderer overrides the KeepAlive value
+    pub fn keep_alive_policy(&self, renderer_ip: &str) -> KeepAlivePolicy {
+        self.renderer_keep_alive
+            .as_ref()
+            .and_then(|policies| policies.get(renderer_ip).copied())
+            .or(self.keep_alive)
+            .unwrap_or(KeepAlivePolicy::Silence)
+    }
+
+    /// low_latency - true when a renderer (ip address) streams in low latency mode:
+    /// no pre-roll, and the encoded samples are sent as soon as they are available
+    ///
+    /// a RendererLowLatency entry for the renderer overrides the LowLatency value
+    pub fn low_latency(&self, renderer_ip: &str) -> bool {
+        self.renderer_low_latency
+            .as_ref()
+            .and_then(|renderers| renderers.get(renderer_ip).copied())
+            .or(self.low_latency)
+            .unwrap_or(false)
+    }
+
+    /// pre_roll_ms - the msecs of recently captured audio a renderer (ip address) gets
+    /// when it connects, 0 in low latency mode
+    ///
+    /// a RendererPreRoll entry for the renderer overrides the PreRollMs value
+    pub fn pre_roll_ms(&self, renderer_ip: &str) -> u32 {
+        if self.low_latency(renderer_ip) {
+            return 0;
+        }
+        self.renderer_pre_roll
+            .as_ref()
+            .and_then(|pre_rolls| pre_rolls.get(renderer_ip).copied())
+            .or(self.pre_roll_ms)
+            .unwrap_or(0)
+            .min(self.max_pre_roll_ms())
+    }
+
+    /// max_pre_roll_ms - the msecs of captured audio kept for the pre-roll: the longest
+    /// pre-roll of all renderers, at most half the ClientQueueMs so that a pre-roll
+    /// never overflows the client queue
+    pub fn max_pre_roll_ms(&self) -> u32 {
+        let longest = self
+            .renderer_pre_roll
+            .as_ref()
+            .and_then(|pre_rolls| pre_rolls.values().copied().max())
+            .unwrap_or(0)
+            .max(self.pre_roll_ms.unwrap_or(0));
+        longest
+            .min(MAX_PRE_ROLL_MS)
+            .min(self.client_queue_ms.unwrap_or(DEFAULT_CLIENT_QUEUE_MS) / 2)
+    }
+
+    /// drift_target_ms - the msecs of audio drift compensation keeps in a client queue,
+    /// at most half the ClientQueueMs
+    pub fn drift_target_ms(&self) -> u32 {
+        self.drift_target_ms
+            .unwrap_or(DEFAULT_DRIFT_TARGET_MS)
+            .min(self.client_queue_ms.unwrap_or(DEFAULT_CLIENT_QUEUE_MS) / 2)
+    }
+
+    /// recording_dir - the directory of the recordings: the RecordingDir, or when it is
+    /// empty swyh-rs in the audio (music) directory of the user, or in the config directory
+    pub fn recording_dir(&self) -> PathBuf {
+        match self.recording_dir.as_deref() {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => dirs::audio_dir()
+                .map(|dir| dir.join(PKGNAME))
+                .unwrap_or_else(|| self.config_dir.join("recordings")),
+        }
+    }
+
+    /// output_channels - the number of channels streamed to a renderer (ip address)
+    ///
+    /// the renderers in MultichannelRenderers get all the captured channels in the
+    /// formats that support it (LPCM, WAV and FLAC), the others get the stereo mapping
+    pub fn output_channels(
+        &self,
+        renderer_ip: &str,
+        capture_channels: u16,
+        format: StreamingFormat,
+    ) -> u16 {
+        let multichannel = self
+            .multichannel_renderers
+            .as_ref()
+            .is_some_and(|r| r.iter().any(|ip| ip == renderer_ip));
+        let supported = matches!(
+            format,
+            StreamingFormat::Lpcm
+                | StreamingFormat::Wav
+                | StreamingFormat::Flac
+                | StreamingFormat::OggFlac
+        );
+        if multichannel && supported && (3..=8).contains(&capture_channels) {
+            capture_channels
+        } else {
+            2
+        }
+    }
+
     pub fn update_config(&self) -> std::io::Result<()> {
         let configfile = Self::get_config_path(CONFIGFILE);
-        let f = File::create(configfile).unwrap();
         let conf = Config {
             configuration: self.clone(),
         };
-        let s = toml::to_string(&conf).unwrap();
-        let mut w = BufWriter::new(f);
-        w.write_all(s.as_bytes()).unwrap();
-        w.flush().unwrap();
-        Ok(())
+        let s = toml::to_string(&conf).map_err(std::io::Error::other)?;
+        let mut w = BufWriter::new(File::create(configfile)?);
+        w.write_all(s.as_bytes())?;
+        w.flush()
     }
 
     fn get_config_dir() -> PathBuf {
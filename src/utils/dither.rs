@@ -0,0 +1,254 @@
+///
+/// dither.rs
+///
+/// Ditherer: reduces the f32 samples to 16 bit with TPDF dither, optionally noise shaped
+///
+/// plain truncation makes the quantization error correlated with the signal, which
+/// is heard as distortion on quiet material. TPDF dither of 1 LSB peak decorrelates
+/// the error so that only a constant noise floor remains, and noise shaping moves
+/// most of that noise above the frequencies the ear is most sensitive to.
+///
+use crate::enums::streaming::Dither;
+use dasp_sample::Sample;
+
+/// the 16 bit full scale
+const SCALE: f32 = 32768.0;
+
+/// the error feedback filter of the noise shaping
+/// (3 tap E-weighted, Wannamaker), about -12 dB at DC and +11 dB at the Nyquist frequency
+const SHAPING: [f32; 3] = [1.623, -0.982, 0.109];
+
+/// the largest error that is fed back, so that clipping can't make the filter unstable
+const MAX_ERROR: f32 = 2.0;
+
+/// Ditherer - quantizes interleaved f32 samples to i16, with the filter state per channel
+#[derive(Debug, Clone)]
+pub struct Ditherer {
+    dither: Dither,
+    channels: usize,
+    channel: usize,
+    noise: TpdfNoise,
+    // the last quantization errors of each channel, most recent first
+    errors: Vec<[f32; 3]>,
+}
+
+impl Ditherer {
+    pub fn new(dither: Dither, channels: u16) -> Ditherer {
+        let channels = channels.max(1) as usize;
+        Ditherer {
+            dither,
+            channels,
+            channel: 0,
+            noise: TpdfNoise::new(),
+            errors: vec![[0.0; 3]; channels],
+        }
+    }
+
+    /// to_i16 - the next interleaved sample as a 16 bit sample
+    pub fn to_i16(&mut self, sample: f32) -> i16 {
+        let channel = self.channel;
+        self.channel = (self.channel + 1) % self.channels;
+        if self.dither == Dither::None {
+            return i16::from_sample(sample);
+        }
+        let mut value = sample.clamp(-1.0, 1.0) * SCALE;
+        if self.dither == Dither::Shaped {
+            let errors = &self.errors[channel];
+            value -= SHAPING
+                .iter()
+                .zip(errors.iter())
+                .map(|(h, e)| h * e)
+                .sum::<f32>();
+        }
+        let quantized = (value + self.noise.next_sample())
+            .round()
+            .clamp(i16::MIN as f32, i16::MAX as f32);
+        if self.dither == Dither::Shaped {
+            let errors = &mut self.errors[channel];
+            errors.rotate_right(1);
+            errors[0] = (quantized - value).clamp(-MAX_ERROR, MAX_ERROR);
+        }
+        quantized as i16
+    }
+}
+
+/// TpdfNoise - triangular (TPDF) noise of 1 LSB peak, the sum of two uniform random values
+#[derive(Debug, Clone)]
+pub struct TpdfNoise {
+    // xorshift32 state
+    seed: u32,
+}
+
+impl TpdfNoise {
+    pub fn new() -> TpdfNoise {
+        TpdfNoise {
+            seed: rand::random::<u32>() | 1,
+        }
+    }
+
+    /// next_sample - a random value in -1.0..1.0
+    pub fn next_sample(&mut self) -> f32 {
+        self.uniform() + self.uniform()
+    }
+
+    /// uniform - a random value in -0.5..0.5
+    fn uniform(&mut self) -> f32 {
+        let mut x = self.seed;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.seed = x;
+        (x as f32 / u32::MAX as f32) - 0.5
+    }
+}
+
+impl Default for TpdfNoise {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const N: usize = 16384;
+
+    // the amplitude of the sine with `cycles` periods in the samples (one DFT bin)
+    fn amplitude(samples: &[f32], cycles: usize) -> f32 {
+        let (mut re, mut im) = (0f64, 0f64);
+        for (n, s) in samples.iter().enumerate() {
+            let phi = 2.0 * std::f64::consts::PI * (cycles * n) as f64 / samples.len() as f64;
+            re += *s as f64 * phi.cos();
+            im += *s as f64 * phi.sin();
+        }
+        (2.0 * (re * re + im * im).sqrt() / samples.len() as f64) as f32
+    }
+
+    // the power of the samples after a 32 sample moving average, a low pass
+    // of about fs/64, or of the samples modulated to the Nyquist frequency, a high pass
+    fn band_power(samples: &[f32], high: bool) -> f32 {
+        let modulated: Vec<f32> = samples
+            .iter()
+            .enumerate()
+            .map(|(n, s)| if high && n % 2 == 1 { -s } else { *s })
+            .collect();
+        let averages: Vec<f32> = modulated
+            .windows(32)
+            .map(|w| w.iter().sum::<f32>() / 32.0)
+            .collect();
+        averages.iter().map(|a| a * a).sum::<f32>() / averages.len() as f32
+    }
+
+    // a sine with `cycles` periods of `lsb` 16 bit steps amplitude
+    fn sine(cycles: usize, lsb: f32) -> Vec<f32> {
+        (0..N)
+            .map(|n| {
+                let phi = 2.0 * std::f32::consts::PI * (cycles * n) as f32 / N as f32;
+                phi.sin() * lsb / SCALE
+            })
+            .collect()
+    }
+
+    // quantize mono samples, and return them in LSB units
+    fn quantize(dither: Dither, input: &[f32]) -> Vec<f32> {
+        let mut ditherer = Ditherer::new(dither, 1);
+        input.iter().map(|s| ditherer.to_i16(*s) as f32).collect()
+    }
+
+    #[test]
+    fn no_dither() {
+        let input = sine(7, 1000.5);
+        let mut ditherer = Ditherer::new(Dither::None, 2);
+        for s in input {
+            assert_eq!(ditherer.to_i16(s), i16::from_sample(s));
+        }
+    }
+
+    #[test]
+    fn tpdf_noise() {
+        // the error is the TPDF noise plus the rounding error, with a zero mean
+        let input = sine(7, 1000.0);
+        let output = quantize(Dither::Tpdf, &input);
+        let errors: Vec<f32> = output
+            .iter()
+            .zip(input.iter())
+            .map(|(o, i)| o - i * SCALE)
+            .collect();
+        assert!(errors.iter().all(|e| e.abs() <= 1.5));
+        let mean = errors.iter().sum::<f32>() / N as f32;
+        assert!(mean.abs() < 0.05, "mean {mean}");
+        // the variance of TPDF dither (1/6) and rounding (1/12)
+        let variance = errors.iter().map(|e| e * e).sum::<f32>() / N as f32;
+        assert!((variance - 0.25).abs() < 0.03, "variance {variance}");
+    }
+
+    #[test]
+    fn tpdf_linearizes() {
+        // a sine below 1/2 LSB is lost without dither, and preserved with TPDF dither
+        let input = sine(101, 0.4);
+        assert_eq!(amplitude(&quantize(Dither::None, &input), 101), 0.0);
+        let a = amplitude(&quantize(Dither::Tpdf, &input), 101);
+        assert!((a - 0.4).abs() < 0.05, "amplitude {a}");
+        // the harmonic distortion of a low level sine disappears in the noise floor
+        let input = sine(101, 1.5);
+        let plain = quantize(Dither::None, &input);
+        let dithered = quantize(Dither::Tpdf, &input);
+        for harmonic in [3, 5] {
+            let distortion = amplitude(&plain, 101 * harmonic);
+            let residual = amplitude(&dithered, 101 * harmonic);
+            assert!(distortion > 0.1, "plain harmonic {harmonic}: {distortion}");
+            assert!(residual < 0.03, "dithered harmonic {harmonic}: {residual}");
+        }
+    }
+
+    #[test]
+    fn noise_shaping() {
+        // the shaped noise is lower at the low frequencies, and higher near the Nyquist frequency
+        let input = sine(101, 100.0);
+        let error = |dither| -> Vec<f32> {
+            quantize(dither, &input)
+                .iter()
+                .zip(input.iter())
+                .map(|(o, i)| o - i * SCALE)
+                .collect()
+        };
+        let tpdf = error(Dither::Tpdf);
+        let shaped = error(Dither::Shaped);
+        let low = |e: &[f32]| band_power(e, false);
+        let high = |e: &[f32]| band_power(e, true);
+        assert!(
+            low(&shaped) < low(&tpdf) / 4.0,
+            "low band {} {}",
+            low(&shaped),
+            low(&tpdf)
+        );
+        assert!(
+            high(&shaped) > high(&tpdf) * 4.0,
+            "high band {} {}",
+            high(&shaped),
+            high(&tpdf)
+        );
+        // the signal itself is unchanged
+        let a = amplitude(&quantize(Dither::Shaped, &input), 101);
+        assert!((a - 100.0).abs() < 0.2, "amplitude {a}");
+    }
+
+    #[test]
+    fn interleaved_channels() {
+        // the filter state is per channel: a silent channel stays quiet
+        let mut ditherer = Ditherer::new(Dither::Shaped, 2);
+        let mut right = Vec::with_capacity(N);
+        for s in sine(13, 30000.0) {
+            ditherer.to_i16(s);
+            right.push(ditherer.to_i16(0.0) as f32);
+        }
+        assert!(right.iter().all(|s| s.abs() <= 8.0));
+        // full scale doesn't wrap around
+        let mut ditherer = Ditherer::new(Dither::Shaped, 1);
+        for _ in 0..100 {
+            assert!(ditherer.to_i16(1.0) > 32000);
+            assert!(ditherer.to_i16(-1.0) < -32000);
+        }
+    }
+}
@@ -0,0 +1,198 @@
+///
+/// drift.rs
+///
+/// DriftCompensator: keeps the queue of a client near DriftTargetMs despite clock drift
+///
+/// the capture device clock and the DAC clock of a renderer are never exactly the same,
+/// so over hours the queue of a client slowly grows (more latency) or runs empty (keep-alive
+/// silence in the middle of the music). the renderer drains the stream at the rate of its
+/// own clock, so the fill of the client queue shows the difference.
+///
+/// once a second the resampler thread of the client passes the fill of the queue, and a
+/// slow PI controller adjusts the resampling ratio by at most MAX_CORRECTION. the integral
+/// part of the correction is the estimated drift of the renderer clock.
+///
+use std::{
+    sync::{
+        atomic::{AtomicU32, Ordering::Relaxed},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// the fill is averaged over this interval before the ratio is adjusted
+const UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+/// no correction while the renderer fills its own buffer at the start of the stream
+const WARM_UP: Duration = Duration::from_secs(10);
+/// the time constant (secs) of the low pass filter on the fill
+const FILL_FILTER_SECS: f64 = 10.0;
+/// the proportional gain (per sec) and the integral gain (per sec²), critically damped
+const KP: f64 = 0.01;
+const KI: f64 = KP * KP / 4.0;
+/// the maximum correction of the resampling ratio (2000 ppm, about 3.5 cents)
+const MAX_CORRECTION: f64 = 0.002;
+/// the maximum drift the integral part can compensate (1000 ppm)
+const MAX_DRIFT: f64 = 0.001;
+
+/// DriftStats - the current state of the compensation, for the API
+#[derive(Debug, Default)]
+pub struct DriftStats {
+    // f32 bits
+    drift_ppm: AtomicU32,
+    fill_ms: AtomicU32,
+}
+
+impl DriftStats {
+    /// the estimated drift of the renderer clock in ppm, negative when it is slower
+    /// than the capture clock
+    pub fn drift_ppm(&self) -> f32 {
+        f32::from_bits(self.drift_ppm.load(Relaxed))
+    }
+
+    /// the (filtered) msecs of audio waiting in the client queue
+    pub fn fill_ms(&self) -> f32 {
+        f32::from_bits(self.fill_ms.load(Relaxed))
+    }
+}
+
+/// DriftCompensator - the controller, owned by the resampler thread of a client
+#[derive(Debug)]
+pub struct DriftCompensator {
+    // samples per second of the queued audio (capture rate * channels)
+    samples_per_sec: f64,
+    // the target fill in secs
+    target: f64,
+    started: Instant,
+    last_update: Instant,
+    // the sum and number of the fills since the last update
+    fill_sum: f64,
+    fills: u32,
+    // the filtered fill in secs, None until the warm up is over
+    fill: Option<f64>,
+    integral: f64,
+    stats: Arc<DriftStats>,
+}
+
+impl DriftCompensator {
+    pub fn new(capture_rate: u32, channels: u16, target_ms: u32) -> DriftCompensator {
+        let now = Instant::now();
+        DriftCompensator {
+            samples_per_sec: capture_rate as f64 * channels as f64,
+            target: target_ms as f64 / 1000.0,
+            started: now,
+            last_update: now,
+            fill_sum: 0.0,
+            fills: 0,
+            fill: None,
+            integral: 0.0,
+            stats: Arc::new(DriftStats::default()),
+        }
+    }
+
+    /// the statistics, shared with the ChannelStream of the client
+    pub fn stats(&self) -> Arc<DriftStats> {
+        self.stats.clone()
+    }
+
+    /// update - pass the number of queued samples, returns the new relative resampling
+    /// ratio once every UPDATE_INTERVAL after the warm up
+    pub fn update(&mut self, queued_samples: usize) -> Option<f64> {
+        self.fill_sum += queued_samples as f64 / self.samples_per_sec;
+        self.fills += 1;
+        let elapsed = self.last_update.elapsed();
+        if elapsed < UPDATE_INTERVAL {
+            return None;
+        }
+        let fill = self.fill_sum / self.fills as f64;
+        self.fill_sum = 0.0;
+        self.fills = 0;
+        self.last_update = Instant::now();
+        if self.started.elapsed() < WARM_UP {
+            self.publish(fill);
+            return None;
+        }
+        Some(self.correct(fill, elapsed.as_secs_f64()))
+    }
+
+    /// correct - one step of the PI controller with the average fill (secs) over dt secs
+    ///
+    /// a fill above the target gives a ratio below 1: fewer samples for the renderer,
+    /// so that it drains the queue a little faster than it is filled
+    fn correct(&mut self, fill: f64, dt: f64) -> f64 {
+        let alpha = (dt / FILL_FILTER_SECS).min(1.0);
+        let filtered = match self.fill {
+            Some(f) => f + alpha * (fill - f),
+            None => fill,
+        };
+        self.fill = Some(filtered);
+        let error = self.target - filtered;
+        self.integral = (self.integral + KI * error * dt).clamp(-MAX_DRIFT, MAX_DRIFT);
+        self.publish(filtered);
+        1.0 + (KP * error + self.integral).clamp(-MAX_CORRECTION, MAX_CORRECTION)
+    }
+
+    fn publish(&self, fill: f64) {
+        self.stats
+            .drift_ppm
+            .store(((self.integral * 1e6) as f32).to_bits(), Relaxed);
+        self.stats
+            .fill_ms
+            .store(((fill * 1000.0) as f32).to_bits(), Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// simulate a renderer with a clock that is drift ppm off, one second at a time,
+    /// and return the final fill (secs)
+    fn simulate(compensator: &mut DriftCompensator, drift: f64, fill: f64, secs: u32) -> f64 {
+        let mut fill = fill;
+        let mut ratio = 1.0;
+        for _ in 0..secs {
+            // the renderer consumes (1 + drift) secs of resampled audio per sec, which is
+            // (1 + drift) / ratio secs of captured audio
+            fill = (fill + 1.0 - (1.0 + drift) / ratio).max(0.0);
+            ratio = compensator.correct(fill, 1.0);
+            assert!((ratio - 1.0).abs() <= MAX_CORRECTION + 1e-12);
+        }
+        fill
+    }
+
+    #[test]
+    fn slow_renderer() {
+        // a renderer clock 100 ppm slow: the queue would grow 0.36 secs per hour
+        let mut compensator = DriftCompensator::new(44100, 2, 300);
+        let fill = simulate(&mut compensator, -100e-6, 0.3, 3600);
+        assert!((fill - 0.3).abs() < 0.01, "fill {fill}");
+        let drift = compensator.stats().drift_ppm();
+        assert!((drift + 100.0).abs() < 5.0, "drift {drift}");
+        assert!((compensator.stats().fill_ms() - 300.0).abs() < 10.0);
+    }
+
+    #[test]
+    fn fast_renderer_from_empty_queue() {
+        // a renderer clock 250 ppm fast, starting with an empty queue
+        let mut compensator = DriftCompensator::new(48000, 2, 500);
+        let fill = simulate(&mut compensator, 250e-6, 0.0, 3600);
+        assert!((fill - 0.5).abs() < 0.01, "fill {fill}");
+        let drift = compensator.stats().drift_ppm();
+        assert!((drift - 250.0).abs() < 10.0, "drift {drift}");
+    }
+
+    #[test]
+    fn warm_up() {
+        // no correction during the warm up, but the fill is published
+        let mut compensator = DriftCompensator::new(1000, 2, 300);
+        compensator.last_update -= UPDATE_INTERVAL;
+        assert_eq!(compensator.update(1000), None);
+        assert_eq!(compensator.stats().fill_ms(), 500.0);
+        // after the warm up a ratio is returned once per UPDATE_INTERVAL
+        compensator.started -= WARM_UP;
+        assert_eq!(compensator.update(1000), None);
+        compensator.last_update -= UPDATE_INTERVAL;
+        let ratio = compensator.update(1000).unwrap();
+        assert!(ratio < 1.0, "ratio {ratio}");
+    }
+}
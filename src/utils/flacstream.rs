@@ -1,20 +1,74 @@
-use crossbeam_channel::{unbounded, Receiver, Sender};
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError, Sender};
 use flac_bound::{FlacEncoder, WriteWrapper};
-#[cfg(feature = "NOISE")]
-use rand::{distributions::Uniform, rngs::StdRng, Rng, SeedableRng};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
 use std::{
     io::Write,
     sync::{
         atomic::{AtomicBool, Ordering::Relaxed},
         Arc,
     },
-    time::Duration,
 };
 
-#[cfg(feature = "NOISE")]
-use crate::ui_log;
+use crate::{
+    enums::streaming::{Dither, StreamingFormat},
+    utils::{
+        configuration::Configuration,
+        dither::Ditherer,
+        keepalive::KeepAlive,
+        oggmux::OggMuxer,
+        streamencoder::{StreamEncoder, ENCODED_QUEUE_SIZE},
+        ui_logger::ui_log,
+    },
+};
+
+/// didl protocolinfo
+pub static FLAC_PROT_INFO: &str = "http-get:*:audio/flac:DLNA.ORG_PN=FLAC;DLNA.ORG_OP=01;DLNA.ORG_CI=0;DLNA.ORG_FLAGS=01700000000000000000000000000000";
+pub static OGG_PROT_INFO: &str = "http-get:*:audio/ogg:DLNA.ORG_OP=01;DLNA.ORG_CI=0;DLNA.ORG_FLAGS=01700000000000000000000000000000";
+
+/// 32 bit samples need libFLAC 1.4, so try to initialize an encoder once
+static FLAC_32BIT: Lazy<bool> = Lazy::new(|| {
+    let mut sink = std::io::sink();
+    let mut outw = WriteWrapper(&mut sink);
+    FlacEncoder::new().is_some_and(|config| {
+        config
+            .channels(2)
+            .bits_per_sample(32)
+            .sample_rate(44100)
+            .init_write(&mut outw)
+            .is_ok()
+    })
+});
+
+/// flac_bits_per_sample - the sample size libFLAC really encodes, 24 bit if it can't do 32 bit
+pub fn flac_bits_per_sample(bits_per_sample: u32) -> u32 {
+    if bits_per_sample == 32 && !*FLAC_32BIT {
+        24
+    } else {
+        bits_per_sample
+    }
+}
+
+/// the FLAC encoder settings
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FlacSettings {
+    /// 0 (fastest) .. 8 (smallest)
+    pub compression_level: u32,
+    /// 0 is the libFLAC default for the compression level
+    pub block_size: u32,
+    /// verify the encoded data by decoding it
+    pub verify: bool,
+}
 
-const NOISE_PERIOD: u64 = 250;
+impl FlacSettings {
+    pub fn from_config(conf: &Configuration) -> FlacSettings {
+        FlacSettings {
+            compression_level: conf.flac_compression_level.unwrap_or(0),
+            block_size: conf.flac_block_size.unwrap_or(0),
+            verify: conf.flac_verify.unwrap_or(false),
+        }
+    }
+}
 
 // the flacwriter receives the data from the encoder
 // and writes them to the flac output channel
@@ -45,128 +99,271 @@ impl Write for FlacWriter {
     }
 }
 
-// a FlacChannel is set up by the channelstream
+// the OggFlacWriter receives the native FLAC stream from the encoder
+// and writes it as Ogg FLAC pages (the Ogg FLAC mapping version 1.0)
+// to the flac output channel
+//
+// libFLAC calls the write callback once for each frame
+pub struct OggFlacWriter {
+    writer: FlacWriter,
+    muxer: OggMuxer,
+    // the stream marker and the metadata blocks, until the last metadata block is seen
+    header: Vec<u8>,
+    headers_done: bool,
+    samples: u64,
+    pages: Vec<u8>,
+}
+
+impl OggFlacWriter {
+    pub fn new(writer: FlacWriter) -> OggFlacWriter {
+        OggFlacWriter {
+            writer,
+            muxer: OggMuxer::new(rand::random()),
+            header: Vec::new(),
+            headers_done: false,
+            samples: 0,
+            pages: Vec::new(),
+        }
+    }
+
+    // the first packet with the STREAMINFO block on its own page,
+    // followed by the other metadata blocks
+    fn write_headers(&mut self, blocks: &[&[u8]]) {
+        let mut first = Vec::with_capacity(13 + blocks[0].len());
+        first.push(0x7f);
+        first.extend_from_slice(b"FLAC");
+        first.extend_from_slice(&[1, 0]); // mapping version 1.0
+        first.extend_from_slice(&((blocks.len() - 1) as u16).to_be_bytes());
+        first.extend_from_slice(b"fLaC");
+        first.extend_from_slice(blocks[0]);
+        self.muxer.add_packet(&first, 0, &mut self.pages);
+        self.muxer.flush(&mut self.pages);
+        for block in &blocks[1..] {
+            self.muxer.add_packet(block, 0, &mut self.pages);
+        }
+        self.muxer.flush(&mut self.pages);
+    }
+}
+
+impl Write for OggFlacWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if !self.headers_done {
+            self.header.extend_from_slice(buf);
+            let header = std::mem::take(&mut self.header);
+            match metadata_blocks(&header) {
+                Some(blocks) => {
+                    self.write_headers(&blocks);
+                    self.headers_done = true;
+                }
+                None => self.header = header,
+            }
+        } else {
+            // a frame: the granule position is the number of samples after this frame
+            match flac_frame_blocksize(buf) {
+                Some(blocksize) => self.samples += blocksize as u64,
+                None => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "OggFlacWriter invalid FLAC frame header",
+                    ))
+                }
+            }
+            self.muxer.add_packet(buf, self.samples, &mut self.pages);
+            self.muxer.flush(&mut self.pages);
+        }
+        if !self.pages.is_empty() {
+            self.writer.write_all(&self.pages)?;
+            self.pages.clear();
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// metadata_blocks - split the native FLAC header (the "fLaC" marker followed by the
+/// metadata blocks) in metadata blocks, if the last metadata block is complete
+fn metadata_blocks(header: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut blocks = Vec::new();
+    let mut pos = 4; // skip the "fLaC" stream marker
+    while header.len() >= pos + 4 {
+        let last = header[pos] & 0x80 != 0;
+        let len =
+            u32::from_be_bytes([0, header[pos + 1], header[pos + 2], header[pos + 3]]) as usize;
+        if header.len() < pos + 4 + len {
+            return None;
+        }
+        blocks.push(&header[pos..pos + 4 + len]);
+        pos += 4 + len;
+        if last {
+            return Some(blocks);
+        }
+    }
+    None
+}
+
+/// flac_frame_blocksize - the number of samples (per channel) in a FLAC frame
+fn flac_frame_blocksize(frame: &[u8]) -> Option<u32> {
+    if frame.len() < 5 || frame[0] != 0xff || frame[1] & 0xfe != 0xf8 {
+        return None;
+    }
+    let code = frame[2] >> 4;
+    // the frame or sample number is UTF-8 coded, the blocksize follows it
+    let end_of_number = 4 + match frame[4].leading_ones() {
+        0 => 1,
+        n @ 2..=7 => n as usize,
+        _ => return None,
+    };
+    match code {
+        1 => Some(192),
+        2..=5 => Some(576 << (code - 2)),
+        6 => frame.get(end_of_number).map(|b| *b as u32 + 1),
+        7 => frame
+            .get(end_of_number..end_of_number + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]) as u32 + 1),
+        8..=15 => Some(256 << (code - 8)),
+        _ => None,
+    }
+}
+
+// a FlacChannel is the StreamEncoder for (Ogg) FLAC
 // the ChannelStream writes the captured f32 samples
 // to the samples_in channel for encoding
+//
+// the encoder thread owns the only FlacWriter, so that flac_in is disconnected
+// when the thread ends because of an error
 #[derive(Clone)]
 pub struct FlacChannel {
-    samples_in: Receiver<Vec<f32>>,
     pub flac_in: Receiver<Vec<u8>>,
     active: Arc<AtomicBool>,
-    writer: FlacWriter,
+    writer: Arc<Mutex<Option<FlacWriter>>>,
     sample_rate: u32,
     bits_per_sample: u32,
     channels: u32,
+    ogg: bool,
+    dither: Dither,
+    settings: FlacSettings,
 }
 
 impl FlacChannel {
     pub fn new(
-        samples_in: Receiver<Vec<f32>>,
         sample_rate: u32,
         bits_per_sample: u32,
         channels: u32,
+        ogg: bool,
+        dither: Dither,
+        settings: FlacSettings,
     ) -> FlacChannel {
-        let (flac_out, flac_in): (Sender<Vec<u8>>, Receiver<Vec<u8>>) = unbounded();
+        let (flac_out, flac_in): (Sender<Vec<u8>>, Receiver<Vec<u8>>) = bounded(ENCODED_QUEUE_SIZE);
+        let flac_bits = flac_bits_per_sample(bits_per_sample);
+        if flac_bits != bits_per_sample {
+            ui_log("*W*W*> This libFLAC can't encode 32 bit samples, using 24 bit".to_string());
+        }
+        let bits_per_sample = flac_bits;
         FlacChannel {
-            samples_in,
             flac_in,
             active: Arc::new(AtomicBool::new(false)),
-            writer: FlacWriter::new(flac_out),
+            writer: Arc::new(Mutex::new(Some(FlacWriter::new(flac_out)))),
             sample_rate,
             bits_per_sample,
             channels,
+            ogg,
+            dither,
+            settings,
         }
     }
 
-    pub fn run(&self) {
-        // copy instance data for thread
-        let samples_in = self.samples_in.clone();
-        let mut writer = self.writer.clone();
+    pub fn run(&self, samples_in: Receiver<Arc<[f32]>>, mut keep_alive: KeepAlive) {
+        // move the writer to the thread
+        let Some(mut writer) = self.writer.lock().take() else {
+            return;
+        };
         let ch = self.channels;
         let bps = self.bits_per_sample;
         let sr = self.sample_rate;
+        let ogg = self.ogg;
+        let dither = self.dither;
+        let settings = self.settings;
         let l_active = self.active.clone();
         // fire up thread
         self.active.store(true, Relaxed);
-        let _thr = std::thread::Builder::new()
+        let spawned = std::thread::Builder::new()
             .name("flac_encoder".into())
             .stack_size(4 * 1024 * 1024)
             .spawn(move || {
                 // we're running
                 // setup the encoder
-                let mut outw = WriteWrapper(&mut writer);
-                let mut enc = FlacEncoder::new()
-                    .unwrap()
+                // Ogg FLAC is muxed by the OggFlacWriter
+                let mut ogg_writer = OggFlacWriter::new(writer.clone());
+                let out: &mut dyn Write = if ogg { &mut ogg_writer } else { &mut writer };
+                let mut outw = WriteWrapper(out);
+                let Some(config) = FlacEncoder::new() else {
+                    ui_log("*E*E*> Unable to create the FLAC encoder".to_string());
+                    return;
+                };
+                let mut config = config
                     .channels(ch)
                     .bits_per_sample(bps)
                     .sample_rate(sr)
-                    .compression_level(0)
-                    .init_write(&mut outw)
-                    .unwrap();
-                // read captured samples and encode
-                let shift = if bps == 24 { 8u8 } else { 16u8 };
-                #[cfg(feature = "NOISE")]
-                {
-                    // create the random generator for the white noise
-                    let mut rng = StdRng::seed_from_u64(79);
-                    // preallocate the noise buffer
-                    const DIVISOR: u64 = 1000 / NOISE_PERIOD;
-                    let size = ((sr * 2) / DIVISOR as u32) as usize;
-                    let mut noise: Vec<f32> = Vec::with_capacity(size);
-                    noise.resize(size, 0.0);
-                    let mut sending_silence = false;
+                    .compression_level(settings.compression_level)
+                    .verify(settings.verify);
+                if settings.block_size > 0 {
+                    config = config.blocksize(settings.block_size);
                 }
+                let mut enc = match config.init_write(&mut outw) {
+                    Ok(enc) => enc,
+                    Err(e) => {
+                        ui_log(format!(
+                            "*E*E*> Unable to initialize the FLAC encoder: {e:?}"
+                        ));
+                        return;
+                    }
+                };
+                // read captured samples and encode
+                let shift = match bps {
+                    32 => 0u8,
+                    24 => 8u8,
+                    _ => 16u8,
+                };
+                // only 16 bit loses precision, so only 16 bit is dithered
+                let mut ditherer =
+                    (bps == 16 && dither != Dither::None).then(|| Ditherer::new(dither, ch as u16));
                 // read and FLAC encode samples
-                let time_out = Duration::from_millis(NOISE_PERIOD);
                 while l_active.load(Relaxed) {
-                    #[cfg(feature = "NOISE")]
+                    let f32_samples = match keep_alive.recv(&samples_in) {
+                        Ok(f32_samples) => f32_samples,
+                        Err(RecvTimeoutError::Timeout) => continue,
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    };
+                    let samples = f32_samples
+                        .iter()
+                        .map(|s| match ditherer.as_mut() {
+                            Some(d) => d.to_i16(*s) as i32,
+                            None => to_i32_sample(*s) >> shift,
+                        })
+                        .collect::<Vec<i32>>();
+                    if enc
+                        .process_interleaved(
+                            samples.as_slice(),
+                            (samples.len() / ch as usize) as u32,
+                        )
+                        .is_err()
                     {
-                        let time_out = if sending_silence {
-                            Duration::from_millis(NOISE_PERIOD)
-                        } else {
-                            Duration::from_millis(NOISE_PERIOD * 4)
-                        };
-                    }
-                    if let Ok(f32_samples) = samples_in.recv_timeout(time_out) {
-                        #[cfg(feature = "NOISE")]
-                        {
-                            sending_silence = false;
-                        }
-                        let samples = f32_samples
-                            .iter()
-                            .map(|s| to_i32_sample(*s) >> shift)
-                            .collect::<Vec<i32>>();
-                        enc.process_interleaved(samples.as_slice(), (samples.len() / 2) as u32)
-                            .unwrap();
-                    } else {
-                        #[cfg(feature = "NOISE")]
-                        {
-                            // if no samples for a certain time: send a faint white noise
-                            sending_silence = true;
-                            if l_active.load(Relaxed) {
-                                fill_noise_buffer(&mut rng, &mut noise);
-                                let samples = noise
-                                    .iter()
-                                    .map(|s| to_i32_sample(*s) >> shift)
-                                    .collect::<Vec<i32>>();
-                                let res = enc.process_interleaved(
-                                    samples.as_slice(),
-                                    (samples.len() / 2) as u32,
-                                );
-                                if let Err(e) = res {
-                                    ui_log(format!(
-                                        "Flac encoding error caused by silence {:?}",
-                                        e
-                                    ));
-                                    break;
-                                }
-                            }
-                        }
+                        ui_log(format!("*E*E*> FLAC encoding error: {:?}", enc.state()));
+                        break;
                     }
                 }
                 let _ = enc.finish();
-            })
-            .unwrap();
+            });
+        // the closure and its writer are dropped, so the stream reader gets an error
+        if let Err(e) = spawned {
+            ui_log(format!(
+                "*E*E*> Unable to start the FLAC encoder thread: {e}"
+            ));
+            self.active.store(false, Relaxed);
+        }
     }
 
     pub fn stop(&self) {
@@ -174,7 +371,56 @@ impl FlacChannel {
     }
 }
 
-fn to_i32_sample(mut f32_sample: f32) -> i32 {
+impl StreamEncoder for FlacChannel {
+    fn format(&self) -> StreamingFormat {
+        if self.ogg {
+            StreamingFormat::OggFlac
+        } else {
+            StreamingFormat::Flac
+        }
+    }
+
+    fn mime_type(&self) -> String {
+        if self.ogg { "audio/ogg" } else { "audio/flac" }.to_string()
+    }
+
+    fn bits_per_sample(&self) -> u16 {
+        self.bits_per_sample as u16
+    }
+
+    fn protocol_info(&self) -> String {
+        if self.ogg {
+            OGG_PROT_INFO
+        } else {
+            FLAC_PROT_INFO
+        }
+        .to_string()
+    }
+
+    fn description(&self) -> String {
+        if self.ogg {
+            "audio/ogg (FLAC)"
+        } else {
+            "audio/FLAC"
+        }
+        .to_string()
+    }
+
+    fn start(
+        &self,
+        samples_in: &Receiver<Arc<[f32]>>,
+        keep_alive: KeepAlive,
+    ) -> Option<Receiver<Vec<u8>>> {
+        self.run(samples_in.clone(), keep_alive);
+        Some(self.flac_in.clone())
+    }
+
+    fn stop(&self) {
+        FlacChannel::stop(self);
+    }
+}
+
+pub fn to_i32_sample(mut f32_sample: f32) -> i32 {
     f32_sample = f32_sample.clamp(-1.0, 1.0);
     if f32_sample >= 0.0 {
         ((f32_sample as f64 * i32::MAX as f64) + 0.5) as i32
@@ -183,13 +429,92 @@ fn to_i32_sample(mut f32_sample: f32) -> i32 {
     }
 }
 
-#[cfg(feature = "NOISE")]
-///
-/// fille the pre-allocated noise buffer with a very faint white noise (-60db)
-///
-fn fill_noise_buffer(rng: &mut StdRng, noise: &mut [f32]) {
-    let amplitude: f32 = 0.001;
-    for sample in noise.iter_mut() {
-        *sample = ((rng.sample(Uniform::new(0.0, 1.0)) * 2.0) - 1.0) * amplitude;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::enums::streaming::KeepAlivePolicy;
+    use crossbeam_channel::unbounded;
+    use std::time::Duration;
+
+    #[test]
+    fn frame_blocksize() {
+        // fixed blocksize codes
+        assert_eq!(
+            flac_frame_blocksize(&[0xff, 0xf8, 0x19, 0x08, 0x00]),
+            Some(192)
+        );
+        assert_eq!(
+            flac_frame_blocksize(&[0xff, 0xf8, 0x39, 0x08, 0x00]),
+            Some(1152)
+        );
+        assert_eq!(
+            flac_frame_blocksize(&[0xff, 0xf8, 0xc9, 0x08, 0x00]),
+            Some(4096)
+        );
+        // 8 and 16 bit blocksize after a 1 and a 2 byte frame number
+        assert_eq!(
+            flac_frame_blocksize(&[0xff, 0xf8, 0x69, 0x08, 0x05, 0xff]),
+            Some(256)
+        );
+        assert_eq!(
+            flac_frame_blocksize(&[0xff, 0xf8, 0x79, 0x08, 0xc2, 0x80, 0x11, 0xff]),
+            Some(0x1200)
+        );
+        // not a frame header
+        assert_eq!(flac_frame_blocksize(b"fLaC\0"), None);
+        assert_eq!(flac_frame_blocksize(&[0xff, 0xf8, 0x09, 0x08, 0x00]), None);
+    }
+
+    #[test]
+    fn encoder_end_disconnects() {
+        // the HTTP reader gets an error instead of waiting forever when the encoder ends
+        let flac = FlacChannel::new(44100, 16, 2, false, Dither::None, FlacSettings::default());
+        let (tx, rx) = unbounded();
+        let keep_alive = KeepAlive::new(KeepAlivePolicy::Silence, -60.0, 44100, 2, 250);
+        let flac_in = flac.start(&rx, keep_alive).unwrap();
+        tx.send(vec![0.0f32; 4096].into()).unwrap();
+        flac.stop();
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        loop {
+            match flac_in.recv_deadline(deadline) {
+                Ok(_) => continue,
+                Err(e) => {
+                    assert!(e.is_disconnected(), "the encoder didn't end");
+                    break;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn ogg_flac_headers() {
+        let (flac_out, flac_in) = unbounded();
+        let mut writer = OggFlacWriter::new(FlacWriter::new(flac_out));
+        let mut streaminfo = vec![0x00, 0, 0, 34];
+        streaminfo.extend_from_slice(&[0u8; 34]);
+        let vorbis_comment = [0x84, 0, 0, 4, 0, 0, 0, 0];
+        writer.write_all(b"fLaC").unwrap();
+        writer.write_all(&streaminfo).unwrap();
+        assert!(flac_in.try_recv().is_err());
+        writer.write_all(&vorbis_comment).unwrap();
+        let pages = flac_in.try_recv().unwrap();
+        // the first page has only the mapping header packet of 13 + 38 bytes
+        assert_eq!(&pages[..4], b"OggS");
+        assert_eq!(pages[5], 0x02);
+        assert_eq!(pages[26], 1);
+        assert_eq!(pages[27], 51);
+        assert_eq!(&pages[28..33], b"\x7fFLAC");
+        assert_eq!(&pages[35..37], &[0, 1]);
+        assert_eq!(&pages[37..41], b"fLaC");
+        // the second page has the vorbis comment block
+        let second = &pages[28 + 51..];
+        assert_eq!(&second[..4], b"OggS");
+        assert_eq!(&second[28..], &vorbis_comment);
+        // a frame gets the sample count as granule position
+        writer
+            .write_all(&[0xff, 0xf8, 0x39, 0x08, 0x00, 0x00])
+            .unwrap();
+        let page = flac_in.try_recv().unwrap();
+        assert_eq!(i64::from_le_bytes(page[6..14].try_into().unwrap()), 1152);
     }
 }
@@ -0,0 +1,212 @@
+///
+/// icymeta.rs
+///
+/// ICY (SHOUTcast) in-band metadata: when a client sends "Icy-MetaData: 1" a metadata block
+/// is inserted in the audio stream after every ICY_METAINT audio bytes
+///
+/// a metadata block is a length byte (in units of 16 bytes) followed by "StreamTitle='...';"
+/// padded with zeroes, a block with length 0 means "the title has not changed"
+///
+/// the StreamTitle is made from the IcyTitle template in the config with these placeholders:
+///   {source}  the audio source
+///   {format}  the streaming format
+///   {version} the swyh-rs version
+///   {meta}    the first line of the IcyMetaFile, or of the output of the IcyMetaCommand
+///
+use crate::globals::statics::{APP_VERSION, CONFIG};
+use log::debug;
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    process::Command,
+    sync::Once,
+    thread,
+    time::Duration,
+};
+use strfmt::strfmt;
+
+/// the number of audio bytes between two metadata blocks (the "icy-metaint" header value)
+pub const ICY_METAINT: usize = 16000;
+/// the maximum metadata block size (the length byte is a multiple of 16)
+const MAX_META_LEN: usize = 255 * 16;
+/// the refresh interval of the {meta} placeholder
+const META_REFRESH: Duration = Duration::from_secs(2);
+
+// the last {meta} value read from the IcyMetaFile or the IcyMetaCommand
+static META_TITLE: Lazy<RwLock<String>> = Lazy::new(|| RwLock::new(String::new()));
+
+/// IcyMetaData - inserts the metadata blocks in the audio stream of one client
+#[derive(Debug, Clone)]
+pub struct IcyMetaData {
+    format: String,
+    bytes_until_meta: usize,
+    last_title: String,
+}
+
+impl IcyMetaData {
+    pub fn new(format: String) -> IcyMetaData {
+        start_meta_updater();
+        IcyMetaData {
+            format,
+            bytes_until_meta: ICY_METAINT,
+            last_title: String::new(),
+        }
+    }
+
+    /// interleave - copy the audio bytes to out and insert a metadata block
+    /// every ICY_METAINT bytes, with the current stream title
+    pub fn interleave(&mut self, audio: &[u8], out: &mut VecDeque<u8>) {
+        let format = self.format.clone();
+        self.insert_meta(audio, out, || stream_title(&format));
+    }
+
+    fn insert_meta<F>(&mut self, audio: &[u8], out: &mut VecDeque<u8>, title: F)
+    where
+        F: Fn() -> String,
+    {
+        let mut audio = audio;
+        while !audio.is_empty() {
+            let n = audio.len().min(self.bytes_until_meta);
+            out.extend(&audio[..n]);
+            audio = &audio[n..];
+            self.bytes_until_meta -= n;
+            if self.bytes_until_meta == 0 {
+                let block = self.meta_block(&title());
+                out.extend(block);
+                self.bytes_until_meta = ICY_METAINT;
+            }
+        }
+    }
+
+    /// meta_block - the metadata block for this title, empty if the title has not changed
+    fn meta_block(&mut self, title: &str) -> Vec<u8> {
+        if title == self.last_title {
+            return vec![0];
+        }
+        debug!("ICY StreamTitle: {title}");
+        self.last_title = title.to_string();
+        // a single quote would end the title
+        let mut meta = format!("StreamTitle='{}';", title.replace('\'', "\u{2019}"));
+        if meta.len() > MAX_META_LEN {
+            let mut end = MAX_META_LEN - 2;
+            while !meta.is_char_boundary(end) {
+                end -= 1;
+            }
+            meta.truncate(end);
+            meta.push_str("';");
+        }
+        let len = meta.len().div_ceil(16);
+        let mut block = Vec::with_capacity(1 + len * 16);
+        block.push(len as u8);
+        block.extend(meta.as_bytes());
+        block.resize(1 + len * 16, 0);
+        block
+    }
+}
+
+/// stream_title - fill in the IcyTitle template
+fn stream_title(format: &str) -> String {
+    let (template, source) = {
+        let conf = CONFIG.read();
+        (
+            conf.icy_title.clone().unwrap_or_default(),
+            conf.sound_source.clone(),
+        )
+    };
+    let mut vars = HashMap::new();
+    vars.insert("source".to_string(), source);
+    vars.insert("format".to_string(), format.to_string());
+    vars.insert("version".to_string(), APP_VERSION.to_string());
+    vars.insert("meta".to_string(), META_TITLE.read().clone());
+    strfmt(&template, &vars).unwrap_or(template)
+}
+
+/// start_meta_updater - start the thread that refreshes the {meta} value
+///
+/// the IcyMetaFile or IcyMetaCommand is never read in the streaming thread, so that a slow command can't stall the stream
+fn start_meta_updater() {
+    static INITIALIZER: Once = Once::new();
+    INITIALIZER.call_once(|| {
+        let _ = thread::Builder::new()
+            .name("icy_meta_updater".into())
+            .spawn(|| loop {
+                let (file, command) = {
+                    let conf = CONFIG.read();
+                    (conf.icy_meta_file.clone(), conf.icy_meta_command.clone())
+                };
+                let meta = if let Some(file) = file.filter(|f| !f.is_empty()) {
+                    fs::read_to_string(file).unwrap_or_default()
+                } else if let Some(command) = command.filter(|c| !c.is_empty()) {
+                    run_meta_command(&command)
+                } else {
+                    String::new()
+                };
+                *META_TITLE.write() = meta.lines().next().unwrap_or_default().trim().to_string();
+                thread::sleep(META_REFRESH);
+            });
+    });
+}
+
+/// run_meta_command - run the IcyMetaCommand with the shell and return its output
+fn run_meta_command(command: &str) -> String {
+    let output = if cfg!(target_os = "windows") {
+        Command::new("cmd").args(["/C", command]).output()
+    } else {
+        Command::new("sh").args(["-c", command]).output()
+    };
+    match output {
+        Ok(output) => String::from_utf8_lossy(&output.stdout).to_string(),
+        Err(e) => {
+            debug!("IcyMetaCommand {command} failed: {e}");
+            String::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn meta_block() {
+        let mut icy = IcyMetaData {
+            format: "FLAC".to_string(),
+            bytes_until_meta: ICY_METAINT,
+            last_title: String::new(),
+        };
+        let block = icy.meta_block("Artist - Title");
+        let meta = b"StreamTitle='Artist - Title';";
+        assert_eq!(block[0] as usize, meta.len().div_ceil(16));
+        assert_eq!(block.len(), 1 + block[0] as usize * 16);
+        assert_eq!(&block[1..1 + meta.len()], meta);
+        assert!(block[1 + meta.len()..].iter().all(|b| *b == 0));
+        // unchanged title
+        assert_eq!(icy.meta_block("Artist - Title"), vec![0]);
+        // too long title
+        let block = icy.meta_block(&"x".repeat(5000));
+        assert_eq!(block.len(), 1 + MAX_META_LEN);
+        assert!(block.ends_with(b"';"));
+    }
+
+    #[test]
+    fn interleave() {
+        let mut icy = IcyMetaData {
+            format: "WAV".to_string(),
+            bytes_until_meta: ICY_METAINT,
+            last_title: "unchanged".to_string(),
+        };
+        let audio = vec![1u8; ICY_METAINT * 2 + 100];
+        let mut out = VecDeque::new();
+        // in pieces that don't line up with the metadata interval
+        for chunk in audio.chunks(7001) {
+            icy.insert_meta(chunk, &mut out, || "unchanged".to_string());
+        }
+        let out: Vec<u8> = out.into_iter().collect();
+        assert_eq!(out.len(), audio.len() + 2);
+        assert_eq!(out[ICY_METAINT], 0);
+        assert_eq!(out[2 * ICY_METAINT + 1], 0);
+        assert_eq!(out.iter().filter(|b| **b == 0).count(), 2);
+    }
+}
@@ -0,0 +1,152 @@
+///
+/// keepalive.rs
+///
+/// KeepAlive: what is streamed when nothing is captured
+///
+/// most renderers close the connection when no data arrives for a few seconds, and some
+/// of them also when they only receive digital silence. after the capture timeout
+/// silence or a faint noise is streamed in real time until the capture resumes,
+/// or, with KeepAlivePolicy::None, nothing at all.
+///
+/// all the encoders read their samples with KeepAlive::recv, so the keep-alive data
+/// is encoded like the captured samples in every streaming format
+///
+use crate::{enums::streaming::KeepAlivePolicy, utils::dither::TpdfNoise};
+use crossbeam_channel::{Receiver, RecvTimeoutError};
+use std::{sync::Arc, time::Duration};
+
+/// the keep-alive samples are sent in pieces of this many msecs
+const KEEPALIVE_PERIOD: u64 = 250;
+
+/// KeepAlive - receives the samples of a stream, with the keep-alive samples
+/// after the capture timeout
+#[derive(Debug, Clone)]
+pub struct KeepAlive {
+    policy: KeepAlivePolicy,
+    capture_timeout: Duration,
+    // the peak amplitude of the noise
+    amplitude: f32,
+    noise: TpdfNoise,
+    // the number of samples in KEEPALIVE_PERIOD msecs
+    size: usize,
+    // nothing captured since the capture timeout
+    idle: bool,
+}
+
+impl KeepAlive {
+    pub fn new(
+        policy: KeepAlivePolicy,
+        noise_level_db: f32,
+        sample_rate: u32,
+        channels: u16,
+        capture_timeout_ms: u64,
+    ) -> KeepAlive {
+        let size = (sample_rate as u64 * channels as u64 * KEEPALIVE_PERIOD / 1000) as usize;
+        KeepAlive {
+            policy,
+            capture_timeout: Duration::from_millis(capture_timeout_ms.max(KEEPALIVE_PERIOD)),
+            amplitude: 10f32.powf(noise_level_db / 20.0),
+            noise: TpdfNoise::new(),
+            size,
+            idle: false,
+        }
+    }
+
+    /// recv - the next samples of the channel, or the keep-alive samples
+    ///
+    /// the first keep-alive samples are returned after the capture timeout, the next ones
+    /// every KEEPALIVE_PERIOD. without keep-alive the timeout is returned instead,
+    /// so that an encoder thread can check whether it has been stopped
+    ///
+    /// every keep-alive block is a new block, so that a shared conversion doesn't repeat
+    /// the same dither noise
+    pub fn recv(
+        &mut self,
+        samples_in: &Receiver<Arc<[f32]>>,
+    ) -> Result<Arc<[f32]>, RecvTimeoutError> {
+        let time_out = if self.idle {
+            Duration::from_millis(KEEPALIVE_PERIOD)
+        } else {
+            self.capture_timeout
+        };
+        match samples_in.recv_timeout(time_out) {
+            Ok(samples) => {
+                self.idle = false;
+                Ok(samples)
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                self.idle = true;
+                match self.policy {
+                    KeepAlivePolicy::Silence => Ok(vec![0f32; self.size].into()),
+                    KeepAlivePolicy::Noise => Ok((0..self.size)
+                        .map(|_| self.noise.next_sample() * self.amplitude)
+                        .collect()),
+                    KeepAlivePolicy::None => Err(RecvTimeoutError::Timeout),
+                }
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// true when nothing has been captured since the capture timeout
+    pub fn is_idle(&self) -> bool {
+        self.idle
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam_channel::unbounded;
+
+    const SAMPLE_RATE: u32 = 44100;
+
+    #[test]
+    fn keep_alive_policies() {
+        let (tx, rx) = unbounded();
+        // silence after the capture timeout, KEEPALIVE_PERIOD msecs at a time
+        let mut keep_alive = KeepAlive::new(KeepAlivePolicy::Silence, -60.0, SAMPLE_RATE, 6, 300);
+        tx.send(vec![0.5f32; 12].into()).unwrap();
+        assert_eq!(*keep_alive.recv(&rx).unwrap(), [0.5f32; 12]);
+        assert!(!keep_alive.is_idle());
+        let silence = keep_alive.recv(&rx).unwrap();
+        assert_eq!(silence.len(), (SAMPLE_RATE * 6 / 4) as usize);
+        assert!(silence.iter().all(|s| *s == 0.0));
+        assert!(keep_alive.is_idle());
+        // captured samples end the keep-alive
+        tx.send(vec![0.5f32; 12].into()).unwrap();
+        assert_eq!(keep_alive.recv(&rx).unwrap().len(), 12);
+        assert!(!keep_alive.is_idle());
+        // faint noise at -60 dB
+        let mut keep_alive = KeepAlive::new(KeepAlivePolicy::Noise, -60.0, SAMPLE_RATE, 2, 300);
+        let noise = keep_alive.recv(&rx).unwrap();
+        assert_eq!(noise.len(), (SAMPLE_RATE * 2 / 4) as usize);
+        assert!(noise.iter().all(|s| s.abs() <= 0.001));
+        assert!(noise.iter().any(|s| s.abs() > 0.0005));
+        let mean = noise.iter().sum::<f32>() / noise.len() as f32;
+        assert!(mean.abs() < 0.00005, "mean {mean}");
+        // a new piece of noise every time
+        assert_ne!(keep_alive.recv(&rx).unwrap(), noise);
+        // no keep-alive: only the timeout
+        let mut keep_alive = KeepAlive::new(KeepAlivePolicy::None, -60.0, SAMPLE_RATE, 2, 300);
+        assert_eq!(keep_alive.recv(&rx), Err(RecvTimeoutError::Timeout));
+        drop(tx);
+        assert_eq!(keep_alive.recv(&rx), Err(RecvTimeoutError::Disconnected));
+    }
+
+    #[test]
+    fn real_time_keep_alive() {
+        // the first keep-alive samples follow the capture timeout, the next ones follow
+        // each other in real time
+        let (_tx, rx) = unbounded::<Arc<[f32]>>();
+        let mut keep_alive = KeepAlive::new(KeepAlivePolicy::Silence, -60.0, SAMPLE_RATE, 2, 500);
+        let start = std::time::Instant::now();
+        keep_alive.recv(&rx).unwrap();
+        let first = start.elapsed();
+        assert!(first >= Duration::from_millis(500), "{first:?}");
+        keep_alive.recv(&rx).unwrap();
+        let next = start.elapsed() - first;
+        assert!(next >= Duration::from_millis(KEEPALIVE_PERIOD), "{next:?}");
+        assert!(next < Duration::from_millis(500), "{next:?}");
+    }
+}
@@ -1,11 +1,28 @@
 pub mod audiodevices;
+pub mod audiosource;
 pub mod bincommon;
+pub mod capturering;
+pub mod channelmap;
 pub mod commandline;
 pub mod configuration;
+pub mod dither;
+pub mod drift;
 pub mod flacstream;
 pub mod i24;
+pub mod icymeta;
+pub mod keepalive;
 pub mod local_ip_address;
+pub mod mp3stream;
+pub mod oggmux;
+pub mod opusstream;
+pub mod pcmconvert;
+pub mod preroll;
 pub mod priority;
+pub mod recorder;
+pub mod resampler;
 pub mod rwstream;
+pub mod streamencoder;
+pub mod testsignal;
 pub mod traits;
 pub mod ui_logger;
+pub mod wavheader;
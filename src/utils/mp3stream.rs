@@ -0,0 +1,197 @@
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError, Sender};
+use dasp_sample::Sample;
+use mp3lame_encoder::{Bitrate, Builder, Encoder, InterleavedPcm, Quality, VbrMode};
+use parking_lot::Mutex;
+use std::sync::{
+    atomic::{AtomicBool, Ordering::Relaxed},
+    Arc,
+};
+
+use crate::{
+    enums::streaming::StreamingFormat,
+    utils::{
+        keepalive::KeepAlive,
+        streamencoder::{StreamEncoder, ENCODED_QUEUE_SIZE},
+        ui_logger::ui_log,
+    },
+};
+
+/// didl protocolinfo
+pub static MP3_PROT_INFO: &str = "http-get:*:audio/mpeg:DLNA.ORG_PN=MP3;DLNA.ORG_OP=01;DLNA.ORG_CI=0;DLNA.ORG_FLAGS=01700000000000000000000000000000";
+
+// an Mp3Channel is the StreamEncoder for MP3
+// the ChannelStream writes the captured f32 samples
+// to the samples_in channel for encoding
+//
+// the encoder thread takes the only mp3_out Sender, so that mp3_in is disconnected
+// when the thread ends because of an error
+#[derive(Clone)]
+pub struct Mp3Channel {
+    pub mp3_in: Receiver<Vec<u8>>,
+    mp3_out: Arc<Mutex<Option<Sender<Vec<u8>>>>>,
+    active: Arc<AtomicBool>,
+    sample_rate: u32,
+    bitrate: u32,
+    vbr: bool,
+}
+
+impl Mp3Channel {
+    pub fn new(sample_rate: u32, bitrate: u32, vbr: bool) -> Mp3Channel {
+        let (mp3_out, mp3_in): (Sender<Vec<u8>>, Receiver<Vec<u8>>) = bounded(ENCODED_QUEUE_SIZE);
+        Mp3Channel {
+            mp3_in,
+            mp3_out: Arc::new(Mutex::new(Some(mp3_out))),
+            active: Arc::new(AtomicBool::new(false)),
+            sample_rate,
+            bitrate,
+            vbr,
+        }
+    }
+
+    pub fn run(&self, samples_in: Receiver<Arc<[f32]>>, mut keep_alive: KeepAlive) {
+        // move the sender to the thread
+        let Some(mp3_out) = self.mp3_out.lock().take() else {
+            return;
+        };
+        let sr = self.sample_rate;
+        let bitrate = self.bitrate;
+        let vbr = self.vbr;
+        let l_active = self.active.clone();
+        // fire up thread
+        self.active.store(true, Relaxed);
+        let spawned = std::thread::Builder::new()
+            .name("mp3_encoder".into())
+            .stack_size(4 * 1024 * 1024)
+            .spawn(move || {
+                // setup the encoder, dropping mp3_out on an error ends the stream
+                let mut enc = match build_encoder(sr, bitrate, vbr) {
+                    Ok(enc) => enc,
+                    Err(e) => {
+                        ui_log(format!("*E*E*> Unable to initialize the MP3 encoder: {e}"));
+                        return;
+                    }
+                };
+                // read and MP3 encode samples
+                let mut mp3_buffer: Vec<u8> = Vec::new();
+                while l_active.load(Relaxed) {
+                    let samples = match keep_alive.recv(&samples_in) {
+                        Ok(f32_samples) => f32_samples
+                            .iter()
+                            .map(|s| i16::from_sample(*s))
+                            .collect::<Vec<i16>>(),
+                        Err(RecvTimeoutError::Timeout) => continue,
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    };
+                    mp3_buffer.clear();
+                    mp3_buffer.reserve(mp3lame_encoder::max_required_buffer_size(samples.len()));
+                    match enc.encode(InterleavedPcm(&samples), mp3_buffer.spare_capacity_mut()) {
+                        Ok(n) => {
+                            // safety: the encoder has initialized n bytes of the spare capacity
+                            unsafe { mp3_buffer.set_len(n) };
+                            if n > 0 && mp3_out.send(mp3_buffer.clone()).is_err() {
+                                break;
+                            }
+                        }
+                        Err(e) => {
+                            ui_log(format!("*E*E*> MP3 encoding error {e:?}"));
+                            break;
+                        }
+                    }
+                }
+            });
+        // the closure and its sender are dropped, so the stream reader gets an error
+        if let Err(e) = spawned {
+            ui_log(format!(
+                "*E*E*> Unable to start the MP3 encoder thread: {e}"
+            ));
+            self.active.store(false, Relaxed);
+        }
+    }
+
+    pub fn stop(&self) {
+        self.active.store(false, Relaxed);
+    }
+}
+
+impl StreamEncoder for Mp3Channel {
+    fn format(&self) -> StreamingFormat {
+        StreamingFormat::Mp3
+    }
+
+    fn mime_type(&self) -> String {
+        "audio/mpeg".to_string()
+    }
+
+    fn bits_per_sample(&self) -> u16 {
+        16
+    }
+
+    fn protocol_info(&self) -> String {
+        MP3_PROT_INFO.to_string()
+    }
+
+    fn description(&self) -> String {
+        "audio/mpeg (MP3)".to_string()
+    }
+
+    fn start(
+        &self,
+        samples_in: &Receiver<Arc<[f32]>>,
+        keep_alive: KeepAlive,
+    ) -> Option<Receiver<Vec<u8>>> {
+        self.run(samples_in.clone(), keep_alive);
+        Some(self.mp3_in.clone())
+    }
+
+    fn stop(&self) {
+        Mp3Channel::stop(self);
+    }
+}
+
+/// build_encoder - a LAME encoder for stereo at the sample rate, CBR or VBR
+fn build_encoder(sample_rate: u32, bitrate: u32, vbr: bool) -> Result<Encoder, String> {
+    let mut builder = Builder::new().ok_or("unable to create the LAME builder")?;
+    let err = |e| format!("{e:?}");
+    builder.set_num_channels(2).map_err(err)?;
+    builder.set_sample_rate(sample_rate).map_err(err)?;
+    builder.set_brate(to_lame_bitrate(bitrate)).map_err(err)?;
+    if vbr {
+        builder.set_vbr_mode(VbrMode::Mtrh).map_err(err)?;
+        builder
+            .set_vbr_quality(to_vbr_quality(bitrate))
+            .map_err(err)?;
+        // a live stream can't be seeked to write the Xing VBR header
+        builder.set_to_write_vbr_tag(false).map_err(err)?;
+    } else {
+        builder.set_vbr_mode(VbrMode::Off).map_err(err)?;
+    }
+    builder.set_quality(Quality::Good).map_err(err)?;
+    builder.build().map_err(err)
+}
+
+/// to_lame_bitrate - the nearest supported MP3 bitrate (kbps) that is not higher
+fn to_lame_bitrate(bitrate: u32) -> Bitrate {
+    match bitrate {
+        320.. => Bitrate::Kbps320,
+        256..=319 => Bitrate::Kbps256,
+        224..=255 => Bitrate::Kbps224,
+        192..=223 => Bitrate::Kbps192,
+        160..=191 => Bitrate::Kbps160,
+        128..=159 => Bitrate::Kbps128,
+        112..=127 => Bitrate::Kbps112,
+        96..=111 => Bitrate::Kbps96,
+        80..=95 => Bitrate::Kbps80,
+        _ => Bitrate::Kbps64,
+    }
+}
+
+/// to_vbr_quality - the LAME VBR quality (V0..V6) that averages about the configured bitrate
+fn to_vbr_quality(bitrate: u32) -> Quality {
+    match bitrate {
+        256.. => Quality::Best,
+        224..=255 => Quality::SecondBest,
+        192..=223 => Quality::NearBest,
+        160..=191 => Quality::Nice,
+        _ => Quality::Decent,
+    }
+}
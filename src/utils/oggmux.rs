@@ -0,0 +1,199 @@
+///
+/// oggmux.rs
+///
+/// a minimal Ogg page muxer (RFC 3533) for the Ogg Opus and Ogg FLAC streaming formats
+///
+/// packets are added with their granule position, and pages are written when they are full
+/// or when they are flushed by the encoder (to keep the latency low)
+///
+use once_cell::sync::Lazy;
+
+/// the page header type flags
+const CONTINUED_PACKET: u8 = 0x01;
+const BEGIN_OF_STREAM: u8 = 0x02;
+/// the maximum number of lacing values in a page
+const MAX_SEGMENTS: usize = 255;
+
+/// OggMuxer - the Ogg pages of one logical bitstream
+pub struct OggMuxer {
+    serial: u32,
+    sequence: u32,
+    lacing: Vec<u8>,
+    body: Vec<u8>,
+    granule_position: i64,
+    first_page: bool,
+    continued: bool,
+}
+
+impl OggMuxer {
+    pub fn new(serial: u32) -> OggMuxer {
+        OggMuxer {
+            serial,
+            sequence: 0,
+            lacing: Vec::with_capacity(MAX_SEGMENTS),
+            body: Vec::new(),
+            granule_position: -1,
+            first_page: true,
+            continued: false,
+        }
+    }
+
+    /// add_packet - add a packet to the current page, full pages are appended to out
+    ///
+    /// the granule position is the one after the last sample of this packet
+    pub fn add_packet(&mut self, packet: &[u8], granule_position: u64, out: &mut Vec<u8>) {
+        let mut chunks = packet.chunks(255).peekable();
+        // a packet that is a multiple of 255 bytes ends with a 0 lacing value
+        let needs_terminator = packet.len().is_multiple_of(255);
+        while let Some(chunk) = chunks.next() {
+            self.lacing.push(chunk.len() as u8);
+            self.body.extend_from_slice(chunk);
+            let last = chunks.peek().is_none() && !needs_terminator;
+            if last {
+                self.granule_position = granule_position as i64;
+            }
+            if self.lacing.len() == MAX_SEGMENTS {
+                self.write_page(out);
+                self.continued = !last;
+            }
+        }
+        if needs_terminator {
+            self.lacing.push(0);
+            self.granule_position = granule_position as i64;
+            if self.lacing.len() == MAX_SEGMENTS {
+                self.write_page(out);
+                self.continued = false;
+            }
+        }
+    }
+
+    /// flush - append the current page to out, even if it is not full
+    pub fn flush(&mut self, out: &mut Vec<u8>) {
+        if !self.lacing.is_empty() {
+            self.write_page(out);
+            self.continued = false;
+        }
+    }
+
+    fn write_page(&mut self, out: &mut Vec<u8>) {
+        let mut header_type = 0u8;
+        if self.continued {
+            header_type |= CONTINUED_PACKET;
+        }
+        if self.first_page {
+            header_type |= BEGIN_OF_STREAM;
+            self.first_page = false;
+        }
+        let start = out.len();
+        out.extend_from_slice(b"OggS");
+        out.push(0); // stream structure version
+        out.push(header_type);
+        out.extend_from_slice(&self.granule_position.to_le_bytes());
+        out.extend_from_slice(&self.serial.to_le_bytes());
+        out.extend_from_slice(&self.sequence.to_le_bytes());
+        out.extend_from_slice(&[0u8; 4]); // the CRC is computed with a zero CRC field
+        out.push(self.lacing.len() as u8);
+        out.extend_from_slice(&self.lacing);
+        out.extend_from_slice(&self.body);
+        let crc = ogg_crc(&out[start..]);
+        out[start + 22..start + 26].copy_from_slice(&crc.to_le_bytes());
+        self.sequence = self.sequence.wrapping_add(1);
+        self.lacing.clear();
+        self.body.clear();
+        // a page without a completed packet has granule position -1
+        self.granule_position = -1;
+    }
+}
+
+/// ogg_crc - the Ogg page checksum: CRC-32 with polynomial 0x04c11db7,
+/// initial value 0, no reflection and no final xor
+pub fn ogg_crc(data: &[u8]) -> u32 {
+    static CRC_TABLE: Lazy<[u32; 256]> = Lazy::new(|| {
+        let mut table = [0u32; 256];
+        for (i, entry) in table.iter_mut().enumerate() {
+            let mut r = (i as u32) << 24;
+            for _ in 0..8 {
+                r = if r & 0x8000_0000 != 0 {
+                    (r << 1) ^ 0x04c1_1db7
+                } else {
+                    r << 1
+                };
+            }
+            *entry = r;
+        }
+        table
+    });
+    data.iter().fold(0u32, |crc, b| {
+        (crc << 8) ^ CRC_TABLE[((crc >> 24) as u8 ^ b) as usize]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // (header_type, granule, sequence, lacing, body)
+    type Page = (u8, i64, u32, Vec<u8>, Vec<u8>);
+
+    // split a byte stream in pages
+    fn parse_pages(mut data: &[u8]) -> Vec<Page> {
+        let mut pages = Vec::new();
+        while !data.is_empty() {
+            assert_eq!(&data[..4], b"OggS");
+            let nsegs = data[26] as usize;
+            let body_len: usize = data[27..27 + nsegs].iter().map(|l| *l as usize).sum();
+            let page_len = 27 + nsegs + body_len;
+            let mut page = data[..page_len].to_vec();
+            let crc = u32::from_le_bytes(page[22..26].try_into().unwrap());
+            page[22..26].copy_from_slice(&[0; 4]);
+            assert_eq!(ogg_crc(&page), crc);
+            pages.push((
+                data[5],
+                i64::from_le_bytes(data[6..14].try_into().unwrap()),
+                u32::from_le_bytes(data[18..22].try_into().unwrap()),
+                data[27..27 + nsegs].to_vec(),
+                data[27 + nsegs..page_len].to_vec(),
+            ));
+            data = &data[page_len..];
+        }
+        pages
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(ogg_crc(b"123456789"), 0x89a1_897f);
+        assert_eq!(ogg_crc(b""), 0);
+    }
+
+    #[test]
+    fn pages() {
+        let mut muxer = OggMuxer::new(0x1234);
+        let mut out = Vec::new();
+        muxer.add_packet(&[1u8; 19], 0, &mut out);
+        muxer.flush(&mut out);
+        muxer.add_packet(&[2u8; 600], 960, &mut out);
+        muxer.add_packet(&[3u8; 255], 1920, &mut out);
+        muxer.flush(&mut out);
+        // a packet that doesn't fit in one page
+        muxer.add_packet(&[4u8; 70000], 2880, &mut out);
+        muxer.flush(&mut out);
+        let pages = parse_pages(&out);
+        assert_eq!(pages.len(), 4);
+        assert_eq!(pages[0].0, BEGIN_OF_STREAM);
+        assert_eq!(pages[0].3, vec![19]);
+        assert_eq!(pages[1].0, 0);
+        assert_eq!(pages[1].1, 1920);
+        assert_eq!(pages[1].2, 1);
+        assert_eq!(pages[1].3, vec![255, 255, 90, 255, 0]);
+        assert_eq!(pages[1].4.len(), 855);
+        // 70000 = 274 * 255 + 130: 255 segments on the first page, 20 on the next one
+        assert_eq!(pages[2].0, 0);
+        assert_eq!(pages[2].1, -1);
+        assert_eq!(pages[2].3.len(), 255);
+        assert_eq!(pages[3].0, CONTINUED_PACKET);
+        assert_eq!(pages[3].1, 2880);
+        assert_eq!(pages[3].3.len(), 20);
+        assert_eq!(pages[3].3[19], 130);
+        assert_eq!(pages[2].4.len() + pages[3].4.len(), 70000);
+    }
+}
@@ -0,0 +1,240 @@
+use audiopus::{coder::Encoder, Application, Bitrate, Channels, SampleRate};
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError, Sender};
+use parking_lot::Mutex;
+use std::sync::{
+    atomic::{AtomicBool, Ordering::Relaxed},
+    Arc,
+};
+
+use crate::{
+    enums::streaming::StreamingFormat,
+    utils::{
+        flacstream::OGG_PROT_INFO,
+        keepalive::KeepAlive,
+        oggmux::OggMuxer,
+        resampler::Resampler,
+        streamencoder::{StreamEncoder, ENCODED_QUEUE_SIZE},
+        ui_logger::ui_log,
+    },
+};
+
+/// Opus always runs at 48 kHz
+const OPUS_SAMPLE_RATE: u32 = 48000;
+/// a 20 ms stereo frame at 48 kHz
+const FRAME_SIZE: usize = 960;
+/// the maximum packet size recommended by the Opus documentation
+const MAX_PACKET_SIZE: usize = 4000;
+/// the number of packets (60 ms) in an Ogg page
+const PACKETS_PER_PAGE: u64 = 3;
+/// the encoder delay if the encoder can't tell
+const DEFAULT_PRE_SKIP: u32 = 312;
+
+// an OpusChannel is the StreamEncoder for Ogg Opus
+// the ChannelStream writes the captured f32 samples
+// to the samples_in channel for encoding
+//
+// the encoder thread takes the only opus_out Sender, so that opus_in is disconnected
+// when the thread ends because of an error
+#[derive(Clone)]
+pub struct OpusChannel {
+    pub opus_in: Receiver<Vec<u8>>,
+    opus_out: Arc<Mutex<Option<Sender<Vec<u8>>>>>,
+    active: Arc<AtomicBool>,
+    sample_rate: u32,
+    bitrate: u32,
+}
+
+impl OpusChannel {
+    pub fn new(sample_rate: u32, bitrate: u32) -> OpusChannel {
+        let (opus_out, opus_in): (Sender<Vec<u8>>, Receiver<Vec<u8>>) = bounded(ENCODED_QUEUE_SIZE);
+        OpusChannel {
+            opus_in,
+            opus_out: Arc::new(Mutex::new(Some(opus_out))),
+            active: Arc::new(AtomicBool::new(false)),
+            sample_rate,
+            bitrate,
+        }
+    }
+
+    pub fn run(&self, samples_in: Receiver<Arc<[f32]>>, mut keep_alive: KeepAlive) {
+        // move the sender to the thread
+        let Some(opus_out) = self.opus_out.lock().take() else {
+            return;
+        };
+        let sr = self.sample_rate;
+        let bitrate = self.bitrate;
+        let l_active = self.active.clone();
+        // fire up thread
+        self.active.store(true, Relaxed);
+        let spawned = std::thread::Builder::new()
+            .name("opus_encoder".into())
+            .stack_size(4 * 1024 * 1024)
+            .spawn(move || {
+                // setup the encoder
+                let mut enc =
+                    match Encoder::new(SampleRate::Hz48000, Channels::Stereo, Application::Audio) {
+                        Ok(enc) => enc,
+                        Err(e) => {
+                            ui_log(format!(
+                                "*E*E*> Unable to initialize the Opus encoder: {e:?}"
+                            ));
+                            return;
+                        }
+                    };
+                if let Err(e) = enc.set_bitrate(Bitrate::BitsPerSecond((bitrate * 1000) as i32)) {
+                    ui_log(format!("*W*W*> Unable to set the Opus bitrate: {e:?}"));
+                }
+                let pre_skip = enc.lookahead().unwrap_or(DEFAULT_PRE_SKIP);
+                // Opus needs 48 kHz
+                let mut resampler = if sr != OPUS_SAMPLE_RATE {
+                    match Resampler::new(sr, OPUS_SAMPLE_RATE, 2) {
+                        Ok(r) => Some(r),
+                        Err(e) => {
+                            ui_log(format!("*E*E*> {e}"));
+                            return;
+                        }
+                    }
+                } else {
+                    None
+                };
+                // the Ogg identification and comment header pages
+                let mut muxer = OggMuxer::new(rand::random());
+                let mut ogg_buffer: Vec<u8> = Vec::new();
+                muxer.add_packet(&opus_head(pre_skip, sr), 0, &mut ogg_buffer);
+                muxer.flush(&mut ogg_buffer);
+                muxer.add_packet(&opus_tags(), 0, &mut ogg_buffer);
+                muxer.flush(&mut ogg_buffer);
+                if opus_out.send(ogg_buffer.clone()).is_err() {
+                    return;
+                }
+                // read, resample and Opus encode samples
+                let mut pending: Vec<f32> = Vec::with_capacity(FRAME_SIZE * 4);
+                let mut packet = vec![0u8; MAX_PACKET_SIZE];
+                let mut granule_position = 0u64;
+                let mut packets = 0u64;
+                while l_active.load(Relaxed) {
+                    match keep_alive.recv(&samples_in) {
+                        Ok(f32_samples) => match resampler.as_mut() {
+                            Some(r) => pending.extend(r.process(&f32_samples)),
+                            None => pending.extend_from_slice(&f32_samples),
+                        },
+                        Err(RecvTimeoutError::Timeout) => continue,
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                    ogg_buffer.clear();
+                    let mut frames = pending.chunks_exact(FRAME_SIZE * 2);
+                    for frame in frames.by_ref() {
+                        match enc.encode_float(frame, &mut packet) {
+                            Ok(n) => {
+                                granule_position += FRAME_SIZE as u64;
+                                muxer.add_packet(&packet[..n], granule_position, &mut ogg_buffer);
+                                packets += 1;
+                                if packets.is_multiple_of(PACKETS_PER_PAGE) {
+                                    muxer.flush(&mut ogg_buffer);
+                                }
+                            }
+                            Err(e) => {
+                                ui_log(format!("*E*E*> Opus encoding error {e:?}"));
+                                l_active.store(false, Relaxed);
+                                break;
+                            }
+                        }
+                    }
+                    let remainder = frames.remainder().len();
+                    pending.drain(..pending.len() - remainder);
+                    if !ogg_buffer.is_empty() && opus_out.send(ogg_buffer.clone()).is_err() {
+                        break;
+                    }
+                }
+            });
+        // the closure and its sender are dropped, so the stream reader gets an error
+        if let Err(e) = spawned {
+            ui_log(format!(
+                "*E*E*> Unable to start the Opus encoder thread: {e}"
+            ));
+            self.active.store(false, Relaxed);
+        }
+    }
+
+    pub fn stop(&self) {
+        self.active.store(false, Relaxed);
+    }
+}
+
+impl StreamEncoder for OpusChannel {
+    fn format(&self) -> StreamingFormat {
+        StreamingFormat::Opus
+    }
+
+    fn mime_type(&self) -> String {
+        "audio/ogg".to_string()
+    }
+
+    fn bits_per_sample(&self) -> u16 {
+        16
+    }
+
+    fn protocol_info(&self) -> String {
+        OGG_PROT_INFO.to_string()
+    }
+
+    fn description(&self) -> String {
+        "audio/ogg (Opus)".to_string()
+    }
+
+    fn start(
+        &self,
+        samples_in: &Receiver<Arc<[f32]>>,
+        keep_alive: KeepAlive,
+    ) -> Option<Receiver<Vec<u8>>> {
+        self.run(samples_in.clone(), keep_alive);
+        Some(self.opus_in.clone())
+    }
+
+    fn stop(&self) {
+        OpusChannel::stop(self);
+    }
+}
+
+/// opus_head - the Ogg Opus identification header (RFC 7845)
+fn opus_head(pre_skip: u32, input_sample_rate: u32) -> Vec<u8> {
+    let mut head = Vec::with_capacity(19);
+    head.extend_from_slice(b"OpusHead");
+    head.push(1); // version
+    head.push(2); // channels
+    head.extend_from_slice(&(pre_skip as u16).to_le_bytes());
+    head.extend_from_slice(&input_sample_rate.to_le_bytes());
+    head.extend_from_slice(&0i16.to_le_bytes()); // output gain
+    head.push(0); // channel mapping family
+    head
+}
+
+/// opus_tags - the Ogg Opus comment header, with only the vendor string
+fn opus_tags() -> Vec<u8> {
+    let vendor = b"swyh-rs";
+    let mut tags = Vec::with_capacity(16 + vendor.len());
+    tags.extend_from_slice(b"OpusTags");
+    tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    tags.extend_from_slice(vendor);
+    tags.extend_from_slice(&0u32.to_le_bytes()); // no user comments
+    tags
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opus_headers() {
+        let head = opus_head(312, 44100);
+        assert_eq!(head.len(), 19);
+        assert_eq!(&head[..8], b"OpusHead");
+        assert_eq!(head[9], 2);
+        assert_eq!(u16::from_le_bytes([head[10], head[11]]), 312);
+        assert_eq!(u32::from_le_bytes(head[12..16].try_into().unwrap()), 44100);
+        let tags = opus_tags();
+        assert_eq!(&tags[..8], b"OpusTags");
+        assert_eq!(u32::from_le_bytes(tags[8..12].try_into().unwrap()), 7);
+        assert_eq!(&tags[12..19], b"swyh-rs");
+    }
+}
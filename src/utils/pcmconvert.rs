@@ -0,0 +1,236 @@
+///
+/// pcmconvert.rs
+///
+/// PcmConverter: the bulk conversion of the f32 sample blocks to LPCM and WAV bytes
+///
+/// the capture distributor sends every captured block as one Arc<[f32]> to all the clients,
+/// so the clients that stream the same format get the same blocks. they share one
+/// PcmConverter, that converts each block only once and hands out the same bytes.
+///
+use crate::{
+    enums::streaming::Dither,
+    utils::{dither::Ditherer, flacstream::to_i32_sample},
+};
+use dasp_sample::Sample;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Weak},
+};
+
+/// the number of converted blocks that are kept for the other clients,
+/// so that a client that lags a few blocks behind still finds them
+const RECENT_BLOCKS: usize = 16;
+
+/// a converted block: the samples and their bytes
+type ConvertedBlock = (Arc<[f32]>, Arc<[u8]>);
+
+/// the shared converters by format, channels and dither
+type SharedConverters = HashMap<(PcmFormat, u16, Dither), Weak<PcmConverter>>;
+
+/// the byte layout of the converted samples
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PcmFormat {
+    /// 16 bit big endian (audio/L16)
+    I16Be,
+    /// 16 bit little endian (WAV)
+    I16Le,
+    /// 24 bit big endian (audio/L24)
+    I24Be,
+    /// 24 bit little endian (WAV)
+    I24Le,
+    /// 32 bit little endian (WAV)
+    I32Le,
+    /// 32 bit IEEE float little endian (WAV)
+    F32Le,
+}
+
+impl PcmFormat {
+    /// only the 16 bit formats are dithered
+    fn dither(&self, dither: Dither) -> Dither {
+        match self {
+            PcmFormat::I16Be | PcmFormat::I16Le => dither,
+            _ => Dither::None,
+        }
+    }
+
+    /// the number of bytes of a sample
+    pub fn sample_size(&self) -> usize {
+        match self {
+            PcmFormat::I16Be | PcmFormat::I16Le => 2,
+            PcmFormat::I24Be | PcmFormat::I24Le => 3,
+            PcmFormat::I32Le | PcmFormat::F32Le => 4,
+        }
+    }
+}
+
+/// convert - append the samples to out in the format, in one pass per block
+///
+/// the 16 bit samples are dithered with the ditherer, if any
+pub fn convert(
+    samples: &[f32],
+    format: PcmFormat,
+    ditherer: Option<&mut Ditherer>,
+    out: &mut Vec<u8>,
+) {
+    let size = format.sample_size();
+    let start = out.len();
+    out.resize(start + samples.len() * size, 0);
+    let bytes = out[start..].chunks_exact_mut(size).zip(samples);
+    match (format, ditherer) {
+        (PcmFormat::I16Be, Some(d)) => bytes.for_each(|(b, s)| {
+            b.copy_from_slice(&d.to_i16(*s).to_be_bytes());
+        }),
+        (PcmFormat::I16Le, Some(d)) => bytes.for_each(|(b, s)| {
+            b.copy_from_slice(&d.to_i16(*s).to_le_bytes());
+        }),
+        (PcmFormat::I16Be, None) => bytes.for_each(|(b, s)| {
+            b.copy_from_slice(&i16::from_sample(*s).to_be_bytes());
+        }),
+        (PcmFormat::I16Le, None) => bytes.for_each(|(b, s)| {
+            b.copy_from_slice(&i16::from_sample(*s).to_le_bytes());
+        }),
+        // the 24 most significant bits of the 32 bit sample
+        (PcmFormat::I24Be, _) => bytes.for_each(|(b, s)| {
+            b.copy_from_slice(&to_i32_sample(*s).to_be_bytes()[..3]);
+        }),
+        (PcmFormat::I24Le, _) => bytes.for_each(|(b, s)| {
+            b.copy_from_slice(&to_i32_sample(*s).to_le_bytes()[1..]);
+        }),
+        (PcmFormat::I32Le, _) => bytes.for_each(|(b, s)| {
+            b.copy_from_slice(&to_i32_sample(*s).to_le_bytes());
+        }),
+        // the captured samples as they are
+        (PcmFormat::F32Le, _) => bytes.for_each(|(b, s)| {
+            b.copy_from_slice(&s.to_le_bytes());
+        }),
+    }
+}
+
+/// PcmConverter - converts the sample blocks of all the clients with the same format
+pub struct PcmConverter {
+    format: PcmFormat,
+    ditherer: Option<Mutex<Ditherer>>,
+    // the recently converted blocks, the Arc keeps the address of a block unique
+    recent: Mutex<VecDeque<ConvertedBlock>>,
+}
+
+impl PcmConverter {
+    /// new - a converter that isn't shared, only 16 bit is dithered
+    pub fn new(format: PcmFormat, channels: u16, dither: Dither) -> PcmConverter {
+        let dither = format.dither(dither);
+        PcmConverter {
+            format,
+            ditherer: (dither != Dither::None).then(|| Mutex::new(Ditherer::new(dither, channels))),
+            recent: Mutex::new(VecDeque::with_capacity(RECENT_BLOCKS)),
+        }
+    }
+
+    /// shared - the converter of all the clients with this format
+    ///
+    /// the converter lives as long as one of its clients
+    pub fn shared(format: PcmFormat, channels: u16, dither: Dither) -> Arc<PcmConverter> {
+        static CONVERTERS: Lazy<Mutex<SharedConverters>> = Lazy::new(|| Mutex::new(HashMap::new()));
+        let mut converters = CONVERTERS.lock();
+        let key = (format, channels, format.dither(dither));
+        if let Some(converter) = converters.get(&key).and_then(Weak::upgrade) {
+            return converter;
+        }
+        converters.retain(|_, c| c.strong_count() > 0);
+        let converter = Arc::new(PcmConverter::new(format, channels, dither));
+        converters.insert(key, Arc::downgrade(&converter));
+        converter
+    }
+
+    /// convert - the bytes of the block, converted by the first client that needs them
+    pub fn convert(&self, samples: &Arc<[f32]>) -> Arc<[u8]> {
+        let mut recent = self.recent.lock();
+        if let Some((_, bytes)) = recent.iter().find(|(block, _)| Arc::ptr_eq(block, samples)) {
+            return bytes.clone();
+        }
+        let mut out = Vec::with_capacity(samples.len() * self.format.sample_size());
+        match &self.ditherer {
+            Some(d) => convert(samples, self.format, Some(&mut d.lock()), &mut out),
+            None => convert(samples, self.format, None, &mut out),
+        }
+        let bytes: Arc<[u8]> = out.into();
+        if recent.len() == RECENT_BLOCKS {
+            recent.pop_front();
+        }
+        recent.push_back((samples.clone(), bytes.clone()));
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::i24::I24Sample;
+
+    #[test]
+    fn bulk_conversion() {
+        // the same bytes as the sample by sample conversion
+        let samples: Vec<f32> = (0..1000).map(|n| (n as f32 / 500.0) - 1.0).collect();
+        let mut out = Vec::new();
+        convert(&samples, PcmFormat::I24Be, None, &mut out);
+        let expected: Vec<u8> = samples
+            .iter()
+            .flat_map(|s| {
+                let i24 = s.to_i24();
+                [i24.b1, i24.b2, i24.b3]
+            })
+            .collect();
+        assert_eq!(out, expected);
+        out.clear();
+        convert(&samples, PcmFormat::I24Le, None, &mut out);
+        let expected: Vec<u8> = expected
+            .chunks_exact(3)
+            .flat_map(|b| [b[2], b[1], b[0]])
+            .collect();
+        assert_eq!(out, expected);
+        out.clear();
+        convert(&samples, PcmFormat::I16Be, None, &mut out);
+        let expected: Vec<u8> = samples
+            .iter()
+            .flat_map(|s| i16::from_sample(*s).to_be_bytes())
+            .collect();
+        assert_eq!(out, expected);
+        // appended to what is already there
+        convert(&[1.0, -1.0], PcmFormat::I32Le, None, &mut out);
+        assert_eq!(out.len(), expected.len() + 8);
+        assert_eq!(
+            &out[expected.len()..expected.len() + 4],
+            &[0xff, 0xff, 0xff, 0x7f]
+        );
+    }
+
+    #[test]
+    fn shared_conversion() {
+        // the converters are global, so no other test may use these channel counts
+        let first = PcmConverter::shared(PcmFormat::I24Le, 7, Dither::None);
+        let second = PcmConverter::shared(PcmFormat::I24Le, 7, Dither::None);
+        assert!(Arc::ptr_eq(&first, &second));
+        let other = PcmConverter::shared(PcmFormat::I24Le, 5, Dither::None);
+        assert!(!Arc::ptr_eq(&first, &other));
+        // a block is converted once for all the clients
+        let block: Arc<[f32]> = vec![0.5f32; 512].into();
+        let bytes = first.convert(&block);
+        assert_eq!(bytes.len(), 512 * 3);
+        assert!(Arc::ptr_eq(&bytes, &second.convert(&block)));
+        // an equal but different block is converted again
+        let copy: Arc<[f32]> = vec![0.5f32; 512].into();
+        let copy_bytes = second.convert(&copy);
+        assert!(!Arc::ptr_eq(&bytes, &copy_bytes));
+        assert_eq!(bytes, copy_bytes);
+        // a lagging client still finds the recent blocks
+        for _ in 0..RECENT_BLOCKS - 2 {
+            first.convert(&vec![0f32; 16].into());
+        }
+        assert!(Arc::ptr_eq(&bytes, &second.convert(&block)));
+        // the converter ends with its last client
+        let weak = Arc::downgrade(&first);
+        drop((first, second));
+        assert!(weak.upgrade().is_none());
+    }
+}
@@ -0,0 +1,144 @@
+///
+/// preroll.rs
+///
+/// PreRoll: the most recently captured audio, for the clients that connect
+///
+/// renderers that buffer a few seconds before they start playing start a lot faster when
+/// they get the last PreRollMs of audio at once. the capture distributor keeps the blocks it
+/// has sent to the clients here, and a new client gets them before the live samples
+///
+/// the distributor adds a block while it holds the CLIENTS read lock, and a new client is
+/// seeded while the CLIENTS write lock is held, so that no block is lost or sent twice
+///
+use std::{collections::VecDeque, sync::Arc};
+
+/// a captured block: (stereo, captured), the same block when the capture is stereo
+pub type PreRollBlock = (Arc<[f32]>, Arc<[f32]>);
+
+/// PreRoll - the last captured blocks, both stereo and as captured (multichannel)
+#[derive(Debug, Default)]
+pub struct PreRoll {
+    blocks: VecDeque<PreRollBlock>,
+    // the number of stereo frames in the blocks
+    frames: usize,
+    sample_rate: u32,
+}
+
+impl PreRoll {
+    pub fn new() -> PreRoll {
+        PreRoll::default()
+    }
+
+    /// reset - forget the blocks, for a (new) capture at sample_rate
+    pub fn reset(&mut self, sample_rate: u32) {
+        self.blocks.clear();
+        self.frames = 0;
+        self.sample_rate = sample_rate;
+    }
+
+    /// push - keep the block, and forget the blocks older than max_ms
+    pub fn push(&mut self, stereo: &Arc<[f32]>, captured: &Arc<[f32]>, max_ms: u32) {
+        let max_frames = self.ms_to_frames(max_ms);
+        if max_frames == 0 {
+            self.reset(self.sample_rate);
+            return;
+        }
+        self.blocks.push_back((stereo.clone(), captured.clone()));
+        self.frames += stereo.len() / 2;
+        while let Some((oldest, _)) = self.blocks.front() {
+            let oldest_frames = oldest.len() / 2;
+            if self.frames - oldest_frames < max_frames {
+                break;
+            }
+            self.frames -= oldest_frames;
+            self.blocks.pop_front();
+        }
+    }
+
+    /// last - the most recent blocks with at least ms msecs of audio, oldest first,
+    /// or all of them if there is less
+    pub fn last(&self, ms: u32) -> impl Iterator<Item = &PreRollBlock> {
+        let wanted = self.ms_to_frames(ms);
+        let mut frames = 0;
+        let n = if wanted == 0 {
+            0
+        } else {
+            self.blocks
+                .iter()
+                .rev()
+                .take_while(|(stereo, _)| {
+                    let more = frames < wanted;
+                    frames += stereo.len() / 2;
+                    more
+                })
+                .count()
+        };
+        self.blocks.iter().skip(self.blocks.len() - n)
+    }
+
+    /// the sample rate of the capture
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// the msecs of audio in the pre-roll
+    pub fn duration_ms(&self) -> u32 {
+        match self.sample_rate {
+            0 => 0,
+            rate => (self.frames as u64 * 1000 / rate as u64) as u32,
+        }
+    }
+
+    fn ms_to_frames(&self, ms: u32) -> usize {
+        (self.sample_rate as u64 * ms as u64 / 1000) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 10 msecs of stereo at 1 kHz
+    fn block(n: u8) -> Arc<[f32]> {
+        vec![n as f32; 20].into()
+    }
+
+    #[test]
+    fn pre_roll() {
+        let mut pre_roll = PreRoll::new();
+        pre_roll.reset(1000);
+        for n in 0..100 {
+            let b = block(n);
+            pre_roll.push(&b, &b, 250);
+        }
+        assert_eq!(pre_roll.duration_ms(), 250);
+        // the most recent blocks, oldest first
+        let last: Vec<f32> = pre_roll.last(50).map(|(s, _)| s[0]).collect();
+        assert_eq!(last, [95.0, 96.0, 97.0, 98.0, 99.0]);
+        // rounded up to whole blocks
+        assert_eq!(pre_roll.last(45).count(), 5);
+        // not more than there is
+        assert_eq!(pre_roll.last(1000).count(), 25);
+        assert_eq!(pre_roll.last(0).count(), 0);
+        // a smaller maximum shrinks the pre-roll, 0 empties it
+        pre_roll.push(&block(100), &block(100), 100);
+        assert_eq!(pre_roll.duration_ms(), 100);
+        pre_roll.push(&block(101), &block(101), 0);
+        assert_eq!(pre_roll.duration_ms(), 0);
+        assert_eq!(pre_roll.last(100).count(), 0);
+    }
+
+    #[test]
+    fn multichannel_pre_roll() {
+        // the duration is that of the stereo blocks, whatever the captured channels
+        let mut pre_roll = PreRoll::new();
+        pre_roll.reset(1000);
+        let stereo = block(1);
+        let captured: Arc<[f32]> = vec![1.0f32; 60].into();
+        for _ in 0..10 {
+            pre_roll.push(&stereo, &captured, 1000);
+        }
+        assert_eq!(pre_roll.duration_ms(), 100);
+        assert!(pre_roll.last(20).all(|(_, c)| c.len() == 60));
+    }
+}
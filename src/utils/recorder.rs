@@ -0,0 +1,589 @@
+///
+/// recorder.rs
+///
+/// Recorder: records the captured audio to FLAC or WAV files
+///
+/// the capture distributor passes every stereo block it sends to the clients to the
+/// recorder too. a recorder thread encodes the blocks, with the FlacChannel of flacstream.rs
+/// or the WavEncoder, and writes them to a file in the RecordingDir.
+///
+/// a new file is started every RecordingRotateMins minutes or RecordingRotateMb megabytes,
+/// and with RecordingSilenceSplit after that many seconds of silence (the silence up to the
+/// split is recorded, the silence after it is not). the file names are the UTC start time
+/// of the file.
+///
+/// the recorder queue holds ENCODED_QUEUE_SIZE blocks, when the disk can't keep up the
+/// newest blocks are dropped
+///
+use crate::{
+    enums::streaming::{Dither, KeepAlivePolicy, StreamingFormat, WavHeader},
+    globals::statics::{CONFIG, RECORDER},
+    utils::{
+        configuration::Configuration,
+        flacstream::{FlacChannel, FlacSettings},
+        keepalive::KeepAlive,
+        streamencoder::{StreamEncoder, WavEncoder, ENCODED_QUEUE_SIZE},
+        ui_logger::ui_log,
+    },
+};
+use crossbeam_channel::{bounded, unbounded, Receiver, Sender, TrySendError};
+use parking_lot::Mutex;
+use std::{
+    collections::VecDeque,
+    fs::{self, File},
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering::Relaxed},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// samples below this level (-60 dBFS) are silence for the silence split
+const SILENCE_LEVEL: f32 = 0.001;
+
+/// RecordingSettings - the format, directory and rotation of the recordings
+#[derive(Debug, Clone)]
+pub struct RecordingSettings {
+    pub dir: PathBuf,
+    /// Flac or Wav
+    pub format: StreamingFormat,
+    pub bits_per_sample: u16,
+    /// 32 bit float WAV
+    pub float: bool,
+    pub dither: Dither,
+    pub flac: FlacSettings,
+    /// a new file after this time
+    pub rotate_time: Option<Duration>,
+    /// a new file after this many bytes
+    pub rotate_size: Option<u64>,
+    /// a new file after this much silence
+    pub silence_split: Option<Duration>,
+}
+
+impl RecordingSettings {
+    pub fn from_config(conf: &Configuration) -> RecordingSettings {
+        RecordingSettings {
+            dir: conf.recording_dir(),
+            format: match conf.recording_format {
+                Some(StreamingFormat::Wav) => StreamingFormat::Wav,
+                _ => StreamingFormat::Flac,
+            },
+            bits_per_sample: conf.bits_per_sample.unwrap_or(16),
+            float: conf.float_samples.unwrap_or(false),
+            dither: conf.dither.unwrap_or(Dither::None),
+            flac: FlacSettings::from_config(conf),
+            rotate_time: match conf.recording_rotate_mins.unwrap_or(0) {
+                0 => None,
+                mins => Some(Duration::from_secs(mins as u64 * 60)),
+            },
+            rotate_size: match conf.recording_rotate_mb.unwrap_or(0) {
+                0 => None,
+                mb => Some(mb as u64 * 1024 * 1024),
+            },
+            silence_split: match conf.recording_silence_split.unwrap_or(0) {
+                0 => None,
+                secs => Some(Duration::from_secs(secs as u64)),
+            },
+        }
+    }
+}
+
+/// RecordingStatus - the file that is being recorded, for the API
+#[derive(Debug, Clone, Default)]
+pub struct RecordingStatus {
+    pub file: Option<PathBuf>,
+    pub bytes: u64,
+    pub files: u32,
+}
+
+/// Recorder - the sending end of the recorder thread
+pub struct Recorder {
+    tx: Sender<(Arc<[f32]>, u32)>,
+    status: Arc<Mutex<RecordingStatus>>,
+    thread: JoinHandle<()>,
+    // the number of blocks dropped since the queue was last full
+    dropped: AtomicU64,
+}
+
+impl Recorder {
+    /// new - start the recorder thread, the first file is created when the samples arrive
+    pub fn new(settings: RecordingSettings) -> Result<Recorder, String> {
+        Recorder::with_slot(settings, &RECORDER)
+    }
+
+    /// with_slot - start the recorder thread that removes itself from the slot after an error
+    fn with_slot(
+        settings: RecordingSettings,
+        slot: &'static Mutex<Option<Recorder>>,
+    ) -> Result<Recorder, String> {
+        fs::create_dir_all(&settings.dir).map_err(|e| {
+            format!(
+                "Unable to create the recording directory {}: {e}",
+                settings.dir.display()
+            )
+        })?;
+        let (tx, rx) = bounded(ENCODED_QUEUE_SIZE);
+        let status = Arc::new(Mutex::new(RecordingStatus::default()));
+        let thread_status = status.clone();
+        let thread = thread::Builder::new()
+            .name("recorder".into())
+            .spawn(move || run_recorder(rx, settings, thread_status, slot))
+            .map_err(|e| format!("Unable to start the recorder thread: {e}"))?;
+        Ok(Recorder {
+            tx,
+            status,
+            thread,
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    /// write - record the stereo samples captured at sample_rate, never blocks the distributor
+    pub fn write(&self, samples: &Arc<[f32]>, sample_rate: u32) {
+        match self.tx.try_send((samples.clone(), sample_rate)) {
+            Err(TrySendError::Full(_)) => {
+                if self.dropped.fetch_add(1, Relaxed) == 0 {
+                    ui_log("*W*W*> The recording can't keep up, dropping audio".to_string());
+                }
+            }
+            Err(TrySendError::Disconnected(_)) => {}
+            Ok(()) => {
+                let dropped = self.dropped.swap(0, Relaxed);
+                if dropped > 0 {
+                    ui_log(format!("*W*W*> Dropped {dropped} blocks of the recording"));
+                }
+            }
+        }
+    }
+
+    pub fn status(&self) -> RecordingStatus {
+        self.status.lock().clone()
+    }
+
+    /// stop - record the samples that are still queued, close the file and wait for the thread
+    pub fn stop(self) {
+        drop(self.tx);
+        let _ = self.thread.join();
+    }
+}
+
+/// start_recording - start recording with the configured settings
+pub fn start_recording() -> Result<(), String> {
+    let mut recorder = RECORDER.lock();
+    if recorder.is_some() {
+        return Ok(());
+    }
+    let settings = RecordingSettings::from_config(&CONFIG.read());
+    ui_log(format!(
+        "Recording {} to {}",
+        settings.format,
+        settings.dir.display()
+    ));
+    *recorder = Some(Recorder::new(settings)?);
+    Ok(())
+}
+
+/// stop_recording - stop recording, if recording
+pub fn stop_recording() {
+    let recorder = RECORDER.lock().take();
+    if let Some(recorder) = recorder {
+        recorder.stop();
+        ui_log("Recording stopped".to_string());
+    }
+}
+
+/// recording_status - the status of the recorder, None if not recording
+pub fn recording_status() -> Option<RecordingStatus> {
+    RECORDER.lock().as_ref().map(Recorder::status)
+}
+
+/// record - called by the capture distributor with the stereo samples
+pub fn record(samples: &Arc<[f32]>, sample_rate: u32) {
+    if let Some(recorder) = RECORDER.lock().as_ref() {
+        recorder.write(samples, sample_rate);
+    }
+}
+
+/// run_recorder - the recorder thread, ends when the Recorder has been stopped
+/// or when a file can't be written, the Recorder is then removed from its slot (RECORDER)
+fn run_recorder(
+    rx: Receiver<(Arc<[f32]>, u32)>,
+    settings: RecordingSettings,
+    status: Arc<Mutex<RecordingStatus>>,
+    slot: &Mutex<Option<Recorder>>,
+) {
+    let mut file: Option<RecordingFile> = None;
+    let mut silence = Duration::ZERO;
+    for (samples, sample_rate) in rx.iter() {
+        let duration =
+            Duration::from_secs_f64(samples.len() as f64 / 2.0 / sample_rate.max(1) as f64);
+        if samples.iter().all(|s| s.abs() < SILENCE_LEVEL) {
+            silence += duration;
+        } else {
+            silence = Duration::ZERO;
+        }
+        // split on silence: close the file, and skip the silence until the next track
+        if settings.silence_split.is_some_and(|split| silence >= split) {
+            if let Some(f) = file.take() {
+                close_file(f, "silence");
+            }
+            continue;
+        }
+        let reason = file.as_ref().and_then(|f| {
+            if f.sample_rate != sample_rate {
+                Some("new sample rate")
+            } else if settings
+                .rotate_time
+                .is_some_and(|t| f.started.elapsed() >= t)
+            {
+                Some("time rotation")
+            } else if settings.rotate_size.is_some_and(|size| f.bytes >= size) {
+                Some("size rotation")
+            } else {
+                None
+            }
+        });
+        if let Some(reason) = reason {
+            if let Some(f) = file.take() {
+                close_file(f, reason);
+            }
+        }
+        if file.is_none() {
+            match RecordingFile::create(&settings, sample_rate) {
+                Ok(f) => {
+                    ui_log(format!("Recording to {}", f.path.display()));
+                    let mut status = status.lock();
+                    status.file = Some(f.path.clone());
+                    status.bytes = 0;
+                    status.files += 1;
+                    file = Some(f);
+                }
+                Err(e) => {
+                    ui_log(format!("*E*E*> {e}"));
+                    break;
+                }
+            }
+        }
+        if let Some(f) = file.as_mut() {
+            if let Err(e) = f.write(&samples) {
+                ui_log(format!(
+                    "*E*E*> Recording to {} failed: {e}",
+                    f.path.display()
+                ));
+                break;
+            }
+            status.lock().bytes = f.bytes;
+        }
+    }
+    if let Some(f) = file.take() {
+        close_file(f, "recording stopped");
+    }
+    status.lock().file = None;
+    // after an error this is still the active recorder, so that recording_status is None
+    let mut recorder = slot.lock();
+    if recorder
+        .as_ref()
+        .is_some_and(|r| Arc::ptr_eq(&r.status, &status))
+    {
+        *recorder = None;
+        ui_log("Recording stopped".to_string());
+    }
+}
+
+fn close_file(file: RecordingFile, reason: &str) {
+    let path = file.path.clone();
+    match file.close() {
+        Ok(bytes) => ui_log(format!(
+            "Recorded {} ({bytes} bytes, {reason})",
+            path.display()
+        )),
+        Err(e) => ui_log(format!("*E*E*> Unable to close {}: {e}", path.display())),
+    }
+}
+
+/// the encoder of a recording file
+enum FileEncoder {
+    Wav(WavEncoder),
+    // the samples for the FLAC encoder thread and the encoded bytes
+    Flac(FlacChannel, Sender<Arc<[f32]>>, Receiver<Vec<u8>>),
+}
+
+/// RecordingFile - a WAV or FLAC file that is being recorded
+struct RecordingFile {
+    path: PathBuf,
+    out: BufWriter<File>,
+    encoder: FileEncoder,
+    sample_rate: u32,
+    started: Instant,
+    // the bytes written, the header included
+    bytes: u64,
+    // the size of the WAV header
+    header_len: u64,
+}
+
+impl RecordingFile {
+    fn create(settings: &RecordingSettings, sample_rate: u32) -> Result<RecordingFile, String> {
+        let extension = if settings.format == StreamingFormat::Wav {
+            "wav"
+        } else {
+            "flac"
+        };
+        let path = new_file_path(&settings.dir, &utc_timestamp(SystemTime::now()), extension);
+        let file =
+            File::create(&path).map_err(|e| format!("Unable to create {}: {e}", path.display()))?;
+        let mut out = BufWriter::new(file);
+        let (encoder, header) = if settings.format == StreamingFormat::Wav {
+            // the sizes of the classic header are set when the file is closed
+            let wav = WavEncoder::new(
+                sample_rate,
+                settings.bits_per_sample,
+                2,
+                settings.dither,
+                settings.float && settings.bits_per_sample == 32,
+                WavHeader::Classic,
+            );
+            let header = wav.header();
+            (FileEncoder::Wav(wav), header)
+        } else {
+            let flac = FlacChannel::new(
+                sample_rate,
+                settings.bits_per_sample as u32,
+                2,
+                false,
+                settings.dither,
+                settings.flac,
+            );
+            let (tx, rx) = unbounded();
+            // no keep-alive: the recording only has the captured samples
+            let keep_alive = KeepAlive::new(KeepAlivePolicy::None, 0.0, sample_rate, 2, 1000);
+            let Some(encoded) = flac.start(&rx, keep_alive) else {
+                return Err("Unable to start the FLAC encoder".to_string());
+            };
+            (FileEncoder::Flac(flac, tx, encoded), Vec::new())
+        };
+        out.write_all(&header)
+            .map_err(|e| format!("Unable to write {}: {e}", path.display()))?;
+        Ok(RecordingFile {
+            path,
+            out,
+            encoder,
+            sample_rate,
+            started: Instant::now(),
+            bytes: header.len() as u64,
+            header_len: header.len() as u64,
+        })
+    }
+
+    fn write(&mut self, samples: &Arc<[f32]>) -> std::io::Result<()> {
+        match &self.encoder {
+            FileEncoder::Wav(wav) => {
+                let mut bytes = VecDeque::with_capacity(samples.len() * 4);
+                wav.encode(samples, &mut bytes);
+                let (first, second) = bytes.as_slices();
+                self.out.write_all(first)?;
+                self.out.write_all(second)?;
+                self.bytes += bytes.len() as u64;
+            }
+            FileEncoder::Flac(_, tx, encoded) => {
+                let _ = tx.send(samples.clone());
+                for chunk in encoded.try_iter() {
+                    self.out.write_all(&chunk)?;
+                    self.bytes += chunk.len() as u64;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// close - write the rest of the file and return its size
+    fn close(mut self) -> std::io::Result<u64> {
+        match self.encoder {
+            FileEncoder::Wav(_) => {
+                // the RIFF and data sizes, if they fit
+                let data_len = self.bytes - self.header_len;
+                if let (Ok(riff_size), Ok(data_size)) =
+                    (u32::try_from(self.bytes - 8), u32::try_from(data_len))
+                {
+                    self.out.seek(SeekFrom::Start(4))?;
+                    self.out.write_all(&riff_size.to_le_bytes())?;
+                    self.out.seek(SeekFrom::Start(self.header_len - 4))?;
+                    self.out.write_all(&data_size.to_le_bytes())?;
+                }
+            }
+            FileEncoder::Flac(flac, tx, encoded) => {
+                // the encoder thread finishes the stream when the samples end
+                drop(tx);
+                for chunk in encoded.iter() {
+                    self.out.write_all(&chunk)?;
+                    self.bytes += chunk.len() as u64;
+                }
+                flac.stop();
+            }
+        }
+        self.out.flush()?;
+        Ok(self.bytes)
+    }
+}
+
+/// new_file_path - swyh-rs_{timestamp}.{extension} in dir, with a number if it exists
+fn new_file_path(dir: &Path, timestamp: &str, extension: &str) -> PathBuf {
+    let path = dir.join(format!("swyh-rs_{timestamp}.{extension}"));
+    if !path.exists() {
+        return path;
+    }
+    (2..)
+        .map(|n| dir.join(format!("swyh-rs_{timestamp}_{n}.{extension}")))
+        .find(|p| !p.exists())
+        .unwrap_or(path)
+}
+
+/// utc_timestamp - YYYY-MM-DD_HH-MM-SS of the UTC time, for the file names
+fn utc_timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (days, secs) = ((secs / 86400) as i64, secs % 86400);
+    // the civil date of the days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}_{:02}-{:02}-{:02}",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wav_settings(dir: &Path) -> RecordingSettings {
+        RecordingSettings {
+            dir: dir.to_path_buf(),
+            format: StreamingFormat::Wav,
+            bits_per_sample: 16,
+            float: false,
+            dither: Dither::None,
+            flac: FlacSettings::default(),
+            rotate_time: None,
+            rotate_size: None,
+            silence_split: None,
+        }
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("swyh-rs-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn recordings(dir: &Path) -> Vec<Vec<u8>> {
+        let mut files: Vec<PathBuf> = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect();
+        files.sort();
+        files.iter().map(|f| fs::read(f).unwrap()).collect()
+    }
+
+    #[test]
+    fn timestamps() {
+        let time = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        assert_eq!(utc_timestamp(time(0)), "1970-01-01_00-00-00");
+        assert_eq!(utc_timestamp(time(951782400)), "2000-02-29_00-00-00");
+        assert_eq!(utc_timestamp(time(1792332245)), "2026-10-18_14-04-05");
+    }
+
+    #[test]
+    fn wav_recording() {
+        let dir = test_dir("wav");
+        let recorder = Recorder::new(wav_settings(&dir)).unwrap();
+        for _ in 0..10 {
+            recorder.write(&vec![0.5f32; 200].into(), 1000);
+        }
+        recorder.stop();
+        let files = recordings(&dir);
+        assert_eq!(files.len(), 1);
+        let wav = &files[0];
+        assert_eq!(wav.len(), 44 + 10 * 200 * 2);
+        // the sizes have been set
+        assert_eq!(&wav[4..8], &((wav.len() - 8) as u32).to_le_bytes());
+        assert_eq!(&wav[40..44], &(10u32 * 200 * 2).to_le_bytes());
+        assert_eq!(&wav[44..46], &16384i16.to_le_bytes());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn rotation_and_silence_split() {
+        // a new file after 1000 bytes of samples, or after a sample rate change
+        let dir = test_dir("rotation");
+        let settings = RecordingSettings {
+            rotate_size: Some(1000),
+            ..wav_settings(&dir)
+        };
+        let recorder = Recorder::new(settings).unwrap();
+        for _ in 0..6 {
+            recorder.write(&vec![0.5f32; 200].into(), 1000);
+        }
+        recorder.write(&vec![0.5f32; 200].into(), 2000);
+        recorder.stop();
+        let sizes: Vec<usize> = recordings(&dir).iter().map(|f| f.len() - 44).collect();
+        assert_eq!(sizes.iter().sum::<usize>(), 7 * 400);
+        assert_eq!(sizes.len(), 3);
+        let _ = fs::remove_dir_all(&dir);
+        // two tracks with 1.5 seconds of silence in between, split after 1 second
+        let dir = test_dir("silence");
+        let settings = RecordingSettings {
+            silence_split: Some(Duration::from_secs(1)),
+            ..wav_settings(&dir)
+        };
+        let recorder = Recorder::new(settings).unwrap();
+        let tone: Arc<[f32]> = vec![0.5f32; 200].into();
+        let silence: Arc<[f32]> = vec![0f32; 200].into();
+        for block in [&tone, &tone, &silence, &silence, &silence, &tone] {
+            // 100 msecs per block at 1 kHz, so 1.5 seconds of silence takes 15 blocks
+            let repeat = if Arc::ptr_eq(block, &silence) { 5 } else { 1 };
+            for _ in 0..repeat {
+                recorder.write(block, 1000);
+            }
+        }
+        recorder.stop();
+        let files = recordings(&dir);
+        assert_eq!(files.len(), 2);
+        // the first track keeps the silence up to the split, the second one starts with the tone
+        assert_eq!(files[0].len() - 44, 2 * 400 + 9 * 400);
+        assert_eq!(files[1].len() - 44, 400);
+        assert_eq!(&files[1][44..46], &files[0][44..46]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn recorder_removed_after_an_error() {
+        // the recording directory disappears, so the first file can't be created
+        // a slot of its own, RECORDER is shared with the rest of the program
+        let slot: &'static Mutex<Option<Recorder>> = Box::leak(Box::new(Mutex::new(None)));
+        let dir = test_dir("error");
+        let recorder = Recorder::with_slot(wav_settings(&dir), slot).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        *slot.lock() = Some(recorder);
+        if let Some(recorder) = slot.lock().as_ref() {
+            recorder.write(&vec![0.5f32; 200].into(), 1000);
+        }
+        let started = Instant::now();
+        while slot.lock().is_some() && started.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(slot.lock().is_none());
+    }
+}
@@ -0,0 +1,194 @@
+///
+/// resampler.rs
+///
+/// sample rate conversion of the interleaved f32 samples with a rubato sinc resampler
+///
+/// with drift compensation the ratio is adjusted a little by the DriftCompensator
+///
+use crate::utils::drift::DriftCompensator;
+use crossbeam_channel::{bounded, Receiver};
+use rubato::{
+    Resampler as _, SincFixedIn, SincInterpolationParameters, SincInterpolationType, WindowFunction,
+};
+use std::sync::Arc;
+
+/// the number of frames the sinc resampler processes at a time
+const CHUNK_FRAMES: usize = 1024;
+/// the maximum relative ratio change (for drift compensation)
+const MAX_RELATIVE_RATIO: f64 = 1.1;
+/// the number of resampled chunks that can wait for the encoder
+const RESAMPLED_QUEUE_SIZE: usize = 16;
+
+/// Resampler - converts interleaved samples from one sample rate to another
+pub struct Resampler {
+    sinc: SincFixedIn<f32>,
+    channels: usize,
+    // the deinterleaved input samples waiting for a complete chunk
+    input: Vec<Vec<f32>>,
+}
+
+impl Resampler {
+    pub fn new(from_rate: u32, to_rate: u32, channels: usize) -> Result<Resampler, String> {
+        let params = SincInterpolationParameters {
+            sinc_len: 128,
+            f_cutoff: 0.95,
+            oversampling_factor: 128,
+            interpolation: SincInterpolationType::Linear,
+            window: WindowFunction::BlackmanHarris2,
+        };
+        let sinc = SincFixedIn::<f32>::new(
+            to_rate as f64 / from_rate as f64,
+            MAX_RELATIVE_RATIO,
+            params,
+            CHUNK_FRAMES,
+            channels,
+        )
+        .map_err(|e| format!("Unable to resample from {from_rate} to {to_rate} Hz: {e}"))?;
+        Ok(Resampler {
+            sinc,
+            channels,
+            input: vec![Vec::with_capacity(CHUNK_FRAMES * 2); channels],
+        })
+    }
+
+    /// set_relative_ratio - change the ratio relative to to_rate / from_rate,
+    /// gradually over the next chunk
+    pub fn set_relative_ratio(&mut self, ratio: f64) {
+        if let Err(e) = self.sinc.set_resample_ratio_relative(ratio, true) {
+            log::error!("Resampler error {e}");
+        }
+    }
+
+    /// process - resample the interleaved samples, the samples that don't fill a complete
+    /// chunk are kept for the next call, so the output can be empty
+    pub fn process(&mut self, samples: &[f32]) -> Vec<f32> {
+        for frame in samples.chunks_exact(self.channels) {
+            for (ch, sample) in frame.iter().enumerate() {
+                self.input[ch].push(*sample);
+            }
+        }
+        let mut output = Vec::new();
+        while self.input[0].len() >= self.sinc.input_frames_next() {
+            let needed = self.sinc.input_frames_next();
+            let chunk: Vec<&[f32]> = self.input.iter().map(|ch| &ch[..needed]).collect();
+            match self.sinc.process(&chunk, None) {
+                Ok(resampled) => {
+                    let frames = resampled[0].len();
+                    output.reserve(frames * self.channels);
+                    for i in 0..frames {
+                        for ch in resampled.iter() {
+                            output.push(ch[i]);
+                        }
+                    }
+                }
+                Err(e) => log::error!("Resampler error {e}"),
+            }
+            for ch in self.input.iter_mut() {
+                ch.drain(..needed);
+            }
+        }
+        output
+    }
+}
+
+/// resample_channel - resample the stereo samples of a channel in a seperate thread
+///
+/// the thread ends when all the senders of samples_in or the returned receiver are dropped
+///
+/// the output channel is bounded, so that a reader that stops reading also stops the resampler,
+/// and the samples queue up in samples_in. with a DriftCompensator the ratio is adjusted to
+/// keep the number of samples waiting in samples_in near the target
+pub fn resample_channel(
+    samples_in: Receiver<Arc<[f32]>>,
+    from_rate: u32,
+    to_rate: u32,
+    channels: u16,
+    mut drift: Option<DriftCompensator>,
+) -> Result<Receiver<Arc<[f32]>>, String> {
+    let mut resampler = Resampler::new(from_rate, to_rate, channels as usize)?;
+    let (resampled_out, resampled_in) = bounded(RESAMPLED_QUEUE_SIZE);
+    std::thread::Builder::new()
+        .name("resampler".into())
+        .spawn(move || {
+            while let Ok(samples) = samples_in.recv() {
+                if let Some(ratio) = drift
+                    .as_mut()
+                    .and_then(|d| d.update(samples_in.len() * samples.len()))
+                {
+                    resampler.set_relative_ratio(ratio);
+                }
+                let resampled = resampler.process(&samples);
+                if !resampled.is_empty() && resampled_out.send(resampled.into()).is_err() {
+                    break;
+                }
+            }
+        })
+        .map_err(|e| format!("Unable to start the resampler thread: {e}"))?;
+    Ok(resampled_in)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam_channel::unbounded;
+
+    #[test]
+    fn resample_44100_to_48000() {
+        const FREQ: f32 = 1000.0;
+        let mut resampler = Resampler::new(44100, 48000, 2).unwrap();
+        // 1 second of a 1 kHz sine, fed in odd sized pieces
+        let input: Vec<f32> = (0..44100)
+            .flat_map(|n| {
+                let s = (2.0 * std::f32::consts::PI * FREQ * n as f32 / 44100.0).sin() * 0.5;
+                [s, s]
+            })
+            .collect();
+        let mut output = Vec::new();
+        for piece in input.chunks(2 * 441) {
+            output.extend(resampler.process(piece));
+        }
+        let frames = output.len() / 2;
+        // all complete chunks have been resampled
+        assert!(frames > 48000 - 2 * 1200 && frames <= 48000);
+        // still a 1 kHz sine: count the rising zero crossings of the left channel,
+        // skipping the resampler delay
+        let left: Vec<f32> = output.iter().step_by(2).skip(4800).copied().collect();
+        let crossings = left
+            .windows(2)
+            .filter(|w| w[0] < 0.0 && w[1] >= 0.0)
+            .count();
+        let seconds = left.len() as f32 / 48000.0;
+        let freq = crossings as f32 / seconds;
+        assert!((freq - FREQ).abs() < 10.0, "frequency {freq}");
+    }
+
+    #[test]
+    fn relative_ratio() {
+        // drift compensation at the same rate: 0.1% fewer samples
+        let mut resampler = Resampler::new(44100, 44100, 2).unwrap();
+        resampler.set_relative_ratio(0.999);
+        let mut frames = 0;
+        for _ in 0..100 {
+            frames += resampler.process(&[0.25f32; 2 * 4410]).len() / 2;
+        }
+        let expected = 441000.0 * 0.999;
+        assert!(
+            (frames as f64 - expected).abs() < 2.0 * CHUNK_FRAMES as f64,
+            "{frames} frames"
+        );
+        assert!(frames < 441000 - CHUNK_FRAMES);
+    }
+
+    #[test]
+    fn resample_channel_96000_to_44100() {
+        let (tx, rx) = unbounded();
+        let resampled = resample_channel(rx, 96000, 44100, 2, None).unwrap();
+        for _ in 0..100 {
+            tx.send(vec![0.25f32; 2 * 960].into()).unwrap();
+        }
+        drop(tx);
+        let frames: usize = resampled.iter().map(|r| r.len() / 2).sum();
+        // 1 second at 96 kHz, less the samples that don't fill the last chunk
+        assert!(frames > 44100 - 2 * 1024 && frames <= 44100);
+    }
+}
@@ -5,251 +5,516 @@
 /// ChannelStream: the write method sends the received samples on the CrssBeam channel
 /// for the Read trait to read them back
 ///
+/// the channel is a ClientQueue limited to ClientQueueMs of audio, so that a renderer
+/// that stops reading can't make the memory use grow without limit
+///
 /// the Read trait implementation is used by the HTTP response to send the response PCM/L16 stream
-/// to the media Renderer
+/// to the media Renderer, in low latency mode in small pieces as soon as they are encoded
 ///
 */
-use crate::{enums::streaming::StreamingFormat, globals::statics::CONFIG, utils::i24::I24Sample};
-use crossbeam_channel::{Receiver, Sender};
-use dasp_sample::Sample;
-use log::debug;
-use rand::{distributions::Uniform, rngs::StdRng, Rng, SeedableRng};
+use crate::{
+    enums::streaming::{OverflowPolicy, StreamingFormat},
+    globals::statics::CONFIG,
+    utils::{
+        drift::{DriftCompensator, DriftStats},
+        icymeta::IcyMetaData,
+        keepalive::KeepAlive,
+        preroll::PreRoll,
+        resampler::resample_channel,
+        streamencoder::StreamEncoder,
+        ui_logger::ui_log,
+    },
+};
+use crossbeam_channel::{unbounded, Receiver, Sender};
 use std::{
     collections::VecDeque,
-    io::{Read, Result as IoResult},
-    time::Duration,
+    io::{Error as IoError, ErrorKind, Read, Result as IoResult},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering::Relaxed},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
-use super::flacstream::FlacChannel;
+/// the largest read in low latency mode
+const LOW_LATENCY_CHUNK: usize = 4096;
+
+/// ClientQueue - the queue of the samples for a client, shared by the clones of its ChannelStream
+///
+/// the limit is the number of samples in ClientQueueMs of audio. when the queue is full the
+/// oldest samples are dropped or the client is disconnected, depending on the OverflowPolicy
+struct ClientQueue {
+    tx: Sender<Arc<[f32]>>,
+    // the receiving end of tx, to drop the oldest samples
+    oldest: Receiver<Arc<[f32]>>,
+    remote_ip: String,
+    capacity: usize,
+    policy: OverflowPolicy,
+    // None disables the stall detection
+    stall_timeout: Option<Duration>,
+    created: Instant,
+    // msecs after created
+    last_read: AtomicU64,
+    // the number of samples dropped since the queue became full
+    dropped: AtomicU64,
+    // the size of the last block
+    block_len: AtomicU64,
+    disconnected: AtomicBool,
+}
+
+impl ClientQueue {
+    fn new(
+        remote_ip: String,
+        capacity: usize,
+        policy: OverflowPolicy,
+        stall_timeout: Option<Duration>,
+    ) -> ClientQueue {
+        let (tx, oldest) = unbounded();
+        ClientQueue {
+            tx,
+            oldest,
+            remote_ip,
+            capacity: capacity.max(1),
+            policy,
+            stall_timeout,
+            created: Instant::now(),
+            last_read: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            block_len: AtomicU64::new(0),
+            disconnected: AtomicBool::new(false),
+        }
+    }
+
+    /// the samples that are waiting, estimated with the size of the new samples
+    fn queued(&self, samples: usize) -> usize {
+        self.tx.len() * samples
+    }
+
+    /// the samples that are waiting, estimated with the size of the last block
+    fn queued_now(&self) -> usize {
+        self.queued(self.block_len.load(Relaxed) as usize)
+    }
+
+    /// push - queue the samples, or apply the OverflowPolicy if the queue is full
+    fn push(&self, samples: &Arc<[f32]>) {
+        if self.disconnected.load(Relaxed) {
+            return;
+        }
+        self.block_len.store(samples.len() as u64, Relaxed);
+        if self.queued(samples.len()) + samples.len() <= self.capacity {
+            let dropped = self.dropped.swap(0, Relaxed);
+            if dropped > 0 {
+                ui_log(format!(
+                    "The queue of {} has recovered after dropping {dropped} samples",
+                    self.remote_ip
+                ));
+            }
+        } else {
+            match self.policy {
+                OverflowPolicy::DropOldest => {
+                    while self.queued(samples.len()) + samples.len() > self.capacity {
+                        match self.oldest.try_recv() {
+                            Ok(oldest) => {
+                                if self.dropped.fetch_add(oldest.len() as u64, Relaxed) == 0 {
+                                    ui_log(format!(
+                                        "*W*W*> The queue of {} is full, dropping the oldest samples",
+                                        self.remote_ip
+                                    ));
+                                }
+                            }
+                            Err(_) => break,
+                        }
+                    }
+                }
+                OverflowPolicy::Disconnect => {
+                    ui_log(format!(
+                        "*W*W*> The queue of {} is full, disconnecting",
+                        self.remote_ip
+                    ));
+                    self.disconnected.store(true, Relaxed);
+                    return;
+                }
+            }
+        }
+        // the queue keeps a receiver, so the channel is never disconnected
+        let _ = self.tx.send(samples.clone());
+    }
 
-/// Channelstream - used to transport the f32 samples from the wave_reader
-/// to the http output stream in LPCM/WAV/FLAC format
+    /// mark_read - the client has read the stream
+    fn mark_read(&self) {
+        self.last_read
+            .store(self.created.elapsed().as_millis() as u64, Relaxed);
+    }
+
+    /// is_stalled - samples are waiting but the client hasn't read anything for the stall timeout
+    fn is_stalled(&self) -> bool {
+        self.stall_timeout.is_some_and(|timeout| {
+            let idle = self
+                .created
+                .elapsed()
+                .saturating_sub(Duration::from_millis(self.last_read.load(Relaxed)));
+            !self.tx.is_empty() && idle > timeout
+        })
+    }
+}
+
+/// Channelstream - used to transport the f32 samples from the capture distributor
+/// to the http output stream, encoded by the StreamEncoder of the streaming format
 #[derive(Clone)]
 pub struct ChannelStream {
-    pub s: Sender<Vec<f32>>,
-    pub r: Receiver<Vec<f32>>,
+    queue: Arc<ClientQueue>,
+    pub r: Receiver<Arc<[f32]>>,
     pub remote_ip: String,
     pub streaming_format: StreamingFormat,
-    fifo: VecDeque<f32>,
-    flac_fifo: VecDeque<u8>,
-    silence: Vec<f32>,
-    capture_timeout: Duration,
-    sending_silence: bool,
-    wav_hdr: Vec<u8>,
-    use_wave_format: bool,
+    encoder: Arc<dyn StreamEncoder>,
+    encoded_in: Option<Receiver<Vec<u8>>>,
+    encoded_fifo: VecDeque<u8>,
+    keep_alive: KeepAlive,
     bits_per_sample: u16,
-    flac_channel: Option<FlacChannel>,
+    channels: u16,
+    capture_rate: u32,
+    drift: Option<Arc<DriftStats>>,
+    pre_roll_ms: u32,
+    low_latency: bool,
+    bytes_sent: Arc<AtomicU64>,
+    icy: Option<IcyMetaData>,
+    icy_fifo: VecDeque<u8>,
 }
 
 impl ChannelStream {
+    /// new - the stream of the samples captured at capture_rate, resampled to sample_rate
+    /// if the rates differ
     pub fn new(
-        tx: Sender<Vec<f32>>,
-        rx: Receiver<Vec<f32>>,
         remote_ip_addr: String,
-        use_wave_format: bool,
+        capture_rate: u32,
         sample_rate: u32,
         bits_per_sample: u16,
-        streaming_format: StreamingFormat,
-    ) -> ChannelStream {
-        let flac_channel = if streaming_format == StreamingFormat::Flac {
-            Some(FlacChannel::new(
-                rx.clone(),
+        channels: u16,
+        encoder: Arc<dyn StreamEncoder>,
+    ) -> Result<ChannelStream, String> {
+        let (keep_alive, queue, pre_roll_ms, low_latency, drift) = {
+            let conf = CONFIG.read();
+            let keep_alive = KeepAlive::new(
+                conf.keep_alive_policy(&remote_ip_addr),
+                conf.keep_alive_noise_level.unwrap_or(-60.0),
                 sample_rate,
-                bits_per_sample as u32,
-                2,
-            ))
+                channels,
+                conf.capture_timeout.unwrap_or(2000) as u64,
+            );
+            let queue_ms = conf.client_queue_ms.unwrap_or(2000) as u64;
+            let queue = ClientQueue::new(
+                remote_ip_addr.clone(),
+                (capture_rate as u64 * channels as u64 * queue_ms / 1000) as usize,
+                conf.overflow_policy.unwrap_or(OverflowPolicy::DropOldest),
+                match conf.stall_timeout.unwrap_or(0) {
+                    0 => None,
+                    secs => Some(Duration::from_secs(secs as u64)),
+                },
+            );
+            // drift compensation needs a resampler, even at the capture rate
+            let drift = conf
+                .drift_compensation
+                .unwrap_or(false)
+                .then(|| DriftCompensator::new(capture_rate, channels, conf.drift_target_ms()));
+            (
+                keep_alive,
+                queue,
+                conf.pre_roll_ms(&remote_ip_addr),
+                conf.low_latency(&remote_ip_addr),
+                drift,
+            )
+        };
+        let drift_stats = drift.as_ref().map(DriftCompensator::stats);
+        let rx = if sample_rate != capture_rate || drift.is_some() {
+            resample_channel(
+                queue.oldest.clone(),
+                capture_rate,
+                sample_rate,
+                channels,
+                drift,
+            )?
         } else {
-            None
+            queue.oldest.clone()
         };
-        let capture_timout = CONFIG.read().capture_timeout.unwrap() as u64;
-        let chs = ChannelStream {
-            s: tx,
+        // encoders with their own thread are started now
+        let encoded_in = encoder.start(&rx, keep_alive.clone());
+        let mut encoded_fifo = VecDeque::with_capacity(16384);
+        encoded_fifo.extend(encoder.header());
+        Ok(ChannelStream {
+            queue: Arc::new(queue),
             r: rx,
-            fifo: VecDeque::with_capacity(16384),
-            flac_fifo: VecDeque::with_capacity(16384),
-            silence: get_silence_buffer(sample_rate, capture_timout / 4),
-            capture_timeout: Duration::from_millis(capture_timout), // silence kicks in after CAPTURE_TIMEOUT seconds
-            sending_silence: false,
+            encoded_fifo,
+            keep_alive,
             remote_ip: remote_ip_addr,
-            wav_hdr: if !use_wave_format {
-                Vec::new()
-            } else {
-                create_wav_hdr(sample_rate, bits_per_sample)
-            },
-            use_wave_format,
             bits_per_sample,
-            streaming_format,
-            flac_channel,
-        };
-        if chs.streaming_format == StreamingFormat::Flac {
-            chs.start_flac_encoder();
-        }
-        chs
+            channels,
+            capture_rate,
+            drift: drift_stats,
+            pre_roll_ms,
+            low_latency,
+            streaming_format: encoder.format(),
+            encoder,
+            encoded_in,
+            bytes_sent: Arc::new(AtomicU64::new(0)),
+            icy: None,
+            icy_fifo: VecDeque::new(),
+        })
+    }
+
+    // stop the encoder thread, if any
+    pub fn stop_encoder(&self) {
+        self.encoder.stop();
+    }
+
+    // called by the capture distributor to write the f32 samples to the input channel,
+    // the block is shared with the other clients
+    pub fn write(&self, samples: &Arc<[f32]>) {
+        self.queue.push(samples);
     }
 
-    // the flac encoder runs in a seperate thread
-    fn start_flac_encoder(&self) {
-        if let Some(flac_channel) = &self.flac_channel {
-            flac_channel.run();
+    /// write_pre_roll - write the PreRollMs of recently captured audio, if any,
+    /// and return the msecs written
+    ///
+    /// called with the CLIENTS write lock held, see preroll.rs
+    pub fn write_pre_roll(&self, pre_roll: &PreRoll) -> u32 {
+        if self.pre_roll_ms == 0 {
+            return 0;
         }
+        let mut samples = 0;
+        for (stereo, captured) in pre_roll.last(self.pre_roll_ms) {
+            let block = if self.channels == 2 { stereo } else { captured };
+            samples += block.len();
+            self.write(block);
+        }
+        let frames = samples / self.channels as usize;
+        match pre_roll.sample_rate() {
+            0 => 0,
+            rate => (frames as u64 * 1000 / rate as u64) as u32,
+        }
+    }
+
+    /// true when the encoded samples are sent as soon as they are available
+    pub fn is_low_latency(&self) -> bool {
+        self.low_latency
+    }
+
+    /// disconnect - end the stream, the next read returns an error
+    pub fn disconnect(&self) {
+        self.queue.disconnected.store(true, Relaxed);
+    }
+
+    /// true when the stream was ended by disconnect or by a queue overflow
+    pub fn is_disconnected(&self) -> bool {
+        self.queue.disconnected.load(Relaxed)
     }
 
-    // stop the flac encoder thread
-    pub fn stop_flac_encoder(&self) {
-        if let Some(flac_channel) = &self.flac_channel {
-            flac_channel.stop();
+    /// true when the client hasn't read the queued samples for StallTimeout seconds
+    pub fn is_stalled(&self) -> bool {
+        self.queue.is_stalled()
+    }
+
+    /// the msecs of captured audio waiting in the queue of the client
+    pub fn buffer_ms(&self) -> f32 {
+        match &self.drift {
+            Some(drift) => drift.fill_ms(),
+            None => {
+                let frames = self.queue.queued_now() / self.channels as usize;
+                frames as f32 * 1000.0 / self.capture_rate as f32
+            }
         }
     }
 
-    // called by the wave_reader to write the f32 samples to the input channel
-    pub fn write(&self, samples: &[f32]) {
-        self.s.send(samples.to_vec()).unwrap();
+    /// the estimated clock drift of the renderer in ppm, with drift compensation
+    pub fn drift_ppm(&self) -> Option<f32> {
+        self.drift.as_ref().map(|drift| drift.drift_ppm())
+    }
+
+    /// the number of bytes sent to the client so far
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Relaxed)
+    }
+
+    pub fn bits_per_sample(&self) -> u16 {
+        self.bits_per_sample
+    }
+
+    /// the number of channels of the samples written to the stream
+    pub fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    /// insert ICY StreamTitle metadata blocks in the stream, for clients that sent "Icy-MetaData: 1"
+    pub fn enable_icy_metadata(&mut self) {
+        self.icy = Some(IcyMetaData::new(self.streaming_format.to_string()));
     }
 }
 
-/// implement the Read trait for the HTTP writer
+/// read_audio - read the audio stream for the HTTP writer
 ///
-/// for LPCM/WAV the f32 samples are read from the f32 input channel and pushed
-/// on the fifo VecDeque that is then read for conversion to LPCM and transmission
+/// for encoders with their own thread (FLAC/MP3/Ogg) the f32 samples have already been
+/// encoded and written to the output channel of the encoder.
+/// that channel is read here and pushed on the encoded_fifo VecDeque for transmission
 ///
-/// for FLAC the f32 samples have already been encoded to FLAC and written to the
-/// flac_out channel of the FlacChannel encoder.
-/// the flac_in channel of the FlacChannel is read here and pushed on the flac_fifo VecDeque
-/// for transmission  
-impl Read for ChannelStream {
-    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
-        if self.flac_channel.is_none() {
-            // naked LPCM or WAV LPCM
-            let time_out = self.capture_timeout;
-            let mut i = 0;
-            if self.use_wave_format && !self.wav_hdr.is_empty() {
-                i = self.wav_hdr.len();
-                buf[..i].copy_from_slice(&self.wav_hdr);
-                self.wav_hdr.clear();
-            }
-            let bytes_per_sample = (self.bits_per_sample / 8) as usize;
-            while i < buf.len() - bytes_per_sample {
-                if let Some(f32sample) = self.fifo.pop_front() {
-                    if self.bits_per_sample == 16 {
-                        //let sample: i16 = f32sample.to_i16();
-                        let sample = i16::from_sample(f32sample);
-                        let b = if self.use_wave_format {
-                            sample.to_le_bytes()
-                        } else {
-                            sample.to_be_bytes()
-                        };
-                        buf[i] = b[0];
-                        buf[i + 1] = b[1];
-                        i += 2;
-                    } else {
-                        let sample = f32sample.to_i24();
-                        if self.use_wave_format {
-                            buf[i] = sample.b3;
-                            buf[i + 1] = sample.b2;
-                            buf[i + 2] = sample.b1;
-                        } else {
-                            buf[i] = sample.b1;
-                            buf[i + 1] = sample.b2;
-                            buf[i + 2] = sample.b3;
-                        }
-                        i += 3;
+/// for the other encoders (LPCM/WAV) the f32 samples are read from the f32 input channel
+/// and converted by the encoder to the encoded_fifo VecDeque
+///
+/// in both cases the KeepAlive supplies the samples when nothing is captured
+///
+/// in low latency mode a read returns at most LOW_LATENCY_CHUNK bytes, and doesn't wait
+/// for more samples once it has something to send
+///
+/// an encoder thread that ends (because of an encoding error) ends the stream with an io::Error,
+/// so that the HTTP connection is closed and the client is removed
+///
+/// the stream header is the start of the encoded_fifo, so a read buffer that is
+/// smaller than the header simply gets the rest of it with the next read
+impl ChannelStream {
+    fn read_audio(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        if self.is_disconnected() {
+            return Err(IoError::new(
+                ErrorKind::BrokenPipe,
+                format!("{} has been disconnected", self.remote_ip),
+            ));
+        }
+        let len = if self.low_latency {
+            buf.len().min(LOW_LATENCY_CHUNK)
+        } else {
+            buf.len()
+        };
+        let mut i: usize = 0;
+        while i < len {
+            if !self.encoded_fifo.is_empty() {
+                i += drain_fifo(&mut self.encoded_fifo, &mut buf[i..len]);
+            } else if self.low_latency && i > 0 {
+                break;
+            } else if let Some(encoded_in) = &self.encoded_in {
+                match encoded_in.recv() {
+                    Ok(chunk) => self.encoded_fifo.extend(chunk),
+                    // the encoder thread ended, send what we have and then end the stream
+                    Err(_) if i > 0 => break,
+                    Err(_) => {
+                        return Err(IoError::new(
+                            ErrorKind::BrokenPipe,
+                            format!("the {} encoder has stopped", self.streaming_format),
+                        ))
                     }
-                } else if let Ok(chunk) = self.r.recv_timeout(time_out) {
-                    self.fifo.extend(chunk);
-                    self.sending_silence = false;
-                } else {
-                    self.fifo.extend(self.silence.clone());
-                    self.sending_silence = true;
                 }
-            }
-            Ok(i)
-        } else {
-            // FLAC
-            let flac_in = self.flac_channel.as_ref().unwrap().flac_in.clone();
-            let mut i: usize = 0;
-            while i < buf.len() {
-                if let Some(flacbyte) = self.flac_fifo.pop_front() {
-                    buf[i] = flacbyte;
-                    i += 1;
-                } else if let Ok(chunk) = flac_in.recv() {
-                    self.flac_fifo.extend(chunk);
+            } else {
+                match self.keep_alive.recv(&self.r) {
+                    Ok(chunk) => self.encoder.encode(&chunk, &mut self.encoded_fifo),
+                    // no keep-alive: send what we have, or wait for the next samples
+                    // unless the client has been disconnected in the meantime
+                    Err(_) if i > 0 => break,
+                    Err(_) if self.is_disconnected() => {
+                        return Err(IoError::new(
+                            ErrorKind::BrokenPipe,
+                            format!("{} has been disconnected", self.remote_ip),
+                        ))
+                    }
+                    Err(_) => {}
                 }
             }
-            Ok(i)
         }
+        self.bytes_sent.fetch_add(i as u64, Relaxed);
+        self.queue.mark_read();
+        Ok(i)
     }
 }
 
-// create an "infinite size" wav hdr
-// note this may not work when streaming to a "libsndfile" based renderer
-// as libsndfile insists on a seekable WAV file depending on the open mode used
-fn create_wav_hdr(sample_rate: u32, bits_per_sample: u16) -> Vec<u8> {
-    let mut hdr = [0u8; 44];
-    let channels: u16 = 2;
-    let bytes_per_sample: u16 = bits_per_sample / 8;
-    let block_align: u16 = channels * bytes_per_sample;
-    let byte_rate: u32 = sample_rate * block_align as u32;
-    hdr[0..4].copy_from_slice(b"RIFF"); //ChunkId, little endian WAV
-    let subchunksize: u32 = std::u32::MAX; // "infinite" data chunksize signal value
-    let chunksize: u32 = subchunksize; // "infinite" RIFF chunksize signal value
-    hdr[4..8].copy_from_slice(&chunksize.to_le_bytes()); // ChunkSize
-    hdr[8..12].copy_from_slice(b"WAVE"); // File Format
-    hdr[12..16].copy_from_slice(b"fmt "); // SubChunk = Format
-    hdr[16..20].copy_from_slice(&16u32.to_le_bytes()); // SubChunk1Size for PCM
-    hdr[20..22].copy_from_slice(&1u16.to_le_bytes()); // AudioFormat: uncompressed PCM
-    hdr[22..24].copy_from_slice(&channels.to_le_bytes()); // numchannels 2
-    hdr[24..28].copy_from_slice(&sample_rate.to_le_bytes()); // SampleRate
-    hdr[28..32].copy_from_slice(&byte_rate.to_le_bytes()); // ByteRate (Bps)
-    hdr[32..34].copy_from_slice(&block_align.to_le_bytes()); // BlockAlign
-    hdr[34..36].copy_from_slice(&bits_per_sample.to_le_bytes()); // BitsPerSample
-    hdr[36..40].copy_from_slice(b"data"); // SubChunk2Id
-    hdr[40..44].copy_from_slice(&subchunksize.to_le_bytes()); // SubChunk2Size
-    debug!("WAV Header (l={}): \r\n{:02x?}", hdr.len(), hdr);
-    hdr.to_vec()
-}
-
-//#[allow(dead_code)]
-fn get_silence_buffer(sample_rate: u32, silence_period: u64) -> Vec<f32> {
-    // silence_period is in msecs (capture_timeout / 4), sample rate is per second, 2 channels for stereo
-    let size = ((sample_rate * 2 * silence_period as u32) / 1000) as usize;
-    let mut silence = Vec::with_capacity(size);
-    silence.resize(size, 0f32);
-    silence
+/// the HTTP writer reads the audio stream, with the ICY metadata blocks if they were requested
+impl Read for ChannelStream {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        if self.icy.is_none() {
+            return self.read_audio(buf);
+        }
+        if self.icy_fifo.is_empty() {
+            let mut audio = vec![0u8; buf.len().max(1024)];
+            let n = self.read_audio(&mut audio)?;
+            if let Some(icy) = self.icy.as_mut() {
+                icy.interleave(&audio[..n], &mut self.icy_fifo);
+            }
+        }
+        let n = buf.len().min(self.icy_fifo.len());
+        for (b, v) in buf.iter_mut().zip(self.icy_fifo.drain(..n)) {
+            *b = v;
+        }
+        Ok(n)
+    }
 }
 
-///
-/// fille the pre-allocated noise buffer with a very faint white noise (-60db)
-///
-#[allow(dead_code)]
-fn get_noise_buffer(sample_rate: u32, silence_period: u64) -> Vec<f32> {
-    // create the random generator for the white noise
-    let mut rng = StdRng::seed_from_u64(79);
-    let size = ((sample_rate * 2 * silence_period as u32) / 1000) as usize;
-    let mut noise = Vec::with_capacity(size);
-    noise.resize(size, 0.0);
-    let amplitude: f32 = 0.001;
-    for sample in noise.iter_mut() {
-        *sample = ((rng.sample(Uniform::new(0.0, 1.0)) * 2.0) - 1.0) * amplitude;
-    }
-    noise
+/// drain_fifo - move as many bytes as fit from the fifo to buf
+fn drain_fifo(fifo: &mut VecDeque<u8>, buf: &mut [u8]) -> usize {
+    let n = buf.len().min(fifo.len());
+    for (b, v) in buf[..n].iter_mut().zip(fifo.drain(..n)) {
+        *b = v;
+    }
+    n
 }
 
 #[cfg(test)]
 mod tests {
     use crate::utils::rwstream::*;
     #[test]
+    fn small_reads() {
+        // a header that is larger than the read buffer is sent in pieces
+        let header: Vec<u8> = (0..104).collect();
+        let mut fifo: VecDeque<u8> = header.iter().copied().collect();
+        let mut received = Vec::new();
+        let mut buf = [0u8; 10];
+        while !fifo.is_empty() {
+            let n = drain_fifo(&mut fifo, &mut buf);
+            assert!(n > 0 && n <= buf.len());
+            received.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(received, header);
+        assert_eq!(drain_fifo(&mut fifo, &mut buf), 0);
+    }
 
-    fn test_wav_hdr() {
-        let _hdr = create_wav_hdr(44100, 24);
-        //eprintln!("WAV Header (l={}): \r\n{:02x?}", hdr.len(), hdr);
-        let _hdr = create_wav_hdr(44100, 16);
-        //eprintln!("WAV Header (l={}): \r\n{:02x?}", hdr.len(), hdr);
+    #[test]
+    fn queue_overflow() {
+        // drop oldest: the queue holds the newest samples up to the capacity
+        let queue = ClientQueue::new("drop".to_string(), 1000, OverflowPolicy::DropOldest, None);
+        for n in 0..10 {
+            queue.push(&vec![n as f32; 200].into());
+        }
+        assert!(!queue.disconnected.load(Relaxed));
+        let queued: Vec<f32> = queue.oldest.try_iter().map(|s| s[0]).collect();
+        assert_eq!(queued, vec![5.0, 6.0, 7.0, 8.0, 9.0]);
+        assert_eq!(queue.dropped.load(Relaxed), 5 * 200);
+        // the next push that fits ends the overflow
+        queue.push(&vec![0.0; 200].into());
+        assert_eq!(queue.dropped.load(Relaxed), 0);
+        // disconnect: nothing is queued after the overflow
+        let queue = ClientQueue::new("disc".to_string(), 1000, OverflowPolicy::Disconnect, None);
+        for n in 0..10 {
+            queue.push(&vec![n as f32; 200].into());
+        }
+        assert!(queue.disconnected.load(Relaxed));
+        assert_eq!(queue.oldest.len(), 5);
     }
 
     #[test]
-    fn test_silence() {
-        const SAMPLE_RATE: u32 = 44100;
-        let sb = get_silence_buffer(SAMPLE_RATE, 250);
-        assert_eq!(sb.len(), ((SAMPLE_RATE * 2) as u64 / (1000 / 250)) as usize);
+    fn stall_detection() {
+        let queue = ClientQueue::new(
+            "stall".to_string(),
+            1000,
+            OverflowPolicy::DropOldest,
+            Some(Duration::from_millis(50)),
+        );
+        // nothing waiting is not a stall
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(!queue.is_stalled());
+        // samples waiting and no read
+        queue.push(&vec![0.0; 200].into());
+        assert!(queue.is_stalled());
+        queue.mark_read();
+        assert!(!queue.is_stalled());
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(queue.is_stalled());
+        // no stall timeout
+        let queue = ClientQueue::new("stall".to_string(), 1000, OverflowPolicy::DropOldest, None);
+        queue.push(&vec![0.0; 200].into());
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(!queue.is_stalled());
     }
 }
@@ -0,0 +1,517 @@
+///
+/// streamencoder.rs
+///
+/// StreamEncoder: everything that is specific to a streaming format
+///
+/// the encoder owns the stream header, the sample conversion, the MIME type,
+/// the DLNA protocolinfo and the encoder thread if the format needs one,
+/// so that a new format only needs a new StreamEncoder implementation
+///
+use crate::{
+    enums::streaming::{Dither, StreamingFormat, WavHeader},
+    globals::statics::CONFIG,
+    utils::{
+        flacstream::{
+            flac_bits_per_sample, FlacChannel, FlacSettings, FLAC_PROT_INFO, OGG_PROT_INFO,
+        },
+        keepalive::KeepAlive,
+        mp3stream::{Mp3Channel, MP3_PROT_INFO},
+        opusstream::OpusChannel,
+        pcmconvert::{PcmConverter, PcmFormat},
+        wavheader::{create_wav_hdr, WAVE_FORMAT_IEEE_FLOAT, WAVE_FORMAT_PCM},
+    },
+};
+use crossbeam_channel::Receiver;
+use std::{collections::VecDeque, sync::Arc};
+
+/// the number of encoded messages (FLAC frames, MP3 frames, Ogg pages) that can wait for
+/// the HTTP writer, the encoder thread blocks when the writer doesn't keep up, so that
+/// the samples queue up in the ClientQueue of the ChannelStream
+pub const ENCODED_QUEUE_SIZE: usize = 64;
+
+static WAV_PROT_INFO: &str = "http-get:*:audio/wav:DLNA.ORG_PN=WAV;DLNA.ORG_OP=01;DLNA.ORG_CI=0;DLNA.ORG_FLAGS=03700000000000000000000000000000";
+
+pub trait StreamEncoder: Send + Sync {
+    /// the streaming format of this encoder
+    fn format(&self) -> StreamingFormat;
+
+    /// the HTTP Content-Type
+    fn mime_type(&self) -> String;
+
+    /// the DIDL protocolinfo
+    fn protocol_info(&self) -> String;
+
+    /// the sample size of the stream, for the DIDL metadata
+    fn bits_per_sample(&self) -> u16;
+
+    /// the contentFeatures.dlna.org header value
+    ///
+    /// this is the 4th field of the didl protocolinfo, so that the HTTP headers
+    /// always match what was announced to the renderer in the DIDL metadata
+    fn content_features(&self) -> String {
+        self.protocol_info()
+            .splitn(4, ':')
+            .nth(3)
+            .unwrap_or("*")
+            .to_string()
+    }
+
+    /// a short description for the log
+    fn description(&self) -> String;
+
+    /// the header that is sent before the audio data, if any
+    fn header(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// start the encoder thread reading the samples_in channel with the keep_alive,
+    /// and return the channel with the encoded data
+    ///
+    /// encoders without a thread return None, the samples are then converted
+    /// by `encode` when the HTTP writer reads the stream
+    fn start(
+        &self,
+        _samples_in: &Receiver<Arc<[f32]>>,
+        _keep_alive: KeepAlive,
+    ) -> Option<Receiver<Vec<u8>>> {
+        None
+    }
+
+    /// stop the encoder thread, if any
+    fn stop(&self) {}
+
+    /// convert the f32 samples and append them to out (encoders without a thread)
+    ///
+    /// the block is shared by all the clients, so the encoders can share its conversion
+    fn encode(&self, _samples: &Arc<[f32]>, _out: &mut VecDeque<u8>) {}
+}
+
+/// new_encoder - the StreamEncoder for a streaming format
+///
+/// MP3 and Opus are always stereo, the configured dither is used for 16 bit LPCM, WAV and FLAC
+///
+/// 32 bit is integer or (WAV only) float, LPCM has no 32 bit MIME type and uses 24 bit
+pub fn new_encoder(
+    format: StreamingFormat,
+    sample_rate: u32,
+    bits_per_sample: u16,
+    channels: u16,
+) -> Arc<dyn StreamEncoder> {
+    let (dither, float, wav_header, flac_settings) = {
+        let conf = CONFIG.read();
+        (
+            conf.dither.unwrap_or(Dither::None),
+            conf.float_samples.unwrap_or(false),
+            conf.wav_header.unwrap_or(WavHeader::Classic),
+            FlacSettings::from_config(&conf),
+        )
+    };
+    match format {
+        StreamingFormat::Lpcm => Arc::new(LpcmEncoder::new(
+            sample_rate,
+            bits_per_sample.min(24),
+            channels,
+            dither,
+        )),
+        StreamingFormat::Wav => Arc::new(WavEncoder::new(
+            sample_rate,
+            bits_per_sample,
+            channels,
+            dither,
+            float && bits_per_sample == 32,
+            wav_header,
+        )),
+        StreamingFormat::Flac | StreamingFormat::OggFlac => Arc::new(FlacChannel::new(
+            sample_rate,
+            bits_per_sample as u32,
+            channels as u32,
+            format == StreamingFormat::OggFlac,
+            dither,
+            flac_settings,
+        )),
+        StreamingFormat::Mp3 => {
+            let (bitrate, vbr) = {
+                let conf = CONFIG.read();
+                (
+                    conf.mp3_bitrate.unwrap_or(320),
+                    conf.mp3_vbr.unwrap_or(false),
+                )
+            };
+            Arc::new(Mp3Channel::new(sample_rate, bitrate, vbr))
+        }
+        StreamingFormat::Opus => {
+            let bitrate = CONFIG.read().opus_bitrate.unwrap_or(128);
+            Arc::new(OpusChannel::new(sample_rate, bitrate))
+        }
+    }
+}
+
+/// stream_protocol_info - the DIDL protocolinfo and the sample size of the stream
+/// that new_encoder returns for the same arguments, without creating the encoder
+pub fn stream_protocol_info(
+    format: StreamingFormat,
+    sample_rate: u32,
+    bits_per_sample: u16,
+    channels: u16,
+) -> (String, u16) {
+    match format {
+        StreamingFormat::Lpcm => {
+            let bits_per_sample = bits_per_sample.min(24);
+            (
+                lpcm_protocol_info(sample_rate, bits_per_sample, channels),
+                bits_per_sample,
+            )
+        }
+        StreamingFormat::Wav => (WAV_PROT_INFO.to_string(), bits_per_sample),
+        StreamingFormat::Flac => (
+            FLAC_PROT_INFO.to_string(),
+            flac_bits_per_sample(bits_per_sample as u32) as u16,
+        ),
+        StreamingFormat::OggFlac => (
+            OGG_PROT_INFO.to_string(),
+            flac_bits_per_sample(bits_per_sample as u32) as u16,
+        ),
+        StreamingFormat::Mp3 => (MP3_PROT_INFO.to_string(), 16),
+        StreamingFormat::Opus => (OGG_PROT_INFO.to_string(), 16),
+    }
+}
+
+fn lpcm_protocol_info(sample_rate: u32, bits_per_sample: u16, channels: u16) -> String {
+    format!("http-get:*:audio/L{bits_per_sample};rate={sample_rate};channels={channels}:DLNA.ORG_PN=LPCM")
+}
+
+/// LpcmEncoder - naked big endian LPCM (audio/L16 or audio/L24)
+pub struct LpcmEncoder {
+    sample_rate: u32,
+    bits_per_sample: u16,
+    channels: u16,
+    converter: Arc<PcmConverter>,
+}
+
+impl LpcmEncoder {
+    pub fn new(
+        sample_rate: u32,
+        bits_per_sample: u16,
+        channels: u16,
+        dither: Dither,
+    ) -> LpcmEncoder {
+        let format = if bits_per_sample == 16 {
+            PcmFormat::I16Be
+        } else {
+            PcmFormat::I24Be
+        };
+        LpcmEncoder {
+            sample_rate,
+            bits_per_sample,
+            channels,
+            converter: PcmConverter::shared(format, channels, dither),
+        }
+    }
+}
+
+impl StreamEncoder for LpcmEncoder {
+    fn format(&self) -> StreamingFormat {
+        StreamingFormat::Lpcm
+    }
+
+    fn mime_type(&self) -> String {
+        format!(
+            "audio/L{};rate={};channels={}",
+            self.bits_per_sample, self.sample_rate, self.channels
+        )
+    }
+
+    fn protocol_info(&self) -> String {
+        lpcm_protocol_info(self.sample_rate, self.bits_per_sample, self.channels)
+    }
+
+    fn bits_per_sample(&self) -> u16 {
+        self.bits_per_sample
+    }
+
+    fn description(&self) -> String {
+        format!("audio/L{} (LPCM)", self.bits_per_sample)
+    }
+
+    fn encode(&self, samples: &Arc<[f32]>, out: &mut VecDeque<u8>) {
+        out.extend(self.converter.convert(samples).iter());
+    }
+}
+
+/// WavEncoder - little endian LPCM with an "infinite size" WAV, RF64 or Wave64 header
+pub struct WavEncoder {
+    sample_rate: u32,
+    bits_per_sample: u16,
+    channels: u16,
+    converter: Arc<PcmConverter>,
+    // 32 bit IEEE float samples instead of integers
+    float: bool,
+    header: WavHeader,
+}
+
+impl WavEncoder {
+    pub fn new(
+        sample_rate: u32,
+        bits_per_sample: u16,
+        channels: u16,
+        dither: Dither,
+        float: bool,
+        header: WavHeader,
+    ) -> WavEncoder {
+        let format = match bits_per_sample {
+            16 => PcmFormat::I16Le,
+            24 => PcmFormat::I24Le,
+            _ if float => PcmFormat::F32Le,
+            _ => PcmFormat::I32Le,
+        };
+        WavEncoder {
+            sample_rate,
+            bits_per_sample,
+            channels,
+            converter: PcmConverter::shared(format, channels, dither),
+            float,
+            header,
+        }
+    }
+}
+
+impl StreamEncoder for WavEncoder {
+    fn format(&self) -> StreamingFormat {
+        StreamingFormat::Wav
+    }
+
+    fn mime_type(&self) -> String {
+        format!(
+            "audio/vnd.wave;codec={}",
+            if self.float {
+                WAVE_FORMAT_IEEE_FLOAT
+            } else {
+                WAVE_FORMAT_PCM
+            }
+        )
+    }
+
+    fn protocol_info(&self) -> String {
+        WAV_PROT_INFO.to_string()
+    }
+
+    fn bits_per_sample(&self) -> u16 {
+        self.bits_per_sample
+    }
+
+    fn description(&self) -> String {
+        let codec = if self.float {
+            "audio/wave;codec=3 (WAV float"
+        } else {
+            "audio/wave;codec=1 (WAV"
+        };
+        match self.header {
+            WavHeader::Classic => format!("{codec})"),
+            header => format!("{codec} {header})"),
+        }
+    }
+
+    fn header(&self) -> Vec<u8> {
+        create_wav_hdr(
+            self.header,
+            self.sample_rate,
+            self.bits_per_sample,
+            self.channels,
+            self.float,
+        )
+    }
+
+    fn encode(&self, samples: &Arc<[f32]>, out: &mut VecDeque<u8>) {
+        out.extend(self.converter.convert(samples).iter());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_features() {
+        assert_eq!(
+            LpcmEncoder::new(44100, 16, 2, Dither::None).content_features(),
+            "DLNA.ORG_PN=LPCM"
+        );
+        assert_eq!(
+            FlacChannel::new(44100, 24, 2, false, Dither::None, FlacSettings::default()).content_features(),
+            "DLNA.ORG_PN=FLAC;DLNA.ORG_OP=01;DLNA.ORG_CI=0;DLNA.ORG_FLAGS=01700000000000000000000000000000"
+        );
+        assert!(Mp3Channel::new(44100, 320, false)
+            .content_features()
+            .starts_with("DLNA.ORG_PN=MP3;"));
+        let wav = WavEncoder::new(44100, 16, 2, Dither::None, false, WavHeader::Classic);
+        assert!(wav.protocol_info().ends_with(&wav.content_features()));
+        assert!(OpusChannel::new(44100, 128)
+            .protocol_info()
+            .starts_with("http-get:*:audio/ogg:"));
+        assert_eq!(
+            FlacChannel::new(44100, 24, 2, true, Dither::None, FlacSettings::default())
+                .protocol_info(),
+            OpusChannel::new(44100, 128).protocol_info()
+        );
+    }
+
+    #[test]
+    fn stream_protocol_info_matches_the_encoders() {
+        let encoders: [Arc<dyn StreamEncoder>; 6] = [
+            Arc::new(LpcmEncoder::new(48000, 24, 2, Dither::None)),
+            Arc::new(WavEncoder::new(
+                44100,
+                32,
+                2,
+                Dither::None,
+                true,
+                WavHeader::Classic,
+            )),
+            Arc::new(FlacChannel::new(
+                44100,
+                24,
+                2,
+                false,
+                Dither::None,
+                FlacSettings::default(),
+            )),
+            Arc::new(FlacChannel::new(
+                96000,
+                16,
+                6,
+                true,
+                Dither::None,
+                FlacSettings::default(),
+            )),
+            Arc::new(Mp3Channel::new(44100, 320, false)),
+            Arc::new(OpusChannel::new(44100, 128)),
+        ];
+        for (encoder, (sample_rate, bits_per_sample, channels)) in encoders.iter().zip([
+            (48000, 24, 2),
+            (44100, 32, 2),
+            (44100, 24, 2),
+            (96000, 16, 6),
+            (44100, 24, 2),
+            (44100, 24, 2),
+        ]) {
+            assert_eq!(
+                stream_protocol_info(encoder.format(), sample_rate, bits_per_sample, channels),
+                (encoder.protocol_info(), encoder.bits_per_sample())
+            );
+        }
+        assert_eq!(
+            stream_protocol_info(StreamingFormat::Lpcm, 44100, 32, 2).1,
+            24
+        );
+    }
+
+    #[test]
+    fn mime_types() {
+        assert_eq!(
+            LpcmEncoder::new(44100, 16, 2, Dither::None).mime_type(),
+            "audio/L16;rate=44100;channels=2"
+        );
+        assert_eq!(
+            LpcmEncoder::new(48000, 24, 2, Dither::None).protocol_info(),
+            "http-get:*:audio/L24;rate=48000;channels=2:DLNA.ORG_PN=LPCM"
+        );
+        assert_eq!(
+            LpcmEncoder::new(48000, 16, 6, Dither::None).mime_type(),
+            "audio/L16;rate=48000;channels=6"
+        );
+        assert_eq!(
+            WavEncoder::new(44100, 16, 2, Dither::None, false, WavHeader::Classic).mime_type(),
+            "audio/vnd.wave;codec=1"
+        );
+        assert_eq!(
+            FlacChannel::new(44100, 16, 2, false, Dither::None, FlacSettings::default())
+                .mime_type(),
+            "audio/flac"
+        );
+        assert_eq!(Mp3Channel::new(44100, 320, false).mime_type(), "audio/mpeg");
+        assert_eq!(OpusChannel::new(44100, 128).mime_type(), "audio/ogg");
+    }
+
+    #[test]
+    fn pcm_encoding() {
+        let samples = [1.0f32, -1.0];
+        let mut out = VecDeque::new();
+        LpcmEncoder::new(44100, 16, 2, Dither::None).encode(&samples.into(), &mut out);
+        assert_eq!(out, [0x7f, 0xff, 0x80, 0x00]);
+        out.clear();
+        WavEncoder::new(44100, 16, 2, Dither::None, false, WavHeader::Classic)
+            .encode(&samples.into(), &mut out);
+        assert_eq!(out, [0xff, 0x7f, 0x00, 0x80]);
+        out.clear();
+        LpcmEncoder::new(44100, 24, 2, Dither::None).encode(&samples.into(), &mut out);
+        assert_eq!(out, [0x7f, 0xff, 0xff, 0x80, 0x00, 0x00]);
+        out.clear();
+        WavEncoder::new(44100, 24, 2, Dither::None, false, WavHeader::Classic)
+            .encode(&samples.into(), &mut out);
+        assert_eq!(out, [0xff, 0xff, 0x7f, 0x00, 0x00, 0x80]);
+        assert_eq!(
+            WavEncoder::new(44100, 24, 2, Dither::None, false, WavHeader::Classic)
+                .header()
+                .len(),
+            68
+        );
+        assert!(LpcmEncoder::new(44100, 24, 2, Dither::None)
+            .header()
+            .is_empty());
+    }
+
+    #[test]
+    fn wav_32bit() {
+        // float: the captured samples pass bit exact
+        let samples = [0.123_456_79f32, -1.0];
+        let float = WavEncoder::new(96000, 32, 2, Dither::None, true, WavHeader::Classic);
+        assert_eq!(float.mime_type(), "audio/vnd.wave;codec=3");
+        // more than 16 bit has a WAVE_FORMAT_EXTENSIBLE header with the float SubFormat
+        let hdr = float.header();
+        assert_eq!(hdr.len(), 68);
+        assert_eq!(u16::from_le_bytes([hdr[34], hdr[35]]), 32);
+        assert_eq!(
+            u16::from_le_bytes([hdr[44], hdr[45]]),
+            WAVE_FORMAT_IEEE_FLOAT
+        );
+        let mut out = VecDeque::new();
+        float.encode(&samples.into(), &mut out);
+        let decoded: Vec<f32> = out
+            .make_contiguous()
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        assert_eq!(decoded, samples);
+        // integer
+        let int = WavEncoder::new(96000, 32, 2, Dither::None, false, WavHeader::Classic);
+        assert_eq!(int.mime_type(), "audio/vnd.wave;codec=1");
+        assert_eq!(int.header().len(), 68);
+        out.clear();
+        int.encode(&[1.0, -1.0].into(), &mut out);
+        assert_eq!(out, [0xff, 0xff, 0xff, 0x7f, 0x00, 0x00, 0x00, 0x80]);
+        // the configured header
+        let rf64 = WavEncoder::new(96000, 24, 2, Dither::None, false, WavHeader::Rf64);
+        assert_eq!(&rf64.header()[0..4], b"RF64");
+        assert_eq!(rf64.description(), "audio/wave;codec=1 (WAV RF64)");
+    }
+
+    #[test]
+    fn dithered_encoding() {
+        // 16 bit silence becomes dither noise of at most 1 LSB
+        let silence = [0f32; 1024];
+        let mut out = VecDeque::new();
+        LpcmEncoder::new(44100, 16, 2, Dither::Tpdf).encode(&silence.into(), &mut out);
+        let samples: Vec<i16> = out
+            .make_contiguous()
+            .chunks_exact(2)
+            .map(|b| i16::from_be_bytes([b[0], b[1]]))
+            .collect();
+        assert!(samples.iter().all(|s| (-1..=1).contains(s)));
+        assert!(samples.iter().any(|s| *s != 0));
+        // 24 bit is not dithered
+        out.clear();
+        WavEncoder::new(44100, 24, 2, Dither::Shaped, false, WavHeader::Classic)
+            .encode(&silence.into(), &mut out);
+        assert!(out.iter().all(|b| *b == 0));
+    }
+}
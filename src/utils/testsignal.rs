@@ -0,0 +1,255 @@
+///
+/// testsignal.rs
+///
+/// TestSignal: a synthetic AudioSource, to test the renderers and the speakers
+///
+/// - sweep: a logarithmic sine sweep from 20 Hz to 20 kHz in 10 seconds, on all channels
+/// - pink: pink noise (-3 dB per octave), uncorrelated on every channel
+/// - ident: channel identification, 1 kHz beeps on one channel at a time, in channel order,
+///   with 1 beep on the first channel, 2 on the second, and so on
+///
+use crate::utils::{
+    audiosource::{start_source_thread, AudioSource, FrameReader, RunningSource},
+    capturering::CaptureWriter,
+    dither::TpdfNoise,
+};
+use std::{f64::consts::TAU, fmt, str::FromStr};
+
+/// the peak level of the sweep and the beeps (-6 dBFS)
+const LEVEL: f64 = 0.5;
+/// the sweep range and duration
+const SWEEP_FROM: f64 = 20.0;
+const SWEEP_TO: f64 = 20000.0;
+const SWEEP_SECS: f64 = 10.0;
+/// the ident beeps
+const BEEP_FREQ: f64 = 1000.0;
+const BEEP_SECS: f64 = 0.15;
+const CHANNEL_PAUSE_SECS: f64 = 0.7;
+
+/// Signal - the test signals
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    Sweep,
+    PinkNoise,
+    ChannelIdent,
+}
+
+impl FromStr for Signal {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "sweep" => Ok(Signal::Sweep),
+            "pink" => Ok(Signal::PinkNoise),
+            "ident" => Ok(Signal::ChannelIdent),
+            _ => Err(format!("unknown test signal {s} (sweep/pink/ident)")),
+        }
+    }
+}
+
+impl fmt::Display for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Signal::Sweep => write!(f, "sweep"),
+            Signal::PinkNoise => write!(f, "pink"),
+            Signal::ChannelIdent => write!(f, "ident"),
+        }
+    }
+}
+
+/// TestSignal - the generator of a test signal
+pub struct TestSignal {
+    signal: Signal,
+    sample_rate: u32,
+    channels: u16,
+    // the frames generated so far
+    frame: u64,
+    // the phase of the sweep (radians)
+    phase: f64,
+    noise: TpdfNoise,
+    // the pink noise filter state of every channel
+    pink: Vec<[f64; 7]>,
+}
+
+impl TestSignal {
+    pub fn new(signal: Signal, sample_rate: u32, channels: u16) -> TestSignal {
+        TestSignal {
+            signal,
+            sample_rate,
+            channels,
+            frame: 0,
+            phase: 0.0,
+            noise: TpdfNoise::new(),
+            pink: vec![[0.0; 7]; channels as usize],
+        }
+    }
+
+    /// next_frame - generate the next frame
+    fn next_frame(&mut self, frame: &mut [f32]) {
+        let t = self.frame as f64 / self.sample_rate as f64;
+        self.frame += 1;
+        match self.signal {
+            Signal::Sweep => {
+                let s = (LEVEL * self.phase.sin()) as f32;
+                frame.fill(s);
+                let pos = t % SWEEP_SECS / SWEEP_SECS;
+                let freq = SWEEP_FROM * (SWEEP_TO / SWEEP_FROM).powf(pos);
+                self.phase = (self.phase + TAU * freq / self.sample_rate as f64) % TAU;
+            }
+            Signal::PinkNoise => {
+                for (s, b) in frame.iter_mut().zip(self.pink.iter_mut()) {
+                    // Paul Kellet's refined pink noise filter
+                    let white = self.noise.next_sample() as f64 * 0.5;
+                    b[0] = 0.99886 * b[0] + white * 0.0555179;
+                    b[1] = 0.99332 * b[1] + white * 0.0750759;
+                    b[2] = 0.96900 * b[2] + white * 0.1538520;
+                    b[3] = 0.86650 * b[3] + white * 0.3104856;
+                    b[4] = 0.55000 * b[4] + white * 0.5329522;
+                    b[5] = -0.7616 * b[5] - white * 0.0168980;
+                    let pink = b[0] + b[1] + b[2] + b[3] + b[4] + b[5] + b[6] + white * 0.5362;
+                    b[6] = white * 0.115926;
+                    *s = (pink * 0.25).clamp(-1.0, 1.0) as f32;
+                }
+            }
+            Signal::ChannelIdent => {
+                frame.fill(0.0);
+                if let Some((ch, beep_t)) = self.ident_beep(t) {
+                    frame[ch] = (LEVEL * (TAU * BEEP_FREQ * beep_t).sin()) as f32;
+                }
+            }
+        }
+    }
+
+    /// ident_beep - the channel that beeps at time t and the time in the beep, if any
+    ///
+    /// channel n has n + 1 beeps, each followed by a gap of the same length, and then
+    /// a pause before the next channel
+    fn ident_beep(&self, t: f64) -> Option<(usize, f64)> {
+        let slot = |ch: usize| (ch + 1) as f64 * 2.0 * BEEP_SECS + CHANNEL_PAUSE_SECS;
+        let cycle: f64 = (0..self.channels as usize).map(slot).sum();
+        let mut t = t % cycle;
+        for ch in 0..self.channels as usize {
+            if t < slot(ch) {
+                let beep = (t / (2.0 * BEEP_SECS)) as usize;
+                let beep_t = t - beep as f64 * 2.0 * BEEP_SECS;
+                return (beep <= ch && beep_t < BEEP_SECS).then_some((ch, beep_t));
+            }
+            t -= slot(ch);
+        }
+        None
+    }
+}
+
+impl FrameReader for TestSignal {
+    fn read_frames(&mut self, buf: &mut [f32]) -> Result<usize, String> {
+        let channels = self.channels as usize;
+        let n = buf.len() - buf.len() % channels;
+        for frame in buf[..n].chunks_exact_mut(channels) {
+            self.next_frame(frame);
+        }
+        Ok(n)
+    }
+
+    fn needs_pacing(&self) -> bool {
+        true
+    }
+}
+
+impl AudioSource for TestSignal {
+    fn name(&self) -> String {
+        format!(
+            "the {} test signal ({} Hz, {} channels)",
+            self.signal, self.sample_rate, self.channels
+        )
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn start(self: Box<Self>, writer: CaptureWriter) -> Result<RunningSource, String> {
+        let (name, rate, channels) = (self.name(), self.sample_rate, self.channels);
+        start_source_thread(name, *self, rate, channels, writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48000;
+
+    /// generate secs seconds of the signal
+    fn generate(signal: Signal, channels: u16, secs: f64) -> Vec<f32> {
+        let mut generator = TestSignal::new(signal, RATE, channels);
+        let mut samples = vec![0f32; (secs * RATE as f64) as usize * channels as usize];
+        assert_eq!(generator.read_frames(&mut samples).unwrap(), samples.len());
+        samples
+    }
+
+    fn zero_crossings(samples: &[f32]) -> usize {
+        samples
+            .windows(2)
+            .filter(|w| (w[0] < 0.0) != (w[1] < 0.0))
+            .count()
+    }
+
+    #[test]
+    fn sweep() {
+        let samples = generate(Signal::Sweep, 2, SWEEP_SECS);
+        assert!(samples.iter().all(|s| s.abs() <= LEVEL as f32));
+        // the same on both channels
+        assert!(samples.chunks(2).all(|f| f[0] == f[1]));
+        let left: Vec<f32> = samples.iter().step_by(2).copied().collect();
+        // about 20 Hz at the start and 20 kHz at the end: 2 zero crossings per period
+        let start = zero_crossings(&left[..RATE as usize / 10]);
+        assert!((3..=6).contains(&start), "{start}");
+        let end = zero_crossings(&left[left.len() - RATE as usize / 100..]);
+        assert!((360..=400).contains(&end), "{end}");
+    }
+
+    #[test]
+    fn pink_noise() {
+        let samples = generate(Signal::PinkNoise, 2, 2.0);
+        assert!(samples.iter().all(|s| s.abs() <= 1.0));
+        let left: Vec<f32> = samples.iter().step_by(2).copied().collect();
+        let right: Vec<f32> = samples.iter().skip(1).step_by(2).copied().collect();
+        assert_ne!(left, right);
+        let rms = (left.iter().map(|s| s * s).sum::<f32>() / left.len() as f32).sqrt();
+        assert!((0.02..0.3).contains(&rms), "rms {rms}");
+        // much more power in the lows than white noise: the differences of successive
+        // samples (a high pass) have a fraction of the power
+        let diff: f32 = left.windows(2).map(|w| (w[1] - w[0]).powi(2)).sum();
+        let total: f32 = left.iter().map(|s| s * s).sum();
+        assert!(diff < total * 0.5, "{diff} {total}");
+    }
+
+    #[test]
+    fn channel_ident() {
+        // the first slot: 1 beep on channel 0, the second one 2 beeps on channel 1
+        let samples = generate(Signal::ChannelIdent, 3, 3.0);
+        let beeping = |ch: usize, from: f64, to: f64| {
+            let (from, to) = ((from * RATE as f64) as usize, (to * RATE as f64) as usize);
+            samples[from * 3..to * 3]
+                .chunks(3)
+                .any(|f| f[ch].abs() > 0.1)
+        };
+        // only one channel at a time
+        assert!(samples
+            .chunks(3)
+            .all(|f| f.iter().filter(|s| **s != 0.0).count() <= 1));
+        assert!(beeping(0, 0.0, 0.15));
+        assert!(!beeping(0, 0.16, 1.0));
+        assert!(!beeping(1, 0.0, 1.0));
+        // channel 1 starts at 1.0 secs
+        assert!(beeping(1, 1.0, 1.15));
+        assert!(!beeping(1, 1.16, 1.29));
+        assert!(beeping(1, 1.3, 1.45));
+        assert!(!beeping(1, 1.46, 2.3));
+        assert!(beeping(2, 2.3, 2.45));
+    }
+}
@@ -1,8 +1,11 @@
 use fltk::app;
 use log::{error, info, warn};
-use std::sync::atomic::Ordering::Relaxed;
+use std::{collections::VecDeque, sync::atomic::Ordering::Relaxed};
 
-use crate::globals::statics::{HAVE_UI, LOGCHANNEL};
+use crate::globals::statics::{HAVE_UI, LOGCHANNEL, LOG_HISTORY};
+
+/// the number of log messages kept for the web UI
+const LOG_HISTORY_SIZE: usize = 500;
 
 #[allow(dead_code)]
 pub fn enable_ui_log() {
@@ -17,6 +20,7 @@ pub fn disable_ui_log() {
 /// ui_log - send a logmessage to the textbox on the Crossbeam LOGCHANNEL
 pub fn ui_log(s: String) {
     let cat: &str = &s[..2];
+    add_log_history(&s);
     if HAVE_UI.load(Relaxed) {
         match cat {
             "*W" => warn!("tb_log: {}", s),
@@ -34,3 +38,58 @@ pub fn ui_log(s: String) {
         };
     }
 }
+
+/// add_log_history - keep the most recent log messages for the web UI
+fn add_log_history(s: &str) {
+    push_log(&mut LOG_HISTORY.write(), s);
+}
+
+/// get_log_history - get the log messages with a sequence number >= since,
+/// and the sequence number of the next log message
+pub fn get_log_history(since: u64) -> (u64, Vec<String>) {
+    log_lines(&LOG_HISTORY.read(), since)
+}
+
+fn push_log(history: &mut VecDeque<(u64, String)>, s: &str) {
+    let seq = history.back().map_or(0, |(seq, _)| seq + 1);
+    if history.len() >= LOG_HISTORY_SIZE {
+        history.pop_front();
+    }
+    history.push_back((seq, s.to_string()));
+}
+
+fn log_lines(history: &VecDeque<(u64, String)>, since: u64) -> (u64, Vec<String>) {
+    let next = history.back().map_or(0, |(seq, _)| seq + 1);
+    let lines = history
+        .iter()
+        .filter(|(seq, _)| *seq >= since)
+        .map(|(_, line)| line.clone())
+        .collect();
+    (next, lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_history() {
+        // a local history, other tests log to the global one at the same time
+        let mut history = VecDeque::new();
+        for i in 0..LOG_HISTORY_SIZE + 10 {
+            push_log(&mut history, &format!("message {i}"));
+        }
+        let (next, lines) = log_lines(&history, 0);
+        assert_eq!(next, LOG_HISTORY_SIZE as u64 + 10);
+        assert_eq!(lines.len(), LOG_HISTORY_SIZE);
+        assert_eq!(lines[0], "message 10");
+        assert_eq!(
+            lines.last().unwrap(),
+            &format!("message {}", LOG_HISTORY_SIZE + 9)
+        );
+        let (_, lines) = log_lines(&history, next - 2);
+        assert_eq!(lines.len(), 2);
+        let (_, lines) = log_lines(&history, next);
+        assert!(lines.is_empty());
+    }
+}
@@ -0,0 +1,223 @@
+///
+/// wavheader.rs
+///
+/// the "infinite size" headers of the WAV streaming format
+///
+/// a live stream has no length, so the sizes are set to the largest value, in the
+/// classic RIFF header (32 bit sizes), the RF64 header (64 bit sizes in a ds64 chunk)
+/// or the Sony Wave64 header (GUID chunk ids and 64 bit sizes).
+/// renderers that check the sizes (libsndfile based renderers for instance)
+/// may accept a RF64 or Wave64 stream when they reject the classic header.
+///
+use crate::{enums::streaming::WavHeader, utils::channelmap::channel_mask};
+use log::debug;
+
+/// the WAV format tags
+pub const WAVE_FORMAT_PCM: u16 = 1;
+pub const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xfffe;
+
+/// the SubFormat GUID of WAVE_FORMAT_EXTENSIBLE after the format tag
+/// (0000xxxx-0000-0010-8000-00aa00389b71)
+const KSDATAFORMAT_SUBTYPE_SUFFIX: [u8; 14] = [
+    0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xaa, 0x00, 0x38, 0x9b, 0x71,
+];
+
+/// the Wave64 chunk GUIDs
+const W64_RIFF: [u8; 16] = [
+    0x72, 0x69, 0x66, 0x66, 0x2e, 0x91, 0xcf, 0x11, 0xa5, 0xd6, 0x28, 0xdb, 0x04, 0xc1, 0x00, 0x00,
+];
+const W64_WAVE: [u8; 16] = [
+    0x77, 0x61, 0x76, 0x65, 0xf3, 0xac, 0xd3, 0x11, 0x8c, 0xd1, 0x00, 0xc0, 0x4f, 0x8e, 0xdb, 0x8a,
+];
+const W64_FMT: [u8; 16] = [
+    0x66, 0x6d, 0x74, 0x20, 0xf3, 0xac, 0xd3, 0x11, 0x8c, 0xd1, 0x00, 0xc0, 0x4f, 0x8e, 0xdb, 0x8a,
+];
+const W64_DATA: [u8; 16] = [
+    0x64, 0x61, 0x74, 0x61, 0xf3, 0xac, 0xd3, 0x11, 0x8c, 0xd1, 0x00, 0xc0, 0x4f, 0x8e, 0xdb, 0x8a,
+];
+
+/// create_wav_hdr - the header of an "infinite size" WAV stream
+pub fn create_wav_hdr(
+    mode: WavHeader,
+    sample_rate: u32,
+    bits_per_sample: u16,
+    channels: u16,
+    float: bool,
+) -> Vec<u8> {
+    let fmt = fmt_chunk(sample_rate, bits_per_sample, channels, float);
+    let mut hdr = Vec::with_capacity(128);
+    match mode {
+        WavHeader::Classic => {
+            hdr.extend_from_slice(b"RIFF");
+            hdr.extend_from_slice(&u32::MAX.to_le_bytes()); // "infinite" RIFF size
+            hdr.extend_from_slice(b"WAVE");
+            hdr.extend_from_slice(b"fmt ");
+            hdr.extend_from_slice(&(fmt.len() as u32).to_le_bytes());
+            hdr.extend_from_slice(&fmt);
+            hdr.extend_from_slice(b"data");
+            hdr.extend_from_slice(&u32::MAX.to_le_bytes()); // "infinite" data size
+        }
+        WavHeader::Rf64 => {
+            // the 32 bit sizes are -1, the real sizes are in the ds64 chunk
+            hdr.extend_from_slice(b"RF64");
+            hdr.extend_from_slice(&u32::MAX.to_le_bytes());
+            hdr.extend_from_slice(b"WAVE");
+            hdr.extend_from_slice(b"ds64");
+            hdr.extend_from_slice(&28u32.to_le_bytes());
+            hdr.extend_from_slice(&u64::MAX.to_le_bytes()); // RIFF size
+            hdr.extend_from_slice(&u64::MAX.to_le_bytes()); // data size
+            hdr.extend_from_slice(&u64::MAX.to_le_bytes()); // sample count
+            hdr.extend_from_slice(&0u32.to_le_bytes()); // no table
+            hdr.extend_from_slice(b"fmt ");
+            hdr.extend_from_slice(&(fmt.len() as u32).to_le_bytes());
+            hdr.extend_from_slice(&fmt);
+            hdr.extend_from_slice(b"data");
+            hdr.extend_from_slice(&u32::MAX.to_le_bytes());
+        }
+        WavHeader::Wave64 => {
+            // the chunk sizes include the 24 byte GUID and size, the chunks are 8 byte aligned
+            // and the fmt chunk (16 or 40 bytes) needs no padding
+            hdr.extend_from_slice(&W64_RIFF);
+            hdr.extend_from_slice(&u64::MAX.to_le_bytes());
+            hdr.extend_from_slice(&W64_WAVE);
+            hdr.extend_from_slice(&W64_FMT);
+            hdr.extend_from_slice(&(24 + fmt.len() as u64).to_le_bytes());
+            hdr.extend_from_slice(&fmt);
+            hdr.extend_from_slice(&W64_DATA);
+            hdr.extend_from_slice(&u64::MAX.to_le_bytes());
+        }
+    }
+    debug!("WAV Header (l={}): \r\n{:02x?}", hdr.len(), hdr);
+    hdr
+}
+
+/// fmt_chunk - the fmt chunk data
+///
+/// WAVE_FORMAT_EXTENSIBLE with the channel mask for more than 16 bit or more than 2 channels,
+/// plain PCM otherwise
+fn fmt_chunk(sample_rate: u32, bits_per_sample: u16, channels: u16, float: bool) -> Vec<u8> {
+    let extensible = bits_per_sample > 16 || channels > 2;
+    let block_align: u16 = channels * (bits_per_sample / 8);
+    let byte_rate: u32 = sample_rate * block_align as u32;
+    let format = if float {
+        WAVE_FORMAT_IEEE_FLOAT
+    } else {
+        WAVE_FORMAT_PCM
+    };
+    let mut fmt = Vec::with_capacity(40);
+    let format_tag = if extensible {
+        WAVE_FORMAT_EXTENSIBLE
+    } else {
+        format
+    };
+    fmt.extend_from_slice(&format_tag.to_le_bytes());
+    fmt.extend_from_slice(&channels.to_le_bytes());
+    fmt.extend_from_slice(&sample_rate.to_le_bytes());
+    fmt.extend_from_slice(&byte_rate.to_le_bytes());
+    fmt.extend_from_slice(&block_align.to_le_bytes());
+    fmt.extend_from_slice(&bits_per_sample.to_le_bytes());
+    if extensible {
+        fmt.extend_from_slice(&22u16.to_le_bytes()); // cbSize
+        fmt.extend_from_slice(&bits_per_sample.to_le_bytes()); // ValidBitsPerSample
+        fmt.extend_from_slice(&channel_mask(channels).to_le_bytes());
+        fmt.extend_from_slice(&format.to_le_bytes()); // SubFormat
+        fmt.extend_from_slice(&KSDATAFORMAT_SUBTYPE_SUFFIX);
+    }
+    fmt
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16_at(hdr: &[u8], i: usize) -> u16 {
+        u16::from_le_bytes([hdr[i], hdr[i + 1]])
+    }
+
+    fn u32_at(hdr: &[u8], i: usize) -> u32 {
+        u32::from_le_bytes(hdr[i..i + 4].try_into().unwrap())
+    }
+
+    fn u64_at(hdr: &[u8], i: usize) -> u64 {
+        u64::from_le_bytes(hdr[i..i + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_wav_hdr() {
+        let hdr = create_wav_hdr(WavHeader::Classic, 44100, 16, 2, false);
+        assert_eq!(hdr.len(), 44);
+        assert_eq!(&hdr[0..4], b"RIFF");
+        assert_eq!(u32_at(&hdr, 4), u32::MAX);
+        assert_eq!(u32_at(&hdr, 16), 16);
+        assert_eq!(u16_at(&hdr, 20), WAVE_FORMAT_PCM);
+        assert_eq!(u32_at(&hdr, 28), 44100 * 4);
+        assert_eq!(u16_at(&hdr, 32), 4);
+        assert_eq!(&hdr[36..40], b"data");
+        assert_eq!(u32_at(&hdr, 40), u32::MAX);
+    }
+
+    #[test]
+    fn wav_hdr_extensible() {
+        // 24 bit stereo
+        let hdr = create_wav_hdr(WavHeader::Classic, 48000, 24, 2, false);
+        assert_eq!(hdr.len(), 68);
+        assert_eq!(u32_at(&hdr, 16), 40);
+        assert_eq!(u16_at(&hdr, 20), WAVE_FORMAT_EXTENSIBLE);
+        assert_eq!(u32_at(&hdr, 40), 0x3);
+        assert_eq!(&hdr[60..64], b"data");
+        // 5.1
+        let hdr = create_wav_hdr(WavHeader::Classic, 48000, 24, 6, false);
+        assert_eq!(hdr.len(), 68);
+        assert_eq!(u16_at(&hdr, 22), 6);
+        assert_eq!(u32_at(&hdr, 28), 48000 * 6 * 3);
+        assert_eq!(u16_at(&hdr, 32), 18);
+        assert_eq!(u16_at(&hdr, 36), 22);
+        assert_eq!(u16_at(&hdr, 38), 24);
+        assert_eq!(u32_at(&hdr, 40), 0x3f);
+        assert_eq!(u16_at(&hdr, 44), WAVE_FORMAT_PCM);
+        assert_eq!(hdr[46..60], KSDATAFORMAT_SUBTYPE_SUFFIX);
+        // float
+        let hdr = create_wav_hdr(WavHeader::Classic, 48000, 32, 2, true);
+        assert_eq!(u16_at(&hdr, 20), WAVE_FORMAT_EXTENSIBLE);
+        assert_eq!(u16_at(&hdr, 44), WAVE_FORMAT_IEEE_FLOAT);
+    }
+
+    #[test]
+    fn rf64_hdr() {
+        let hdr = create_wav_hdr(WavHeader::Rf64, 96000, 24, 2, false);
+        assert_eq!(hdr.len(), 104);
+        assert_eq!(&hdr[0..4], b"RF64");
+        assert_eq!(u32_at(&hdr, 4), u32::MAX);
+        assert_eq!(&hdr[8..12], b"WAVE");
+        assert_eq!(&hdr[12..16], b"ds64");
+        assert_eq!(u32_at(&hdr, 16), 28);
+        assert_eq!(u64_at(&hdr, 20), u64::MAX);
+        assert_eq!(u64_at(&hdr, 28), u64::MAX);
+        assert_eq!(u32_at(&hdr, 44), 0);
+        assert_eq!(&hdr[48..52], b"fmt ");
+        assert_eq!(u32_at(&hdr, 52), 40);
+        assert_eq!(u32_at(&hdr, 60), 96000);
+        assert_eq!(&hdr[96..100], b"data");
+        assert_eq!(u32_at(&hdr, 100), u32::MAX);
+    }
+
+    #[test]
+    fn wave64_hdr() {
+        let hdr = create_wav_hdr(WavHeader::Wave64, 44100, 16, 2, false);
+        assert_eq!(hdr.len(), 40 + 40 + 24);
+        assert_eq!(hdr[0..16], W64_RIFF);
+        assert_eq!(u64_at(&hdr, 16), u64::MAX);
+        assert_eq!(hdr[24..40], W64_WAVE);
+        assert_eq!(hdr[40..56], W64_FMT);
+        assert_eq!(u64_at(&hdr, 56), 40);
+        assert_eq!(u16_at(&hdr, 64), WAVE_FORMAT_PCM);
+        assert_eq!(hdr[80..96], W64_DATA);
+        assert_eq!(u64_at(&hdr, 96), u64::MAX);
+        // the extensible fmt chunk keeps the data chunk 8 byte aligned
+        let hdr = create_wav_hdr(WavHeader::Wave64, 44100, 24, 2, false);
+        assert_eq!(u64_at(&hdr, 56), 64);
+        assert_eq!(hdr[104..120], W64_DATA);
+        assert!(hdr.len().is_multiple_of(8));
+    }
+}